@@ -1,5 +1,6 @@
 use bevy::prelude::*;
 
+mod protocol;
 mod visualizer;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -14,6 +15,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .add_plugin(visualizer::message::MessagePlugin)
         // process msg
         .add_plugin(visualizer::process_msg::ProcessMsgPlugin)
+        // ui panel
+        .add_plugin(visualizer::ui::UiPlugin)
+        // camera and debug images
+        .add_plugin(visualizer::image_view::ImageViewPlugin)
         .run();
 
     Ok(())
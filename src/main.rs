@@ -1,25 +1,55 @@
-use std::io::{self, Write};
-use std::net::TcpStream;
-use std::thread;
-use std::time::Duration;
+use std::error::Error;
 
+mod protocol;
 mod slam;
 
-fn main() -> io::Result<()> {
-    // 连接到目标 TCP 服务器
-    let mut stream = TcpStream::connect("127.0.0.1:9123")?;
-    let mut i = 0;
-
-    // 循环发送消息
-    loop {
-        let message1 = format!("{{body {} 0.0 0.0 0.0 1.0 0.01 0.01 0.01}}", i);
-        let message2 = format!("{{point {} {} 0.0 0.0}}", i, 0.1 * i as f32);
-        i += 1;
-        // let message = "Hello, TCP server!";
-        stream.write_all(message1.as_bytes())?;
-        stream.write_all(message2.as_bytes())?;
-
-        // 暂停一段时间
-        thread::sleep(Duration::from_micros(10000));
+const USAGE: &str = "\
+Usage: bevy_slam <euroc cam0 dir> [visualizer address]
+
+Tracks the sequence and streams the pose, keyframes, map points, the tracked image and
+the debug images to a running frontend (default address: 127.0.0.1:9123). Start the
+frontend first, or at any time, the map is sent once it connects.";
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = match args.next() {
+        Some(path) if path != "--help" && path != "-h" => path,
+        _ => {
+            println!("{}", USAGE);
+            std::process::exit(2);
+        },
+    };
+    let address = args.next().unwrap_or(slam::visualizer_client::DEFAULT_ADDRESS.to_string());
+
+    if let Err(e) = run(&path, &address) {
+        eprintln!("run failed: {}", e);
+        std::process::exit(1);
     }
-}
\ No newline at end of file
+}
+
+fn run(path: &str, address: &str) -> Result<(), Box<dyn Error>> {
+    let data_set = slam::load_data::load_euroc_data(path)?;
+    let camera = slam::camera::CameraIntrinsics::new_euroc();
+    let config = slam::config::SlamConfig::default();
+    let mut tracker = slam::process_image::Tracker::new(camera, config.clone())?;
+    // debug images go to the frontend instead of a window or the disk
+    let (debug_tx, debug_rx) = crossbeam_channel::bounded(8);
+    tracker.set_debug_output(slam::debug::DebugOutput::new(
+        Box::new(slam::debug::ChannelSink::new(debug_tx)),
+        config.debug.stages.clone(),
+    ));
+    let scale = slam::output::WorldScale::new(&config.output);
+    let mut client = slam::visualizer_client::VisualizerClient::new(address, scale)?;
+
+    for data in data_set {
+        let pose = tracker.track_and_optimize(data);
+        let tracked = pose.as_ref().ok().filter(|_| tracker.initializer.done());
+        client.publish(tracked, &tracker.map);
+        client.publish_frame(&tracker.curr_frame);
+        for image in debug_rx.try_iter() {
+            client.publish_image(image.stage.name(), &image.img, Vec::new());
+        }
+    }
+
+    Ok(())
+}
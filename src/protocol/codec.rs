@@ -0,0 +1,506 @@
+use std::io::Write;
+
+use super::{CameraImage, CameraInfo, EdgeKind, GraphEdge, ImageKeypoint, KeyFramePose, KeypointStatus, Message, MessageKind, PointUpdate, ProtocolError, HEADER_LEN, MAGIC, MAX_PAYLOAD_LEN, VERSION};
+
+/// Encoded sizes of the records of the batch messages.
+const POINT_UPDATE_LEN: usize = 31;
+const KEYFRAME_POSE_LEN: usize = 32;
+const GRAPH_EDGE_LEN: usize = 13;
+const IMAGE_KEYPOINT_LEN: usize = 9;
+
+/// Appends `message` as one frame to `out`. A payload larger than `MAX_PAYLOAD_LEN`,
+/// which the decoder would reject, is an error and leaves `out` unchanged.
+pub fn encode(message: &Message, out: &mut Vec<u8>) -> Result<(), ProtocolError> {
+    let mut payload = Vec::new();
+    match message {
+        Message::BodyTransform { id, relative, rotation, translation } => {
+            put_u32(&mut payload, *id);
+            payload.push(*relative as u8);
+            put_f32s(&mut payload, rotation);
+            put_f32s(&mut payload, translation);
+        },
+        Message::Point { id, position } => {
+            put_u32(&mut payload, *id);
+            put_f32s(&mut payload, position);
+        },
+        Message::PointBatch(points) => {
+            put_u32(&mut payload, points.len() as u32);
+            for point in points {
+                put_u32(&mut payload, point.id);
+                put_f32s(&mut payload, &point.position);
+                payload.extend_from_slice(&point.color);
+                put_u32(&mut payload, point.observations);
+                put_f32s(&mut payload, &[point.reprojection_error]);
+                put_u32(&mut payload, point.first_keyframe);
+            }
+        },
+        Message::KeyFramePoseBatch(poses) => {
+            put_u32(&mut payload, poses.len() as u32);
+            for pose in poses {
+                put_u32(&mut payload, pose.id);
+                put_f32s(&mut payload, &pose.rotation);
+                put_f32s(&mut payload, &pose.translation);
+            }
+        },
+        Message::CameraInfo(camera) => {
+            put_u32(&mut payload, camera.width);
+            put_u32(&mut payload, camera.height);
+            put_f32s(&mut payload, &[camera.fx, camera.fy, camera.cx, camera.cy]);
+        },
+        Message::Reset => {},
+        Message::KeyFrameGraph(edges) => {
+            put_u32(&mut payload, edges.len() as u32);
+            for edge in edges {
+                put_u32(&mut payload, edge.from);
+                put_u32(&mut payload, edge.to);
+                payload.push(edge.kind as u8);
+                put_u32(&mut payload, edge.weight);
+            }
+        },
+        Message::Observations { keyframe, points } => {
+            put_u32(&mut payload, *keyframe);
+            put_u32(&mut payload, points.len() as u32);
+            for point in points {
+                put_u32(&mut payload, *point);
+            }
+        },
+        Message::Image(image) => {
+            put_bytes(&mut payload, image.label.as_bytes());
+            put_u32(&mut payload, image.keypoints.len() as u32);
+            for keypoint in image.keypoints.iter() {
+                put_f32s(&mut payload, &keypoint.position);
+                payload.push(keypoint.status as u8);
+            }
+            put_bytes(&mut payload, &image.jpeg);
+        },
+        Message::PointRemove(ids) => {
+            put_u32(&mut payload, ids.len() as u32);
+            for id in ids {
+                put_u32(&mut payload, *id);
+            }
+        },
+    }
+
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(ProtocolError::PayloadTooLarge(payload.len()));
+    }
+    out.extend_from_slice(&MAGIC);
+    out.push(VERSION);
+    out.push(message.kind() as u8);
+    put_u32(out, payload.len() as u32);
+    out.extend_from_slice(&payload);
+    Ok(())
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), ProtocolError> {
+    let mut frame = Vec::new();
+    encode(message, &mut frame)?;
+    writer.write_all(&frame)?;
+    Ok(())
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_f32s(out: &mut Vec<u8>, values: &[f32]) {
+    for value in values {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// A `u32` length followed by the bytes.
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Reads little endian values from a payload.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.bytes.len() < N {
+            return None;
+        }
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+        head.try_into().ok()
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        match self.take::<1>()? {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take::<4>().map(u32::from_le_bytes)
+    }
+
+    /// A count of records of `record_len` bytes each, checked against the remaining bytes
+    /// so a corrupt count cannot trigger a huge allocation.
+    fn count(&mut self, record_len: usize) -> Option<usize> {
+        let count = self.u32()? as usize;
+        (count.checked_mul(record_len)? <= self.bytes.len()).then_some(count)
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.count(1)?;
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Some(head)
+    }
+
+    fn f32s<const N: usize>(&mut self) -> Option<[f32; N]> {
+        let mut values = [0.0; N];
+        for value in values.iter_mut() {
+            *value = f32::from_le_bytes(self.take::<4>()?);
+        }
+        Some(values)
+    }
+}
+
+fn read_message(kind: MessageKind, reader: &mut Reader) -> Option<Message> {
+    let message = match kind {
+        MessageKind::BodyTransform => Message::BodyTransform {
+            id: reader.u32()?,
+            relative: reader.bool()?,
+            rotation: reader.f32s()?,
+            translation: reader.f32s()?,
+        },
+        MessageKind::Point => Message::Point {
+            id: reader.u32()?,
+            position: reader.f32s()?,
+        },
+        MessageKind::PointBatch => {
+            let count = reader.count(POINT_UPDATE_LEN)?;
+            let mut points = Vec::with_capacity(count);
+            for _ in 0..count {
+                points.push(PointUpdate {
+                    id: reader.u32()?,
+                    position: reader.f32s()?,
+                    color: reader.take()?,
+                    observations: reader.u32()?,
+                    reprojection_error: reader.f32s::<1>()?[0],
+                    first_keyframe: reader.u32()?,
+                });
+            }
+            Message::PointBatch(points)
+        },
+        MessageKind::KeyFramePoseBatch => {
+            let count = reader.count(KEYFRAME_POSE_LEN)?;
+            let mut poses = Vec::with_capacity(count);
+            for _ in 0..count {
+                poses.push(KeyFramePose {
+                    id: reader.u32()?,
+                    rotation: reader.f32s()?,
+                    translation: reader.f32s()?,
+                });
+            }
+            Message::KeyFramePoseBatch(poses)
+        },
+        MessageKind::CameraInfo => {
+            let width = reader.u32()?;
+            let height = reader.u32()?;
+            let [fx, fy, cx, cy] = reader.f32s()?;
+            Message::CameraInfo(CameraInfo { width, height, fx, fy, cx, cy })
+        },
+        MessageKind::Reset => Message::Reset,
+        MessageKind::KeyFrameGraph => {
+            let count = reader.count(GRAPH_EDGE_LEN)?;
+            let mut edges = Vec::with_capacity(count);
+            for _ in 0..count {
+                edges.push(GraphEdge {
+                    from: reader.u32()?,
+                    to: reader.u32()?,
+                    kind: EdgeKind::from_u8(reader.take::<1>()?[0])?,
+                    weight: reader.u32()?,
+                });
+            }
+            Message::KeyFrameGraph(edges)
+        },
+        MessageKind::Observations => {
+            let keyframe = reader.u32()?;
+            let count = reader.count(4)?;
+            let mut points = Vec::with_capacity(count);
+            for _ in 0..count {
+                points.push(reader.u32()?);
+            }
+            Message::Observations { keyframe, points }
+        },
+        MessageKind::Image => {
+            let label = String::from_utf8(reader.bytes()?.to_vec()).ok()?;
+            let count = reader.count(IMAGE_KEYPOINT_LEN)?;
+            let mut keypoints = Vec::with_capacity(count);
+            for _ in 0..count {
+                keypoints.push(ImageKeypoint {
+                    position: reader.f32s()?,
+                    status: KeypointStatus::from_u8(reader.take::<1>()?[0])?,
+                });
+            }
+            let jpeg = reader.bytes()?.to_vec();
+            Message::Image(CameraImage { label, keypoints, jpeg })
+        },
+        MessageKind::PointRemove => {
+            let count = reader.count(4)?;
+            let mut ids = Vec::with_capacity(count);
+            for _ in 0..count {
+                ids.push(reader.u32()?);
+            }
+            Message::PointRemove(ids)
+        },
+    };
+    Some(message)
+}
+
+fn decode_payload(kind: MessageKind, payload: &[u8]) -> Result<Message, ProtocolError> {
+    let mut reader = Reader { bytes: payload };
+    match read_message(kind, &mut reader) {
+        Some(message) if reader.bytes.is_empty() => Ok(message),
+        _ => Err(ProtocolError::InvalidPayload { kind, len: payload.len() }),
+    }
+}
+
+/// Turns a byte stream that arrives in arbitrary chunks back into messages.
+///
+/// Partial frames stay buffered until the rest arrives. A corrupt frame is reported once
+/// and skipped, so the stream stays usable afterwards.
+#[derive(Default)]
+pub struct StreamDecoder {
+    buffer: Vec<u8>,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Number of buffered bytes not yet decoded.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The next complete message, `Ok(None)` if more bytes are needed.
+    pub fn next_message(&mut self) -> Result<Option<Message>, ProtocolError> {
+        if !could_start_frame(&self.buffer) {
+            self.resync();
+            return Err(ProtocolError::BadMagic);
+        }
+        if self.buffer.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let version = self.buffer[4];
+        let kind = self.buffer[5];
+        let len = u32::from_le_bytes([self.buffer[6], self.buffer[7], self.buffer[8], self.buffer[9]]) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            self.resync();
+            return Err(ProtocolError::PayloadTooLarge(len));
+        }
+        if self.buffer.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let frame = self.buffer.drain(..HEADER_LEN + len).collect::<Vec<_>>();
+        if version != VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+        let kind = MessageKind::from_u8(kind).ok_or(ProtocolError::UnknownKind(kind))?;
+        decode_payload(kind, &frame[HEADER_LEN..]).map(Some)
+    }
+
+    /// Drops bytes up to the next position that could start a frame.
+    fn resync(&mut self) {
+        let start = (1..self.buffer.len())
+            .find(|i| could_start_frame(&self.buffer[*i..]))
+            .unwrap_or(self.buffer.len());
+        self.buffer.drain(..start);
+    }
+}
+
+impl Iterator for StreamDecoder {
+    type Item = Result<Message, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_message().transpose()
+    }
+}
+
+/// Whether `bytes` begins with the magic, or with a prefix of it.
+fn could_start_frame(bytes: &[u8]) -> bool {
+    let n = bytes.len().min(MAGIC.len());
+    bytes[..n] == MAGIC[..n]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::BodyTransform { id: 3, relative: false, rotation: [0.0, 0.0, 0.0, 1.0], translation: [0.1, -0.2, 0.3] },
+            Message::BodyTransform { id: 1, relative: true, rotation: [0.0, 0.6, 0.0, 0.8], translation: [0.0; 3] },
+            Message::Reset,
+            Message::Point { id: 7, position: [1.0, 2.0, -3.5] },
+            Message::Point { id: u32::MAX, position: [0.0, f32::MIN_POSITIVE, 1e9] },
+            Message::PointBatch(vec![]),
+            Message::PointBatch((0..100)
+                .map(|i| PointUpdate {
+                    id: i,
+                    position: [i as f32, -0.5, 2.0],
+                    color: [i as u8, 128, 255],
+                    observations: i % 7,
+                    reprojection_error: i as f32 * 0.01,
+                    first_keyframe: i / 10,
+                })
+                .collect()),
+            Message::KeyFramePoseBatch(vec![
+                KeyFramePose { id: 0, rotation: [0.0, 0.0, 0.0, 1.0], translation: [0.0; 3] },
+                KeyFramePose { id: 4, rotation: [0.0, 0.6, 0.0, 0.8], translation: [1.0, 0.0, -0.25] },
+            ]),
+            Message::KeyFrameGraph(vec![
+                GraphEdge { from: 0, to: 1, kind: EdgeKind::SpanningTree, weight: 0 },
+                GraphEdge { from: 0, to: 1, kind: EdgeKind::Covisibility, weight: 250 },
+                GraphEdge { from: 9, to: 0, kind: EdgeKind::Loop, weight: 40 },
+            ]),
+            Message::Observations { keyframe: 9, points: vec![1, 5, 8, 13] },
+            Message::CameraInfo(CameraInfo { width: 752, height: 480, fx: 458.7, fy: 457.3, cx: 367.2, cy: 248.4 }),
+            Message::Image(CameraImage {
+                label: "camera".to_string(),
+                keypoints: vec![
+                    ImageKeypoint { position: [10.5, 20.0], status: KeypointStatus::Matched },
+                    ImageKeypoint { position: [700.0, 0.0], status: KeypointStatus::New },
+                    ImageKeypoint { position: [1.0, 479.5], status: KeypointStatus::Outlier },
+                ],
+                jpeg: vec![0xff, 0xd8, 0xff, 0xd9],
+            }),
+            Message::Image(CameraImage { label: "inliers".to_string(), keypoints: vec![], jpeg: vec![] }),
+            Message::PointRemove(vec![7, 0, u32::MAX]),
+            Message::PointRemove(vec![]),
+        ]
+    }
+
+    fn encode_all(messages: &[Message]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for message in messages {
+            encode(message, &mut bytes).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_split_reads() {
+        let bytes = encode_all(&messages());
+        // every chunk size, including frames straddling reads
+        for chunk in 1..bytes.len() {
+            let mut decoder = StreamDecoder::new();
+            let mut decoded = Vec::new();
+            for part in bytes.chunks(chunk) {
+                decoder.push(part);
+                decoded.extend(decoder.by_ref().map(|m| m.unwrap()));
+            }
+            assert_eq!(decoded, messages(), "chunk size {}", chunk);
+            assert_eq!(decoder.pending(), 0);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let valid = encode_all(&messages()[3..4]);
+
+        // garbage before a frame is reported once and skipped
+        let mut decoder = StreamDecoder::new();
+        decoder.push(b"{point 1 0.0 0.0 0.0}");
+        decoder.push(&valid);
+        assert!(matches!(decoder.next_message(), Err(ProtocolError::BadMagic)));
+        assert_eq!(decoder.next_message().unwrap(), Some(messages()[3].clone()));
+
+        // frames of unknown kinds and versions are skipped whole
+        let mut unknown = valid.clone();
+        unknown[5] = 200;
+        let mut future = valid.clone();
+        future[4] = VERSION + 1;
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&unknown);
+        decoder.push(&future);
+        decoder.push(&valid);
+        assert!(matches!(decoder.next_message(), Err(ProtocolError::UnknownKind(200))));
+        assert!(matches!(decoder.next_message(), Err(ProtocolError::UnsupportedVersion(_))));
+        assert_eq!(decoder.next_message().unwrap(), Some(messages()[3].clone()));
+
+        // a payload that does not match its kind
+        let mut short = valid.clone();
+        short[6] = 12;
+        short.truncate(HEADER_LEN + 12);
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&short);
+        assert!(matches!(
+            decoder.next_message(),
+            Err(ProtocolError::InvalidPayload { kind: MessageKind::Point, len: 12 })
+        ));
+
+        // a relative flag that is neither 0 nor 1
+        let mut flag = encode_all(&messages()[0..1]);
+        flag[HEADER_LEN + 4] = 2;
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&flag);
+        assert!(matches!(
+            decoder.next_message(),
+            Err(ProtocolError::InvalidPayload { kind: MessageKind::BodyTransform, .. })
+        ));
+
+        // a keypoint status out of range, the first status follows the label and count
+        let image = messages().into_iter().find(|m| m.kind() == MessageKind::Image).unwrap();
+        let mut status = encode_all(&[image]);
+        status[HEADER_LEN + 4 + "camera".len() + 4 + 8] = 7;
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&status);
+        assert!(matches!(
+            decoder.next_message(),
+            Err(ProtocolError::InvalidPayload { kind: MessageKind::Image, .. })
+        ));
+
+        // a batch count larger than the payload
+        let mut batch = encode_all(&messages()[6..7]);
+        batch[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&1000u32.to_le_bytes());
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&batch);
+        assert!(matches!(
+            decoder.next_message(),
+            Err(ProtocolError::InvalidPayload { kind: MessageKind::PointBatch, .. })
+        ));
+
+        let mut huge = valid;
+        huge[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&huge);
+        assert!(matches!(decoder.next_message(), Err(ProtocolError::PayloadTooLarge(_))));
+        assert_eq!(decoder.next_message().unwrap(), None);
+    }
+
+    #[test]
+    fn test_encode_too_large() {
+        let image = |len| Message::Image(CameraImage { label: String::new(), keypoints: vec![], jpeg: vec![0; len] });
+        // the empty label and keypoint count and the jpeg length take 12 bytes
+        let mut bytes = Vec::new();
+        encode(&image(MAX_PAYLOAD_LEN - 12), &mut bytes).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + MAX_PAYLOAD_LEN);
+
+        let mut bytes = vec![1, 2, 3];
+        assert!(matches!(
+            encode(&image(MAX_PAYLOAD_LEN - 11), &mut bytes),
+            Err(ProtocolError::PayloadTooLarge(len)) if len == MAX_PAYLOAD_LEN + 1
+        ));
+        assert_eq!(bytes, vec![1, 2, 3]);
+        assert!(matches!(write_message(&mut Vec::new(), &image(MAX_PAYLOAD_LEN)), Err(ProtocolError::PayloadTooLarge(_))));
+    }
+}
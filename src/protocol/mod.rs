@@ -0,0 +1,250 @@
+//! Wire protocol between the SLAM side and the visualizer.
+//!
+//! Every message is sent as one frame: a 10 byte header of `MAGIC`, the protocol
+//! `VERSION`, the message kind and the little endian payload length as a `u32`, followed
+//! by the payload. See `codec` for the encoder and the streaming decoder.
+
+use std::{error::Error, fmt, io};
+
+pub mod codec;
+
+pub const MAGIC: [u8; 4] = *b"BSLM";
+pub const VERSION: u8 = 5;
+pub const HEADER_LEN: usize = 10;
+/// Larger payloads are rejected as corrupt rather than buffered.
+pub const MAX_PAYLOAD_LEN: usize = 16 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MessageKind {
+    BodyTransform = 1,
+    Point = 2,
+    PointBatch = 3,
+    KeyFramePoseBatch = 4,
+    CameraInfo = 5,
+    Reset = 6,
+    KeyFrameGraph = 7,
+    Observations = 8,
+    Image = 9,
+    PointRemove = 10,
+}
+
+impl MessageKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(MessageKind::BodyTransform),
+            2 => Some(MessageKind::Point),
+            3 => Some(MessageKind::PointBatch),
+            4 => Some(MessageKind::KeyFramePoseBatch),
+            5 => Some(MessageKind::CameraInfo),
+            6 => Some(MessageKind::Reset),
+            7 => Some(MessageKind::KeyFrameGraph),
+            8 => Some(MessageKind::Observations),
+            9 => Some(MessageKind::Image),
+            10 => Some(MessageKind::PointRemove),
+            _ => None,
+        }
+    }
+}
+
+/// One map point of a `Message::PointBatch`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PointUpdate {
+    pub id: u32,
+    pub position: [f32; 3],
+    /// RGB
+    pub color: [u8; 3],
+    /// number of keyframes observing the point
+    pub observations: u32,
+    /// mean reprojection error over the observing keyframes, in pixels
+    pub reprojection_error: f32,
+    /// oldest keyframe observing the point, standing in for when it was created
+    pub first_keyframe: u32,
+}
+
+/// Camera to world pose of one keyframe, `rotation` as a quaternion `[x, y, z, w]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyFramePose {
+    pub id: u32,
+    pub rotation: [f32; 4],
+    pub translation: [f32; 3],
+}
+
+/// Pinhole model of the tracked camera, used to draw the frustums.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraInfo {
+    pub width: u32,
+    pub height: u32,
+    pub fx: f32,
+    pub fy: f32,
+    pub cx: f32,
+    pub cy: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum EdgeKind {
+    /// keyframes sharing at least a minimum number of map points
+    Covisibility = 0,
+    /// keyframe to its parent
+    SpanningTree = 1,
+    Loop = 2,
+}
+
+impl EdgeKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(EdgeKind::Covisibility),
+            1 => Some(EdgeKind::SpanningTree),
+            2 => Some(EdgeKind::Loop),
+            _ => None,
+        }
+    }
+}
+
+/// Edge between two keyframes of a `Message::KeyFrameGraph`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphEdge {
+    pub from: u32,
+    pub to: u32,
+    pub kind: EdgeKind,
+    /// number of shared map points
+    pub weight: u32,
+}
+
+/// What tracking made of a keypoint, kept with the frame and sent with its image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum KeypointStatus {
+    /// matched to a map point and kept by PnP
+    Matched = 0,
+    /// not matched to any map point
+    New = 1,
+    /// matched to a map point but rejected by PnP
+    Outlier = 2,
+}
+
+impl KeypointStatus {
+    pub fn from_u8(status: u8) -> Option<Self> {
+        match status {
+            0 => Some(KeypointStatus::Matched),
+            1 => Some(KeypointStatus::New),
+            2 => Some(KeypointStatus::Outlier),
+            _ => None,
+        }
+    }
+}
+
+/// Keypoint of a `CameraImage`, in pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageKeypoint {
+    pub position: [f32; 2],
+    pub status: KeypointStatus,
+}
+
+/// A compressed image with the keypoints found in it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraImage {
+    /// what the image shows, `camera` for the tracked frame or the name of a debug stage,
+    /// the visualizer keeps the latest image of every label
+    pub label: String,
+    pub keypoints: Vec<ImageKeypoint>,
+    /// JPEG encoded
+    pub jpeg: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// camera to world pose of body `id`, or with `relative` the motion
+    /// `T_{c_old, c_new}` to apply on top of its current pose; `rotation` as a quaternion
+    /// `[x, y, z, w]`
+    BodyTransform {
+        id: u32,
+        relative: bool,
+        rotation: [f32; 4],
+        translation: [f32; 3],
+    },
+    Point {
+        id: u32,
+        position: [f32; 3],
+    },
+    /// new or moved map points, encoded as a `u32` count followed by fixed size records
+    PointBatch(Vec<PointUpdate>),
+    KeyFramePoseBatch(Vec<KeyFramePose>),
+    CameraInfo(CameraInfo),
+    /// drops all bodies, points, keyframes and trajectories received so far
+    Reset,
+    /// all edges between keyframes, replacing the previous graph
+    KeyFrameGraph(Vec<GraphEdge>),
+    /// the map points a keyframe observes, replacing the previous list
+    Observations {
+        keyframe: u32,
+        points: Vec<u32>,
+    },
+    Image(CameraImage),
+    /// ids of map points that were culled or merged away
+    PointRemove(Vec<u32>),
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::BodyTransform { .. } => MessageKind::BodyTransform,
+            Message::Point { .. } => MessageKind::Point,
+            Message::PointBatch(_) => MessageKind::PointBatch,
+            Message::KeyFramePoseBatch(_) => MessageKind::KeyFramePoseBatch,
+            Message::CameraInfo(_) => MessageKind::CameraInfo,
+            Message::Reset => MessageKind::Reset,
+            Message::KeyFrameGraph(_) => MessageKind::KeyFrameGraph,
+            Message::Observations { .. } => MessageKind::Observations,
+            Message::Image(_) => MessageKind::Image,
+            Message::PointRemove(_) => MessageKind::PointRemove,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// the stream is not at a frame boundary, the decoder skips to the next magic
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    PayloadTooLarge(usize),
+    /// the payload does not have the size its kind requires
+    InvalidPayload { kind: MessageKind, len: usize },
+    Io(io::Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadMagic => write!(f, "bad frame magic, resynchronizing"),
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}, expected {}", version, VERSION)
+            },
+            ProtocolError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            ProtocolError::PayloadTooLarge(len) => {
+                write!(f, "payload of {} bytes exceeds {} bytes", len, MAX_PAYLOAD_LEN)
+            },
+            ProtocolError::InvalidPayload { kind, len } => {
+                write!(f, "invalid payload of {} bytes for {:?}", len, kind)
+            },
+            ProtocolError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProtocolError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        ProtocolError::Io(err)
+    }
+}
@@ -0,0 +1,163 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::time;
+
+use nalgebra as na;
+
+mod protocol;
+mod slam;
+
+const USAGE: &str = "\
+Usage: runner --path <dataset dir> [options]
+
+Options:
+    --dataset <type>     dataset type, one of: euroc (default: euroc)
+    --path <dir>         dataset directory, e.g. .../MH_01_easy/mav0/cam0
+    --camera <camera>    camera preset (euroc) or path to a json camera config (default: euroc)
+    --config <file>      yaml or toml file overriding the default slam parameters
+    --output <dir>       output directory (default: output)
+    --start <frame>      index of the first frame to process (default: 0)
+    --end <frame>        index one past the last frame to process (default: all)
+    --visualize <addr>   stream the run and the debug images to a running frontend,
+                         e.g. 127.0.0.1:9123
+    --help               print this message";
+
+struct Args {
+    dataset: slam::load_data::DatasetKind,
+    path: String,
+    camera: String,
+    config: Option<PathBuf>,
+    output: PathBuf,
+    start: usize,
+    end: Option<usize>,
+    visualize: Option<String>,
+}
+
+impl Args {
+    fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut dataset = slam::load_data::DatasetKind::Euroc;
+        let mut path = None;
+        let mut camera = "euroc".to_string();
+        let mut config = None;
+        let mut output = PathBuf::from("output");
+        let mut start = 0;
+        let mut end = None;
+        let mut visualize = None;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            let value = args.next().ok_or(format!("missing value for {}", arg))?;
+            match arg.as_str() {
+                "--dataset" => dataset = value.parse()?,
+                "--path" => path = Some(value),
+                "--camera" => camera = value,
+                "--config" => config = Some(PathBuf::from(value)),
+                "--output" => output = PathBuf::from(value),
+                "--start" => start = value.parse()?,
+                "--end" => end = Some(value.parse()?),
+                "--visualize" => visualize = Some(value),
+                _ => return Err(format!("unknown option: {}", arg).into()),
+            }
+        }
+
+        let path = path.ok_or("--path is required")?;
+        if let Some(end) = end {
+            if end <= start {
+                return Err(format!("--end ({}) must be greater than --start ({})", end, start).into());
+            }
+        }
+
+        Ok(Self { dataset, path, camera, config, output, start, end, visualize })
+    }
+}
+
+fn main() {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        },
+    };
+
+    if let Err(e) = run(&args) {
+        eprintln!("run failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Box<dyn Error>> {
+    let data_set = slam::load_data::load_data(args.dataset, &args.path)?;
+    let camera = slam::camera::CameraIntrinsics::from_name_or_file(&args.camera)?;
+    let config = match &args.config {
+        Some(path) => slam::config::SlamConfig::from_file(path)?,
+        None => slam::config::SlamConfig::default(),
+    };
+    let mut tracker = slam::process_image::Tracker::new(camera, config.clone())?;
+    let scale = slam::output::WorldScale::new(&config.output);
+    let mut client = args.visualize.as_deref()
+        .map(|address| slam::visualizer_client::VisualizerClient::new(address, scale))
+        .transpose()?;
+    // stages routed to the `visualizer` sink go to the frontend, the others to their configured sink
+    let (debug_tx, debug_rx) = crossbeam_channel::bounded(8);
+    tracker.set_debug_output(slam::debug::DebugOutput::from_config(
+        &config.debug,
+        client.as_ref().map(|_| debug_tx),
+    ));
+    if client.is_none() && config.debug.uses_sink(slam::debug::DebugSinkKind::Visualizer) {
+        println!("debug stages routed to the visualizer are dropped without --visualize");
+    }
+    std::fs::create_dir_all(&args.output)?;
+
+    let end = args.end.unwrap_or(data_set.len()).min(data_set.len());
+    println!("processing frames [{}, {}) of {}", args.start, end, data_set.len());
+
+    let mut trajectory: Vec<(time::Duration, na::Isometry3<f64>)> = Vec::new();
+    let mut timing = slam::output::TimingStats::new();
+    let mut failures = 0;
+
+    for data in data_set.into_iter().take(end).skip(args.start) {
+        let timestamp = data.timestamp;
+        let start = time::Instant::now();
+        let pose = tracker.track_and_optimize(data);
+        timing.push(timestamp, start.elapsed());
+
+        if let Some(client) = client.as_mut() {
+            let tracked = pose.as_ref().ok().filter(|_| tracker.initializer.done());
+            client.publish(tracked, &tracker.map);
+            client.publish_frame(&tracker.curr_frame);
+            for image in debug_rx.try_iter() {
+                client.publish_image(image.stage.name(), &image.img, Vec::new());
+            }
+        }
+
+        match pose {
+            Ok(pose) => {
+                if tracker.initializer.done() {
+                    trajectory.push((timestamp, pose));
+                }
+            },
+            Err(e) => {
+                println!("track failed at {:.9}: {}", timestamp.as_secs_f64(), e);
+                failures += 1;
+            },
+        }
+    }
+
+    slam::output::save_trajectory_tum(&args.output.join("trajectory.txt"), &trajectory, scale)?;
+    slam::output::save_map_json(&tracker.map, &args.output.join("map.json"), scale)?;
+    timing.save(&args.output.join("timing.txt"))?;
+
+    println!("{}", timing.summary());
+    println!(
+        "tracked: {}, failed: {}, keyframes: {}, mappoints: {}",
+        trajectory.len(), failures, tracker.map.keyframes.len(), tracker.map.mappoints.len()
+    );
+    println!("results written to {}", args.output.display());
+
+    Ok(())
+}
@@ -1,4 +1,8 @@
+use std::error::Error;
+use std::fs;
+
 use nalgebra as na;
+use serde::Deserialize;
 
 /*fx, fy, cx, cy, k1, k2, p1, p2*/
 type Vector8<T> = na::Matrix<T, na::U8, na::U1, na::ArrayStorage<T, 8, 1>>;
@@ -17,7 +21,62 @@ pub struct CameraIntrinsics {
     pub k_mat : na::Matrix3<f64>,
 }
 
+/// Radial-tangential distortion coefficients.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Distortion {
+    pub k1: f64,
+    pub k2: f64,
+    pub p1: f64,
+    pub p2: f64,
+}
+
+/// Camera parameters as they appear in a camera config file.
+#[derive(Deserialize)]
+struct CameraConfig {
+    fx: f64,
+    fy: f64,
+    cx: f64,
+    cy: f64,
+    #[serde(default)]
+    k1: f64,
+    #[serde(default)]
+    k2: f64,
+    #[serde(default)]
+    p1: f64,
+    #[serde(default)]
+    p2: f64,
+}
+
 impl CameraIntrinsics {
+    /// Intrinsics from `[fx, fy, cx, cy]` and the distortion coefficients.
+    pub fn new([fx, fy, cx, cy]: [f64; 4], distortion: Distortion) -> Self {
+        let Distortion { k1, k2, p1, p2 } = distortion;
+        Self { fx, fy, cx, cy, k1, k2, p1, p2,
+            k_mat: na::Matrix3::<f64>::new(
+                fx, 0.0, cx,
+                0.0, fy, cy,
+                0.0, 0.0, 1.0,
+            )
+        }
+    }
+
+    /// Loads intrinsics from a json file such as
+    /// `{"fx": 458.654, "fy": 457.296, "cx": 367.215, "cy": 248.375, "k1": -0.283, "k2": 0.074, "p1": 0.0002, "p2": 0.00002}`.
+    /// Distortion coefficients default to zero.
+    pub fn from_json_file(path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = fs::File::open(path)?;
+        let c: CameraConfig = serde_json::from_reader(file)?;
+        Ok(Self::new([c.fx, c.fy, c.cx, c.cy], Distortion { k1: c.k1, k2: c.k2, p1: c.p1, p2: c.p2 }))
+    }
+
+    /// Resolves a camera argument: either a preset name (`euroc`) or a path to a json config.
+    pub fn from_name_or_file(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "euroc" => Ok(Self::new_euroc()),
+            path => Self::from_json_file(path),
+        }
+    }
+
     pub fn new_euroc() -> Self {
         Self { fx: 458.654, fy: 457.296, cx: 367.215, cy: 248.375, k1: -0.28340811, k2: 0.07395907, p1: 0.00019359, p2: 1.76187114e-05 ,
             k_mat: na::Matrix3::<f64>::new(
@@ -28,6 +87,13 @@ impl CameraIntrinsics {
         }
     }
 
+    /// Projects a point given in camera coordinates onto the image plane, ignoring distortion.
+    pub fn projection(&self, p_c: &na::Vector3<f64>) -> na::Point2<f64> {
+        let u = self.fx * p_c.x / p_c.z + self.cx;
+        let v = self.fy * p_c.y / p_c.z + self.cy;
+        na::Point2::<f64>::new(u, v)
+    }
+
     pub fn inv_projection(&self, pt: &na::Point2<f64>) -> na::Point2<f64> {
         let x = (pt.x - self.cx) / self.fx;
         let y = (pt.y - self.cy) / self.fy;
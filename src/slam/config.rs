@@ -0,0 +1,563 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::debug::{DebugSinkKind, DebugStage};
+use super::ransac::RobustMethod;
+use super::triangulation::TriangulationMethod;
+
+/// Detector/descriptor backends selectable with `feature.extractor`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtractorKind {
+    /// FAST on a grid over an image pyramid with ORB descriptors, see `GridExtractor`
+    Grid,
+    /// OpenCV ORB detector and descriptor
+    Orb,
+    /// Shi-Tomasi corners with ORB descriptors
+    ShiTomasi,
+    /// FAST corners with BRIEF descriptors, needs the `contrib` feature and an OpenCV
+    /// build with opencv_contrib
+    FastBrief,
+    Akaze,
+    Brisk,
+    /// needs OpenCV 4.4 or later, where SIFT moved into the main repository
+    Sift,
+}
+
+/// How features are associated between consecutive frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackingMode {
+    /// detect and describe every frame, then match descriptors
+    Descriptor,
+    /// follow features with pyramidal Lucas-Kanade, detect only in sparse grid cells
+    Klt,
+}
+
+/// All tunable parameters of the pipeline.
+///
+/// Every section has defaults, so a config file only needs to list the values it changes:
+///
+/// ```yaml
+/// feature:
+///   grid_cols: 20
+/// init:
+///   max_depth: 80.0
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlamConfig {
+    pub feature: FeatureConfig,
+    pub matcher: MatcherConfig,
+    pub tracking: TrackingConfig,
+    pub init: InitConfig,
+    pub mapping: MappingConfig,
+    pub optimize: OptimizeConfig,
+    pub ransac: RansacConfig,
+    pub output: OutputConfig,
+    pub debug: DebugConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    /// detector/descriptor backend
+    pub extractor: ExtractorKind,
+    /// number of grid cells along the image width
+    pub grid_cols: usize,
+    /// number of grid cells along the image height
+    pub grid_rows: usize,
+    /// feature budget of each grid cell
+    pub features_per_cell: usize,
+    /// maximum number of corners returned by good_features_to_track (shi_tomasi extractor)
+    pub max_corners: usize,
+    pub quality_level: f64,
+    pub min_distance: f64,
+    pub scale_factor: f32,
+    pub n_levels: usize,
+    pub edge_threshold: i32,
+    pub patch_size: i32,
+    pub fast_threshold: i32,
+    /// FAST threshold retried in grid cells where `fast_threshold` finds nothing
+    pub min_fast_threshold: i32,
+    /// distribute keypoints of each pyramid level with a quadtree instead of per cell
+    pub use_quadtree: bool,
+    /// detector response threshold of the akaze extractor
+    pub akaze_threshold: f32,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            extractor: ExtractorKind::Grid,
+            grid_cols: 15,
+            grid_rows: 10,
+            features_per_cell: 6,
+            max_corners: 1000,
+            quality_level: 0.01,
+            min_distance: 10.0,
+            scale_factor: 1.2,
+            n_levels: 8,
+            edge_threshold: 31,
+            patch_size: 31,
+            fast_threshold: 20,
+            min_fast_threshold: 7,
+            use_quadtree: true,
+            akaze_threshold: 0.001,
+        }
+    }
+}
+
+impl FeatureConfig {
+    pub fn num_features(&self) -> usize {
+        self.grid_cols * self.grid_rows * self.features_per_cell
+    }
+
+    /// Octaves, halvings of the image size, spanned by the pyramid of `n_levels` levels,
+    /// for the detectors with a pyramid of octaves.
+    pub fn num_octaves(&self) -> usize {
+        let span = (self.scale_factor as f64).powi(self.n_levels as i32 - 1);
+        (span.log2().ceil() as usize).max(1)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatcherConfig {
+    /// binary descriptor matches with a larger Hamming distance are dropped
+    pub max_hamming_distance: f32,
+    /// float descriptor matches with a larger L2 distance are dropped
+    pub max_l2_distance: f32,
+    /// epipolar distance in pixels used by the fundamental matrix RANSAC
+    pub ransac_threshold: f64,
+    pub ransac_confidence: f64,
+    /// search radius in pixels around a projected map point, scaled by the octave
+    pub projection_radius: f32,
+    /// best match must be closer than `nn_ratio` times the second best
+    pub nn_ratio: f64,
+    /// drop projection matches that disagree with the dominant keypoint rotation
+    pub check_orientation: bool,
+    pub orientation_bins: usize,
+}
+
+impl Default for MatcherConfig {
+    fn default() -> Self {
+        Self {
+            max_hamming_distance: 30.0,
+            max_l2_distance: 250.0,
+            ransac_threshold: 1.0,
+            ransac_confidence: 0.99,
+            projection_radius: 15.0,
+            nn_ratio: 0.9,
+            check_orientation: true,
+            orientation_bins: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackingConfig {
+    pub mode: TrackingMode,
+    /// side of the Lucas-Kanade search window in pixels
+    pub klt_window: i32,
+    /// number of pyramid levels used by Lucas-Kanade, 0 means no pyramid
+    pub klt_levels: i32,
+    pub klt_iterations: i32,
+    /// maximum distance in pixels between a point and its forward-backward tracked position
+    pub klt_max_fb_error: f32,
+    /// grid cells holding fewer tracks than this are topped up to `feature.features_per_cell`
+    pub klt_min_features_per_cell: usize,
+    /// tracking fails when fewer map points than this survive PnP
+    pub min_inliers: usize,
+    /// PnP RANSAC threshold in pixels
+    pub pnp_reprojection_error: f32,
+    /// iteration cap of the PnP RANSAC over P3P samples
+    pub pnp_iterations: i32,
+    /// seed matching and PnP with a constant velocity prediction instead of the last pose
+    pub use_motion_model: bool,
+    /// damping of the predicted motion, 1.0 is a pure constant velocity model
+    pub motion_decay: f64,
+}
+
+impl Default for TrackingConfig {
+    fn default() -> Self {
+        Self {
+            mode: TrackingMode::Descriptor,
+            klt_window: 21,
+            klt_levels: 3,
+            klt_iterations: 30,
+            klt_max_fb_error: 1.0,
+            klt_min_features_per_cell: 3,
+            min_inliers: 20,
+            pnp_reprojection_error: 4.0,
+            pnp_iterations: 100,
+            use_motion_model: true,
+            motion_decay: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InitConfig {
+    /// RANSAC threshold in pixels of the homography and essential matrix estimation
+    pub ransac_threshold: f64,
+    pub ransac_confidence: f64,
+    /// standard deviation in pixels of a match, used to score the two models
+    pub sigma: f64,
+    /// the homography is chosen when `S_H / (S_H + S_F)` exceeds this ratio
+    pub homography_ratio: f64,
+    /// fewer matches with the first frame re-anchor it to the current frame
+    pub min_matches: usize,
+    /// mean keypoint displacement in pixels required before attempting initialization
+    pub min_pixel_parallax: f64,
+    /// minimum median triangulation angle in degrees
+    pub min_parallax_deg: f64,
+    /// minimum number of triangulated points
+    pub min_triangulated: usize,
+    /// failed attempts after which the first frame is re-anchored
+    pub max_attempts: usize,
+    /// minimum ratio of points passing the cheirality check for the best pose
+    pub min_inlier_ratio: f64,
+    /// triangulated points farther than this (in units of the initial baseline) are rejected
+    pub max_depth: f64,
+    pub triangulation: TriangulationMethod,
+    /// triangulated points reprojecting farther than this in pixels in either view are rejected
+    pub max_reprojection_error: f64,
+    /// triangulated points whose depth standard deviation relative to their depth exceeds
+    /// this are rejected, see `Triangulation::relative_depth_sigma`
+    pub max_depth_sigma: f64,
+}
+
+impl Default for InitConfig {
+    fn default() -> Self {
+        Self {
+            ransac_threshold: 1.0,
+            ransac_confidence: 0.999,
+            sigma: 1.0,
+            homography_ratio: 0.45,
+            min_matches: 100,
+            min_pixel_parallax: 1.0,
+            min_parallax_deg: 1.0,
+            min_triangulated: 50,
+            max_attempts: 5,
+            min_inlier_ratio: 0.5,
+            max_depth: 50.0,
+            triangulation: TriangulationMethod::Optimal,
+            max_reprojection_error: 2.0,
+            max_depth_sigma: 0.5,
+        }
+    }
+}
+
+/// When tracking inserts keyframes and how it triangulates their new map points.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappingConfig {
+    /// frames after a keyframe before the next one can be inserted
+    pub keyframe_min_frames: usize,
+    /// a keyframe is inserted after this many frames even if tracking is still good
+    pub keyframe_max_frames: usize,
+    /// a keyframe is inserted once a frame tracks fewer than this fraction of the map
+    /// points of the latest keyframe
+    pub keyframe_tracked_ratio: f64,
+    /// covisible keyframes the new points of a keyframe are triangulated against
+    pub triangulation_neighbors: usize,
+    pub triangulation: TriangulationMethod,
+    /// minimum angle in degrees between the viewing rays of a new point
+    pub min_parallax_deg: f64,
+}
+
+impl Default for MappingConfig {
+    fn default() -> Self {
+        Self {
+            keyframe_min_frames: 2,
+            keyframe_max_frames: 20,
+            keyframe_tracked_ratio: 0.9,
+            triangulation_neighbors: 10,
+            triangulation: TriangulationMethod::Optimal,
+            min_parallax_deg: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OptimizeConfig {
+    /// standard deviation of a keypoint measurement in pixels of its pyramid level,
+    /// weights the bundle adjustment per level and sets its chi-square outlier threshold
+    pub pixel_sigma: f64,
+}
+
+impl Default for OptimizeConfig {
+    fn default() -> Self {
+        Self {
+            pixel_sigma: 1.0,
+        }
+    }
+}
+
+/// Robust estimation shared by every RANSAC call, thresholds and confidences are
+/// set per call site.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RansacConfig {
+    pub method: RobustMethod,
+    pub max_iterations: usize,
+    /// re-fit every new best model to its inliers
+    pub local_optimization: bool,
+    /// seed of the sampler, runs are reproducible for a given seed
+    pub seed: u64,
+}
+
+impl Default for RansacConfig {
+    fn default() -> Self {
+        Self {
+            method: RobustMethod::Prosac,
+            max_iterations: 2000,
+            local_optimization: true,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// output units per map unit, applied to every pose and point written or published.
+    /// A monocular map is in units of the median scene depth seen by the first keyframe,
+    /// set this to that depth in meters (e.g. from ground truth) for metric output.
+    pub world_scale: f64,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            world_scale: 1.0,
+        }
+    }
+}
+
+/// Debug imagery, off unless a sink is chosen.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebugConfig {
+    /// sink of every stage without an entry in `stage_sinks`
+    pub sink: DebugSinkKind,
+    /// per-stage override of `sink`, e.g. reprojections to the visualizer and inliers to disk
+    pub stage_sinks: HashMap<DebugStage, DebugSinkKind>,
+    /// root directory of the `disk` sink, one subdirectory per stage
+    pub output_dir: String,
+    /// stages that produce images
+    pub stages: Vec<DebugStage>,
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        Self {
+            sink: DebugSinkKind::None,
+            stage_sinks: HashMap::new(),
+            output_dir: "debug".to_string(),
+            stages: vec![DebugStage::Inliers],
+        }
+    }
+}
+
+impl DebugConfig {
+    pub fn sink_for(&self, stage: DebugStage) -> DebugSinkKind {
+        self.stage_sinks.get(&stage).copied().unwrap_or(self.sink)
+    }
+
+    /// Whether any enabled stage goes to a sink of this kind.
+    pub fn uses_sink(&self, kind: DebugSinkKind) -> bool {
+        self.stages.iter().any(|&stage| self.sink_for(stage) == kind)
+    }
+}
+
+impl SlamConfig {
+    /// Loads a config from a `.yaml`/`.yml` or `.toml` file and validates it.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let config: SlamConfig = match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&text)?,
+            Some("toml") => toml::from_str(&text)?,
+            _ => return Err(format!("unsupported config format: {}", path.display()).into()),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let feature = &self.feature;
+        if feature.grid_cols == 0 || feature.grid_rows == 0 {
+            return Err("feature.grid_cols and feature.grid_rows must be positive".into());
+        }
+        if feature.num_features() == 0 || feature.max_corners == 0 {
+            return Err("feature budget must be positive".into());
+        }
+        if feature.quality_level <= 0.0 || feature.quality_level >= 1.0 {
+            return Err("feature.quality_level must be in (0, 1)".into());
+        }
+        if feature.scale_factor <= 1.0 {
+            return Err("feature.scale_factor must be greater than 1".into());
+        }
+        if feature.n_levels == 0 {
+            return Err("feature.n_levels must be positive".into());
+        }
+        if feature.min_fast_threshold <= 0 || feature.min_fast_threshold > feature.fast_threshold {
+            return Err("feature.min_fast_threshold must be in (0, feature.fast_threshold]".into());
+        }
+        if feature.akaze_threshold <= 0.0 {
+            return Err("feature.akaze_threshold must be positive".into());
+        }
+
+        if self.matcher.max_hamming_distance <= 0.0 || self.matcher.max_l2_distance <= 0.0 {
+            return Err("matcher.max_hamming_distance and matcher.max_l2_distance must be positive".into());
+        }
+        check_ransac("matcher", self.matcher.ransac_threshold, self.matcher.ransac_confidence)?;
+        if self.matcher.projection_radius <= 0.0 {
+            return Err("matcher.projection_radius must be positive".into());
+        }
+        if self.matcher.nn_ratio <= 0.0 || self.matcher.nn_ratio > 1.0 {
+            return Err("matcher.nn_ratio must be in (0, 1]".into());
+        }
+        if self.matcher.orientation_bins == 0 {
+            return Err("matcher.orientation_bins must be positive".into());
+        }
+
+        let tracking = &self.tracking;
+        if tracking.klt_window < 3 || tracking.klt_levels < 0 || tracking.klt_iterations <= 0 {
+            return Err("tracking.klt_window must be at least 3, klt_levels non-negative and klt_iterations positive".into());
+        }
+        if tracking.klt_max_fb_error <= 0.0 {
+            return Err("tracking.klt_max_fb_error must be positive".into());
+        }
+        if tracking.klt_min_features_per_cell > self.feature.features_per_cell {
+            return Err("tracking.klt_min_features_per_cell must be at most feature.features_per_cell".into());
+        }
+        if tracking.min_inliers < 4 || tracking.pnp_reprojection_error <= 0.0 || tracking.pnp_iterations <= 0 {
+            return Err("tracking.min_inliers must be at least 4, pnp_reprojection_error and pnp_iterations positive".into());
+        }
+        if tracking.motion_decay < 0.0 || tracking.motion_decay > 1.0 {
+            return Err("tracking.motion_decay must be in [0, 1]".into());
+        }
+
+        check_ransac("init", self.init.ransac_threshold, self.init.ransac_confidence)?;
+        if self.init.min_inlier_ratio <= 0.0 || self.init.min_inlier_ratio > 1.0 {
+            return Err("init.min_inlier_ratio must be in (0, 1]".into());
+        }
+        if self.init.max_depth <= 0.0 {
+            return Err("init.max_depth must be positive".into());
+        }
+        if self.init.max_reprojection_error <= 0.0 || self.init.max_depth_sigma <= 0.0 {
+            return Err("init.max_reprojection_error and init.max_depth_sigma must be positive".into());
+        }
+        if self.init.sigma <= 0.0 || self.init.homography_ratio <= 0.0 || self.init.homography_ratio >= 1.0 {
+            return Err("init.sigma must be positive and init.homography_ratio in (0, 1)".into());
+        }
+        if self.init.min_matches < 8 || self.init.min_triangulated == 0 || self.init.max_attempts == 0 {
+            return Err("init.min_matches must be at least 8, min_triangulated and max_attempts positive".into());
+        }
+        if self.init.min_pixel_parallax < 0.0 || self.init.min_parallax_deg < 0.0 {
+            return Err("init.min_pixel_parallax and init.min_parallax_deg must be non-negative".into());
+        }
+
+        let mapping = &self.mapping;
+        if mapping.keyframe_max_frames < mapping.keyframe_min_frames {
+            return Err("mapping.keyframe_max_frames must be at least mapping.keyframe_min_frames".into());
+        }
+        if mapping.keyframe_tracked_ratio <= 0.0 || mapping.keyframe_tracked_ratio > 1.0 {
+            return Err("mapping.keyframe_tracked_ratio must be in (0, 1]".into());
+        }
+        if mapping.min_parallax_deg < 0.0 {
+            return Err("mapping.min_parallax_deg must be non-negative".into());
+        }
+
+        if self.optimize.pixel_sigma <= 0.0 {
+            return Err("optimize.pixel_sigma must be positive".into());
+        }
+
+        if self.output.world_scale <= 0.0 {
+            return Err("output.world_scale must be positive".into());
+        }
+
+        if self.ransac.max_iterations == 0 {
+            return Err("ransac.max_iterations must be positive".into());
+        }
+
+        if self.debug.uses_sink(DebugSinkKind::Disk) && self.debug.output_dir.is_empty() {
+            return Err("debug.output_dir must not be empty".into());
+        }
+
+        Ok(())
+    }
+}
+
+fn check_ransac(section: &str, threshold: f64, confidence: f64) -> Result<(), Box<dyn Error>> {
+    if threshold <= 0.0 {
+        return Err(format!("{}.ransac_threshold must be positive", section).into());
+    }
+    if confidence <= 0.0 || confidence >= 1.0 {
+        return Err(format!("{}.ransac_confidence must be in (0, 1)", section).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_yaml() -> Result<(), Box<dyn Error>> {
+        let config: SlamConfig = serde_yaml::from_str("feature:\n  grid_cols: 20\ninit:\n  max_depth: 80.0\n")?;
+        config.validate()?;
+        assert_eq!(config.feature.grid_cols, 20);
+        assert_eq!(config.feature.grid_rows, FeatureConfig::default().grid_rows);
+        assert_eq!(config.init.max_depth, 80.0);
+        assert_eq!(config.matcher.max_hamming_distance, 30.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_toml_and_validation() -> Result<(), Box<dyn Error>> {
+        let config: SlamConfig = toml::from_str("[feature]\nextractor = \"sift\"\n")?;
+        assert_eq!(config.feature.extractor, ExtractorKind::Sift);
+        let config: SlamConfig = toml::from_str("[matcher]\nransac_confidence = 1.5\n")?;
+        assert!(config.validate().is_err());
+        assert!(serde_yaml::from_str::<SlamConfig>("feature:\n  grid_colz: 20\n").is_err());
+        let config: SlamConfig = toml::from_str("[feature]\nakaze_threshold = 0.0\n")?;
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_num_octaves() {
+        // 8 levels at 1.2 span a factor of 3.6
+        assert_eq!(FeatureConfig::default().num_octaves(), 2);
+        let single = FeatureConfig { n_levels: 1, ..FeatureConfig::default() };
+        assert_eq!(single.num_octaves(), 1);
+        let wide = FeatureConfig { scale_factor: 2.0, n_levels: 5, ..FeatureConfig::default() };
+        assert_eq!(wide.num_octaves(), 4);
+    }
+
+    #[test]
+    fn test_debug_stage_sinks() -> Result<(), Box<dyn Error>> {
+        let config: SlamConfig = serde_yaml::from_str(
+            "debug:\n  sink: disk\n  stages: [inliers, reprojections]\n  stage_sinks:\n    reprojections: visualizer\n",
+        )?;
+        config.validate()?;
+        assert_eq!(config.debug.sink_for(DebugStage::Inliers), DebugSinkKind::Disk);
+        assert_eq!(config.debug.sink_for(DebugStage::Reprojections), DebugSinkKind::Visualizer);
+        assert!(config.debug.uses_sink(DebugSinkKind::Visualizer));
+
+        let config: SlamConfig = toml::from_str("[debug]\noutput_dir = \"\"\n[debug.stage_sinks]\ninliers = \"disk\"\n")?;
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use std::error::Error;
-use opencv::{prelude::*, core::Point2f};
+use opencv::{prelude::*, core::{Point2f, Vector}};
 use nalgebra as na;
 
 pub fn cv_mat_to_na_mat(mat: &Mat) -> Result<na::Matrix3<f64>, Box<dyn Error>> {
@@ -10,6 +10,18 @@ pub fn cv_mat_to_na_mat(mat: &Mat) -> Result<na::Matrix3<f64>, Box<dyn Error>> {
     ]))
 }
 
+pub fn na_mat_to_cv_mat(mat: &na::Matrix3<f64>) -> Result<Mat, Box<dyn Error>> {
+    Ok(Mat::from_slice_2d(&[
+        [mat.m11, mat.m12, mat.m13],
+        [mat.m21, mat.m22, mat.m23],
+        [mat.m31, mat.m32, mat.m33],
+    ])?)
+}
+
 pub fn cv_point2f_to_na_point2f(pt: &Point2f) -> na::Point2<f64> {
     na::Point2::<f64>::new(pt.x as f64, pt.y as f64)
-}
\ No newline at end of file
+}
+
+pub fn cv_points_to_na_points(pts: &Vector<Point2f>) -> Vec<na::Point2<f64>> {
+    pts.iter().map(|pt| cv_point2f_to_na_point2f(&pt)).collect()
+}
@@ -0,0 +1,225 @@
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::PathBuf,
+    time,
+};
+
+use crossbeam_channel::{Sender, TrySendError};
+use opencv::{
+    prelude::*,
+    core,
+    imgcodecs,
+    imgproc,
+};
+use serde::{Deserialize, Serialize};
+
+use super::config::DebugConfig;
+
+/// Pipeline stages that can produce debug imagery.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DebugStage {
+    /// all matches used for initialization
+    Matches,
+    /// matches consistent with the selected two-view model
+    Inliers,
+    /// observed keypoints against reprojected map points
+    Reprojections,
+}
+
+impl DebugStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DebugStage::Matches => "matches",
+            DebugStage::Inliers => "inliers",
+            DebugStage::Reprojections => "reprojections",
+        }
+    }
+}
+
+/// Where debug images go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DebugSinkKind {
+    None,
+    Disk,
+    /// the image view of a connected frontend, see `ChannelSink`
+    Visualizer,
+}
+
+pub trait DebugSink {
+    fn emit(&mut self, stage: DebugStage, timestamp: time::Duration, img: &core::Mat) -> Result<(), Box<dyn Error>>;
+}
+
+/// Discards everything.
+pub struct NullSink;
+
+impl DebugSink for NullSink {
+    fn emit(&mut self, _stage: DebugStage, _timestamp: time::Duration, _img: &core::Mat) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// Writes images to `<dir>/<stage>/<timestamp in ns>.png`.
+pub struct DiskSink {
+    dir: PathBuf,
+}
+
+impl DiskSink {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl DebugSink for DiskSink {
+    fn emit(&mut self, stage: DebugStage, timestamp: time::Duration, img: &core::Mat) -> Result<(), Box<dyn Error>> {
+        let dir = self.dir.join(stage.name());
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.png", timestamp.as_nanos()));
+        imgcodecs::imwrite(path.to_str().ok_or("invalid debug output path")?, img, &core::Vector::default())?;
+        Ok(())
+    }
+}
+
+pub struct DebugImage {
+    pub stage: DebugStage,
+    pub timestamp: time::Duration,
+    pub img: core::Mat,
+}
+
+/// Hands images over to another thread, e.g. the one feeding the visualizer.
+/// Images are dropped rather than stalling the pipeline when the receiver lags behind.
+pub struct ChannelSink {
+    tx: Sender<DebugImage>,
+}
+
+impl ChannelSink {
+    pub fn new(tx: Sender<DebugImage>) -> Self {
+        Self { tx }
+    }
+}
+
+impl DebugSink for ChannelSink {
+    fn emit(&mut self, stage: DebugStage, timestamp: time::Duration, img: &core::Mat) -> Result<(), Box<dyn Error>> {
+        let image = DebugImage { stage, timestamp, img: img.try_clone()? };
+        match self.tx.try_send(image) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Disconnected(_)) => Err("debug image receiver disconnected".into()),
+        }
+    }
+}
+
+type SharedSink = Rc<RefCell<Box<dyn DebugSink>>>;
+
+/// The sinks of the enabled stages, shared by every component of the pipeline.
+#[derive(Clone)]
+pub struct DebugOutput {
+    routes: Vec<(DebugStage, SharedSink)>,
+}
+
+impl DebugOutput {
+    /// Sends all `stages` to a single sink.
+    pub fn new(sink: Box<dyn DebugSink>, stages: Vec<DebugStage>) -> Self {
+        let sink: SharedSink = Rc::new(RefCell::new(sink));
+        Self {
+            routes: stages.into_iter().map(|stage| (stage, sink.clone())).collect(),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Routes every configured stage to its sink. Stages going to the `visualizer`
+    /// sink are dropped when no `visualizer` channel is given.
+    pub fn from_config(config: &DebugConfig, visualizer: Option<Sender<DebugImage>>) -> Self {
+        let mut sinks: HashMap<DebugSinkKind, SharedSink> = HashMap::new();
+        let mut routes = Vec::new();
+        for &stage in &config.stages {
+            let kind = config.sink_for(stage);
+            if !sinks.contains_key(&kind) {
+                let sink: Box<dyn DebugSink> = match (kind, &visualizer) {
+                    (DebugSinkKind::None, _) | (DebugSinkKind::Visualizer, None) => continue,
+                    (DebugSinkKind::Disk, _) => Box::new(DiskSink::new(PathBuf::from(&config.output_dir))),
+                    (DebugSinkKind::Visualizer, Some(tx)) => Box::new(ChannelSink::new(tx.clone())),
+                };
+                sinks.insert(kind, Rc::new(RefCell::new(sink)));
+            }
+            routes.push((stage, sinks[&kind].clone()));
+        }
+        Self { routes }
+    }
+
+    pub fn enabled(&self, stage: DebugStage) -> bool {
+        self.routes.iter().any(|(s, _)| *s == stage)
+    }
+
+    /// Renders and emits an image if `stage` is enabled. Debug output never fails
+    /// the pipeline, errors are only reported.
+    pub fn emit<F>(&self, stage: DebugStage, timestamp: time::Duration, render: F)
+    where
+        F: FnOnce() -> Result<core::Mat, Box<dyn Error>>,
+    {
+        if !self.enabled(stage) {
+            return;
+        }
+        let result = render().and_then(|img| {
+            self.routes.iter()
+                .filter(|(s, _)| *s == stage)
+                .try_for_each(|(_, sink)| sink.borrow_mut().emit(stage, timestamp, &img))
+        });
+        if let Err(e) = result {
+            println!("debug output for {} failed: {}", stage.name(), e);
+        }
+    }
+}
+
+fn to_color(img: &core::Mat) -> Result<core::Mat, Box<dyn Error>> {
+    let mut color = core::Mat::default();
+    if img.channels() == 1 {
+        imgproc::cvt_color(img, &mut color, imgproc::COLOR_GRAY2BGR, 0)?;
+    } else {
+        color = img.try_clone()?;
+    }
+    Ok(color)
+}
+
+fn to_point2i(pt: &core::Point2f) -> core::Point2i {
+    core::Point2i::new(pt.x.round() as i32, pt.y.round() as i32)
+}
+
+/// Draws a line from every point of the previous image to its match in `img`.
+pub fn draw_tracks(
+    img: &core::Mat,
+    from: &core::Vector<core::Point2f>,
+    to: &core::Vector<core::Point2f>,
+    color: core::Scalar,
+) -> Result<core::Mat, Box<dyn Error>> {
+    let mut out = to_color(img)?;
+    for (from, to) in from.iter().zip(to.iter()) {
+        imgproc::line(&mut out, to_point2i(&from), to_point2i(&to), color, 2, imgproc::LINE_8, 0)?;
+    }
+    Ok(out)
+}
+
+/// Draws observed keypoints as green circles and the projections of their map points
+/// as red crosses, joined by a line.
+pub fn draw_reprojections(
+    img: &core::Mat,
+    observed: &[core::Point2f],
+    projected: &[core::Point2f],
+) -> Result<core::Mat, Box<dyn Error>> {
+    let mut out = to_color(img)?;
+    let green = core::Scalar::new(0.0, 255.0, 0.0, 255.0);
+    let red = core::Scalar::new(0.0, 0.0, 255.0, 255.0);
+    for (observed, projected) in observed.iter().zip(projected.iter()) {
+        imgproc::circle(&mut out, to_point2i(observed), 3, green, 1, imgproc::LINE_8, 0)?;
+        imgproc::draw_marker(&mut out, to_point2i(projected), red, imgproc::MARKER_CROSS, 6, 1, imgproc::LINE_8)?;
+        imgproc::line(&mut out, to_point2i(observed), to_point2i(projected), red, 1, imgproc::LINE_8, 0)?;
+    }
+    Ok(out)
+}
@@ -0,0 +1,116 @@
+use std::error::Error;
+
+use opencv::{
+    prelude::*,
+    core,
+    features2d,
+    imgproc,
+};
+#[cfg(feature = "contrib")]
+use opencv::xfeatures2d;
+
+use super::super::config::FeatureConfig;
+use super::{DescriptorKind, FeatureExtractor};
+
+/// FAST corners described with BRIEF, which is only in opencv_contrib.
+#[cfg(feature = "contrib")]
+pub struct FastBriefExtractor {
+    brief: core::Ptr<xfeatures2d::BriefDescriptorExtractor>,
+    fast_threshold: i32,
+    max_features: usize,
+}
+
+#[cfg(feature = "contrib")]
+impl FastBriefExtractor {
+    pub fn new(config: &FeatureConfig) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            brief: xfeatures2d::BriefDescriptorExtractor::create(32, false)?,
+            fast_threshold: config.fast_threshold,
+            max_features: config.num_features(),
+        })
+    }
+}
+
+#[cfg(feature = "contrib")]
+impl FeatureExtractor for FastBriefExtractor {
+    fn extract(
+        &mut self,
+        img: &core::Mat,
+    ) -> Result<(core::Vector<core::KeyPoint>, core::Mat), Box<dyn Error>> {
+        let mut keypoints = core::Vector::<core::KeyPoint>::default();
+        features2d::fast(img, &mut keypoints, self.fast_threshold, true)?;
+        let mut keypoints = super::grid::retain_best(keypoints.to_vec(), self.max_features)
+            .into_iter()
+            .collect::<core::Vector<core::KeyPoint>>();
+
+        let mut descriptors = core::Mat::default();
+        self.brief.compute(img, &mut keypoints, &mut descriptors)?;
+
+        Ok((keypoints, descriptors))
+    }
+
+    fn descriptor_kind(&self) -> DescriptorKind {
+        DescriptorKind::Binary
+    }
+}
+
+/// Shi-Tomasi corners described with ORB, the original front end of the tracker.
+pub struct ShiTomasiOrbExtractor {
+    orb: core::Ptr<features2d::ORB>,
+    max_corners: i32,
+    quality_level: f64,
+    min_distance: f64,
+}
+
+impl ShiTomasiOrbExtractor {
+    pub fn new(config: &FeatureConfig) -> Result<Self, Box<dyn Error>> {
+        let orb = features2d::ORB::create(
+            config.num_features() as i32,
+            config.scale_factor,
+            config.n_levels as i32,
+            config.edge_threshold,
+            0,
+            2,
+            features2d::ORB_ScoreType::HARRIS_SCORE,
+            config.patch_size,
+            config.fast_threshold,
+        )?;
+        Ok(Self {
+            orb,
+            max_corners: config.max_corners as i32,
+            quality_level: config.quality_level,
+            min_distance: config.min_distance,
+        })
+    }
+}
+
+impl FeatureExtractor for ShiTomasiOrbExtractor {
+    fn extract(
+        &mut self,
+        img: &core::Mat,
+    ) -> Result<(core::Vector<core::KeyPoint>, core::Mat), Box<dyn Error>> {
+        let mut corners = core::Vector::<core::Point2f>::default();
+        imgproc::good_features_to_track(
+            img,
+            &mut corners,
+            self.max_corners,
+            self.quality_level,
+            self.min_distance,
+            &core::Mat::default(),
+            3,
+            false,
+            0.04)?;
+
+        let mut keypoints = corners.into_iter()
+            .map(|pt| core::KeyPoint::new_point(pt, 1.0, -1.0, 0.0, 0, -1))
+            .collect::<Result<core::Vector<core::KeyPoint>, _>>()?;
+        let mut descriptors = core::Mat::default();
+        self.orb.compute(img, &mut keypoints, &mut descriptors)?;
+
+        Ok((keypoints, descriptors))
+    }
+
+    fn descriptor_kind(&self) -> DescriptorKind {
+        DescriptorKind::Binary
+    }
+}
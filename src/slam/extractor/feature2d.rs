@@ -0,0 +1,99 @@
+use std::error::Error;
+
+use opencv::{
+    prelude::*,
+    core,
+    features2d,
+};
+
+use super::super::config::FeatureConfig;
+use super::{DescriptorKind, FeatureExtractor};
+
+enum Detector {
+    Orb(core::Ptr<features2d::ORB>),
+    Akaze(core::Ptr<features2d::AKAZE>),
+    Brisk(core::Ptr<features2d::BRISK>),
+    Sift(core::Ptr<features2d::SIFT>),
+}
+
+/// An OpenCV detector that also computes its own descriptors.
+pub struct Feature2dExtractor {
+    detector: Detector,
+    max_features: usize,
+    descriptor_kind: DescriptorKind,
+}
+
+impl Feature2dExtractor {
+    pub fn orb(config: &FeatureConfig) -> Result<Self, Box<dyn Error>> {
+        let orb = features2d::ORB::create(
+            config.num_features() as i32,
+            config.scale_factor,
+            config.n_levels as i32,
+            config.edge_threshold,
+            0,
+            2,
+            features2d::ORB_ScoreType::HARRIS_SCORE,
+            config.patch_size,
+            config.fast_threshold,
+        )?;
+        Ok(Self { detector: Detector::Orb(orb), max_features: config.num_features(), descriptor_kind: DescriptorKind::Binary })
+    }
+
+    pub fn akaze(config: &FeatureConfig) -> Result<Self, Box<dyn Error>> {
+        let akaze = features2d::AKAZE::create(
+            features2d::AKAZE_DescriptorType::DESCRIPTOR_MLDB,
+            0,
+            3,
+            config.akaze_threshold,
+            config.num_octaves() as i32,
+            4,
+            features2d::KAZE_DiffusivityType::DIFF_PM_G2,
+        )?;
+        Ok(Self { detector: Detector::Akaze(akaze), max_features: config.num_features(), descriptor_kind: DescriptorKind::Binary })
+    }
+
+    pub fn brisk(config: &FeatureConfig) -> Result<Self, Box<dyn Error>> {
+        let brisk = features2d::BRISK::create(config.fast_threshold, config.num_octaves() as i32, 1.0)?;
+        Ok(Self { detector: Detector::Brisk(brisk), max_features: config.num_features(), descriptor_kind: DescriptorKind::Binary })
+    }
+
+    pub fn sift(config: &FeatureConfig) -> Result<Self, Box<dyn Error>> {
+        let sift = features2d::SIFT::create(config.num_features() as i32, 3, 0.04, 10.0, 1.6)?;
+        Ok(Self { detector: Detector::Sift(sift), max_features: config.num_features(), descriptor_kind: DescriptorKind::Float })
+    }
+}
+
+impl FeatureExtractor for Feature2dExtractor {
+    fn extract(
+        &mut self,
+        img: &core::Mat,
+    ) -> Result<(core::Vector<core::KeyPoint>, core::Mat), Box<dyn Error>> {
+        let mask = core::Mat::default();
+        let mut keypoints = core::Vector::<core::KeyPoint>::default();
+        match &mut self.detector {
+            Detector::Orb(d) => d.detect(img, &mut keypoints, &mask)?,
+            Detector::Akaze(d) => d.detect(img, &mut keypoints, &mask)?,
+            Detector::Brisk(d) => d.detect(img, &mut keypoints, &mask)?,
+            Detector::Sift(d) => d.detect(img, &mut keypoints, &mask)?,
+        }
+
+        // AKAZE and BRISK have no feature budget of their own
+        let mut keypoints = super::grid::retain_best(keypoints.to_vec(), self.max_features)
+            .into_iter()
+            .collect::<core::Vector<core::KeyPoint>>();
+
+        let mut descriptors = core::Mat::default();
+        match &mut self.detector {
+            Detector::Orb(d) => d.compute(img, &mut keypoints, &mut descriptors)?,
+            Detector::Akaze(d) => d.compute(img, &mut keypoints, &mut descriptors)?,
+            Detector::Brisk(d) => d.compute(img, &mut keypoints, &mut descriptors)?,
+            Detector::Sift(d) => d.compute(img, &mut keypoints, &mut descriptors)?,
+        }
+
+        Ok((keypoints, descriptors))
+    }
+
+    fn descriptor_kind(&self) -> DescriptorKind {
+        self.descriptor_kind
+    }
+}
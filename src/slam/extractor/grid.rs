@@ -0,0 +1,419 @@
+use std::error::Error;
+
+use opencv::{
+    prelude::*,
+    core,
+    features2d,
+    imgproc,
+};
+
+use super::super::config::FeatureConfig;
+use super::{DescriptorKind, FeatureExtractor};
+
+/// half of the patch used to compute the orientation of a keypoint
+const HALF_PATCH_SIZE: i32 = 15;
+/// FAST needs a 3 pixel radius around each candidate
+const FAST_BORDER: i32 = 3;
+
+/// Extracts keypoints spread uniformly over the image.
+///
+/// Every level of an image pyramid is divided into `grid_cols` x `grid_rows` cells
+/// (fewer on coarser levels) and FAST corners are detected per cell, retrying with
+/// `min_fast_threshold` when a cell has no corner at `fast_threshold`. The corners of
+/// a level are then reduced to the level budget, either with the quadtree of ORB-SLAM
+/// or by keeping the strongest corners of every cell. Descriptors are ORB, computed at
+/// the octave each keypoint was detected on.
+pub struct GridExtractor {
+    config: FeatureConfig,
+    orb: core::Ptr<features2d::ORB>,
+    umax: Vec<i32>,
+}
+
+impl GridExtractor {
+    pub fn new(config: &FeatureConfig) -> Result<Self, Box<dyn Error>> {
+        let orb = features2d::ORB::create(
+            config.num_features() as i32,
+            config.scale_factor,
+            config.n_levels as i32,
+            config.edge_threshold,
+            0,
+            2,
+            features2d::ORB_ScoreType::HARRIS_SCORE,
+            config.patch_size,
+            config.fast_threshold,
+        )?;
+
+        Ok(Self {
+            config: config.clone(),
+            orb,
+            umax: circle_umax(HALF_PATCH_SIZE),
+        })
+    }
+
+    fn build_pyramid(&self, img: &core::Mat) -> Result<Vec<core::Mat>, Box<dyn Error>> {
+        let mut pyramid = vec![img.clone()];
+        for level in 1..self.config.n_levels {
+            let scale = self.config.scale_factor.powi(level as i32);
+            let size = core::Size::new(
+                (img.cols() as f32 / scale).round() as i32,
+                (img.rows() as f32 / scale).round() as i32,
+            );
+            let mut level_img = core::Mat::default();
+            imgproc::resize(&pyramid[level - 1], &mut level_img, size, 0.0, 0.0, imgproc::INTER_LINEAR)?;
+            pyramid.push(level_img);
+        }
+        Ok(pyramid)
+    }
+
+    /// Splits the feature budget over the levels proportionally to their area,
+    /// the same geometric series ORB-SLAM uses.
+    fn level_budgets(&self) -> Vec<usize> {
+        let n_levels = self.config.n_levels;
+        let total = self.config.num_features();
+        let factor = 1.0 / self.config.scale_factor as f64;
+        let mut desired = total as f64 * (1.0 - factor) / (1.0 - factor.powi(n_levels as i32));
+
+        let mut budgets = Vec::with_capacity(n_levels);
+        let mut sum = 0;
+        for _ in 0..n_levels.saturating_sub(1) {
+            let n = desired.round() as usize;
+            budgets.push(n);
+            sum += n;
+            desired *= factor;
+        }
+        budgets.push(total.saturating_sub(sum));
+        budgets
+    }
+
+    fn detect_in_cells(
+        &self,
+        img: &core::Mat,
+        area: core::Rect,
+        cols: usize,
+        rows: usize,
+        budget: usize,
+    ) -> Result<Vec<core::KeyPoint>, Box<dyn Error>> {
+        let (min_x, min_y) = (area.x, area.y);
+        let (max_x, max_y) = (area.x + area.width, area.y + area.height);
+        let cell_w = (max_x - min_x) as f32 / cols as f32;
+        let cell_h = (max_y - min_y) as f32 / rows as f32;
+        let cell_budget = (budget as f32 / (cols * rows) as f32).ceil() as usize;
+
+        let mut candidates = Vec::new();
+        for row in 0..rows {
+            let y0 = min_y + (row as f32 * cell_h) as i32;
+            let y1 = if row + 1 == rows { max_y } else { min_y + ((row + 1) as f32 * cell_h) as i32 };
+            for col in 0..cols {
+                let x0 = min_x + (col as f32 * cell_w) as i32;
+                let x1 = if col + 1 == cols { max_x } else { min_x + ((col + 1) as f32 * cell_w) as i32 };
+
+                // extend the cell so FAST can also fire on its border pixels
+                let rect = core::Rect::new(
+                    x0 - FAST_BORDER,
+                    y0 - FAST_BORDER,
+                    x1 - x0 + 2 * FAST_BORDER,
+                    y1 - y0 + 2 * FAST_BORDER,
+                );
+                let cell = core::Mat::roi(img, rect)?;
+
+                let mut cell_keypoints = core::Vector::<core::KeyPoint>::default();
+                features2d::fast(&cell, &mut cell_keypoints, self.config.fast_threshold, true)?;
+                if cell_keypoints.is_empty() {
+                    // low texture cell, lower the threshold
+                    features2d::fast(&cell, &mut cell_keypoints, self.config.min_fast_threshold, true)?;
+                }
+
+                let mut cell_keypoints = cell_keypoints.into_iter()
+                    .map(|mut kp| {
+                        kp.set_pt(core::Point2f::new(kp.pt().x + rect.x as f32, kp.pt().y + rect.y as f32));
+                        kp
+                    })
+                    .filter(|kp| {
+                        let pt = kp.pt();
+                        pt.x >= x0 as f32 && pt.x < x1 as f32 && pt.y >= y0 as f32 && pt.y < y1 as f32
+                    })
+                    .collect::<Vec<_>>();
+
+                if !self.config.use_quadtree {
+                    cell_keypoints.sort_by(|a, b| b.response().partial_cmp(&a.response()).unwrap());
+                    cell_keypoints.truncate(cell_budget);
+                }
+                candidates.extend(cell_keypoints);
+            }
+        }
+
+        Ok(candidates)
+    }
+}
+
+impl FeatureExtractor for GridExtractor {
+    fn extract(
+        &mut self,
+        img: &core::Mat,
+    ) -> Result<(core::Vector<core::KeyPoint>, core::Mat), Box<dyn Error>> {
+        let pyramid = self.build_pyramid(img)?;
+        let budgets = self.level_budgets();
+
+        let mut keypoints = core::Vector::<core::KeyPoint>::default();
+        for (level, level_img) in pyramid.iter().enumerate() {
+            let scale = self.config.scale_factor.powi(level as i32);
+            let cols = ((self.config.grid_cols as f32 / scale).round() as usize).max(1);
+            let rows = ((self.config.grid_rows as f32 / scale).round() as usize).max(1);
+
+            let border = self.config.edge_threshold;
+            let area = core::Rect::new(
+                border,
+                border,
+                level_img.cols() - 2 * border,
+                level_img.rows() - 2 * border,
+            );
+            if area.width < 2 * FAST_BORDER || area.height < 2 * FAST_BORDER {
+                break;
+            }
+
+            let candidates = self.detect_in_cells(level_img, area, cols, rows, budgets[level])?;
+            let selected = if self.config.use_quadtree {
+                distribute_quadtree(candidates, area, budgets[level])
+            } else {
+                retain_best(candidates, budgets[level])
+            };
+
+            for mut kp in selected {
+                kp.set_angle(ic_angle(level_img, kp.pt(), &self.umax)?);
+                kp.set_octave(level as i32);
+                kp.set_size(self.config.patch_size as f32 * scale);
+                kp.set_pt(core::Point2f::new(kp.pt().x * scale, kp.pt().y * scale));
+                keypoints.push(kp);
+            }
+        }
+
+        let mut descriptors = core::Mat::default();
+        self.orb.compute(img, &mut keypoints, &mut descriptors)?;
+
+        Ok((keypoints, descriptors))
+    }
+
+    fn descriptor_kind(&self) -> DescriptorKind {
+        DescriptorKind::Binary
+    }
+}
+
+pub(super) fn retain_best(mut keypoints: Vec<core::KeyPoint>, n: usize) -> Vec<core::KeyPoint> {
+    keypoints.sort_by(|a, b| b.response().partial_cmp(&a.response()).unwrap());
+    keypoints.truncate(n);
+    keypoints
+}
+
+struct QuadNode {
+    min_x: f32,
+    min_y: f32,
+    max_x: f32,
+    max_y: f32,
+    keypoints: Vec<core::KeyPoint>,
+}
+
+impl QuadNode {
+    fn split(self) -> Vec<QuadNode> {
+        let mid_x = (self.min_x + self.max_x) / 2.0;
+        let mid_y = (self.min_y + self.max_y) / 2.0;
+        let mut children = vec![
+            QuadNode { min_x: self.min_x, min_y: self.min_y, max_x: mid_x, max_y: mid_y, keypoints: Vec::new() },
+            QuadNode { min_x: mid_x, min_y: self.min_y, max_x: self.max_x, max_y: mid_y, keypoints: Vec::new() },
+            QuadNode { min_x: self.min_x, min_y: mid_y, max_x: mid_x, max_y: self.max_y, keypoints: Vec::new() },
+            QuadNode { min_x: mid_x, min_y: mid_y, max_x: self.max_x, max_y: self.max_y, keypoints: Vec::new() },
+        ];
+        for kp in self.keypoints {
+            let pt = kp.pt();
+            let idx = (pt.x >= mid_x) as usize + 2 * (pt.y >= mid_y) as usize;
+            children[idx].keypoints.push(kp);
+        }
+        children.into_iter().filter(|c| !c.keypoints.is_empty()).collect()
+    }
+}
+
+/// Distributes keypoints with a quadtree, as in ORB-SLAM: the node holding the most
+/// keypoints is split until there are `n` nodes or no node can be split any further,
+/// then the strongest keypoint of each node is kept.
+fn distribute_quadtree(
+    keypoints: Vec<core::KeyPoint>,
+    area: core::Rect,
+    n: usize,
+) -> Vec<core::KeyPoint> {
+    if keypoints.is_empty() || n == 0 {
+        return Vec::new();
+    }
+
+    let mut nodes = vec![QuadNode {
+        min_x: area.x as f32,
+        min_y: area.y as f32,
+        max_x: (area.x + area.width) as f32,
+        max_y: (area.y + area.height) as f32,
+        keypoints,
+    }];
+    // nodes with a single keypoint, or too small to split, e.g. keypoints on the same pixel
+    let mut leaves = Vec::new();
+    while !nodes.is_empty() && nodes.len() + leaves.len() < n {
+        let (idx, _) = nodes.iter().enumerate()
+            .max_by_key(|(_, node)| node.keypoints.len())
+            .unwrap();
+        let node = nodes.swap_remove(idx);
+        if node.keypoints.len() <= 1 || node.max_x - node.min_x < 1.0 || node.max_y - node.min_y < 1.0 {
+            leaves.push(node);
+            continue;
+        }
+        nodes.extend(node.split());
+    }
+
+    let best = nodes.into_iter()
+        .chain(leaves)
+        .filter_map(|node| {
+            node.keypoints.into_iter()
+                .max_by(|a, b| a.response().partial_cmp(&b.response()).unwrap())
+        })
+        .collect::<Vec<_>>();
+    retain_best(best, n)
+}
+
+/// Row half-widths of a circular patch, see ORB.
+fn circle_umax(half_patch_size: i32) -> Vec<i32> {
+    let mut umax = vec![0; half_patch_size as usize + 1];
+    let vmax = (half_patch_size as f64 * 2.0_f64.sqrt() / 2.0 + 1.0).floor() as i32;
+    let vmin = (half_patch_size as f64 * 2.0_f64.sqrt() / 2.0).ceil() as i32;
+    let hp2 = (half_patch_size * half_patch_size) as f64;
+    for v in 0..=vmax {
+        umax[v as usize] = (hp2 - (v * v) as f64).sqrt().round() as i32;
+    }
+    // make sure the circle is symmetric
+    let mut v0 = 0;
+    for v in (vmin..=half_patch_size).rev() {
+        while umax[v0 as usize] == umax[(v0 + 1) as usize] {
+            v0 += 1;
+        }
+        umax[v as usize] = v0;
+        v0 += 1;
+    }
+    umax
+}
+
+/// Orientation of a keypoint from the intensity centroid of its circular patch, in degrees.
+fn ic_angle(img: &core::Mat, pt: core::Point2f, umax: &[i32]) -> Result<f32, Box<dyn Error>> {
+    let cx = pt.x.round() as i32;
+    let cy = pt.y.round() as i32;
+    let half = umax.len() as i32 - 1;
+    if cx - half < 0 || cy - half < 0 || cx + half >= img.cols() || cy + half >= img.rows() {
+        return Ok(0.0);
+    }
+
+    let mut m_01 = 0i64;
+    let mut m_10 = 0i64;
+    for u in -half..=half {
+        m_10 += u as i64 * *img.at_2d::<u8>(cy, cx + u)? as i64;
+    }
+    for v in 1..=half {
+        let d = umax[v as usize];
+        let mut v_sum = 0i64;
+        for u in -d..=d {
+            let val_plus = *img.at_2d::<u8>(cy + v, cx + u)? as i64;
+            let val_minus = *img.at_2d::<u8>(cy - v, cx + u)? as i64;
+            v_sum += val_plus - val_minus;
+            m_10 += u as i64 * (val_plus + val_minus);
+        }
+        m_01 += v as i64 * v_sum;
+    }
+
+    let angle = (m_01 as f32).atan2(m_10 as f32).to_degrees();
+    Ok(if angle < 0.0 { angle + 360.0 } else { angle })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypoint(x: f32, y: f32, response: f32) -> core::KeyPoint {
+        core::KeyPoint::new_point(core::Point2f::new(x, y), 7.0, -1.0, response, 0, -1).unwrap()
+    }
+
+    /// Random texture, so FAST fires everywhere in the image.
+    fn noise_image(cols: i32, rows: i32) -> core::Mat {
+        let mut img = core::Mat::new_rows_cols_with_default(rows, cols, core::CV_8UC1, core::Scalar::all(0.0)).unwrap();
+        core::randu(&mut img, &core::Scalar::all(0.0), &core::Scalar::all(255.0)).unwrap();
+        let mut blurred = core::Mat::default();
+        imgproc::gaussian_blur(&img, &mut blurred, core::Size::new(3, 3), 0.0, 0.0, core::BORDER_DEFAULT).unwrap();
+        blurred
+    }
+
+    #[test]
+    fn test_quadtree() {
+        let area = core::Rect::new(0, 0, 100, 100);
+        // a dense cluster on a single pixel that cannot be split, and one keypoint per quadrant
+        let mut keypoints = (0..50).map(|i| keypoint(10.0, 10.0, i as f32)).collect::<Vec<_>>();
+        keypoints.extend([keypoint(70.0, 20.0, 1.0), keypoint(20.0, 70.0, 1.0), keypoint(70.0, 70.0, 1.0)]);
+        keypoints.extend((0..4).map(|i| keypoint(60.0 + 10.0 * i as f32, 90.0, 0.5)));
+
+        let selected = distribute_quadtree(keypoints.clone(), area, 6);
+        assert_eq!(selected.len(), 6);
+        // the cluster keeps only its strongest keypoint, the other nodes are still split
+        assert_eq!(selected.iter().filter(|kp| kp.pt().x == 10.0 && kp.pt().y == 10.0).count(), 1);
+        assert!(selected.iter().any(|kp| kp.pt().x == 10.0 && kp.response() == 49.0));
+        for (x, y) in [(70.0, 20.0), (20.0, 70.0), (70.0, 70.0)] {
+            assert!(selected.iter().any(|kp| kp.pt().x == x && kp.pt().y == y));
+        }
+
+        // fewer distinct locations than requested
+        let selected = distribute_quadtree(keypoints, area, 100);
+        assert_eq!(selected.len(), 8);
+        assert!(distribute_quadtree(Vec::new(), area, 10).is_empty());
+    }
+
+    #[test]
+    fn test_level_budgets() {
+        let config = FeatureConfig::default();
+        let extractor = GridExtractor::new(&config).unwrap();
+        let budgets = extractor.level_budgets();
+        assert_eq!(budgets.len(), config.n_levels);
+        assert_eq!(budgets.iter().sum::<usize>(), config.num_features());
+        assert!(budgets.windows(2).all(|w| w[0] >= w[1]));
+    }
+
+    #[test]
+    fn test_extract() {
+        for use_quadtree in [true, false] {
+            let config = FeatureConfig { use_quadtree, ..Default::default() };
+            let mut extractor = GridExtractor::new(&config).unwrap();
+            let (cols, rows) = (752, 480);
+            let (keypoints, descriptors) = extractor.extract(&noise_image(cols, rows)).unwrap();
+
+            // total count within the budget, and close to it on a fully textured image
+            let total = config.num_features();
+            assert!(keypoints.len() <= total);
+            assert!(keypoints.len() > total * 9 / 10, "{} of {}", keypoints.len(), total);
+            assert_eq!(descriptors.rows() as usize, keypoints.len());
+
+            // every cell of the finest level gets keypoints
+            let border = config.edge_threshold as f32;
+            let cell_w = (cols as f32 - 2.0 * border) / config.grid_cols as f32;
+            let cell_h = (rows as f32 - 2.0 * border) / config.grid_rows as f32;
+            let mut cells = vec![0; config.grid_cols * config.grid_rows];
+            for kp in keypoints.iter().filter(|kp| kp.octave() == 0) {
+                let col = ((kp.pt().x - border) / cell_w) as usize;
+                let row = ((kp.pt().y - border) / cell_h) as usize;
+                cells[row.min(config.grid_rows - 1) * config.grid_cols + col.min(config.grid_cols - 1)] += 1;
+            }
+            assert!(cells.iter().all(|count| *count > 0), "{:?}", cells);
+
+            // levels and scales
+            let mut levels = vec![0; config.n_levels];
+            for kp in keypoints.iter() {
+                let octave = kp.octave();
+                assert!(octave >= 0 && (octave as usize) < config.n_levels);
+                levels[octave as usize] += 1;
+                let scale = config.scale_factor.powi(octave);
+                assert!((kp.size() - config.patch_size as f32 * scale).abs() < 1e-3);
+                let pt = kp.pt();
+                assert!(pt.x >= 0.0 && pt.x < cols as f32 && pt.y >= 0.0 && pt.y < rows as f32);
+            }
+            assert!(levels.iter().all(|count| *count > 0), "{:?}", levels);
+            assert!(levels[0] > levels[config.n_levels - 1]);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+pub mod grid;
+pub mod feature2d;
+pub mod fast_brief;
+
+use std::error::Error;
+
+use opencv::{
+    prelude::*,
+    core,
+};
+use super::config::{ExtractorKind, FeatureConfig, MatcherConfig};
+
+pub use grid::GridExtractor;
+pub use feature2d::Feature2dExtractor;
+pub use fast_brief::ShiTomasiOrbExtractor;
+#[cfg(feature = "contrib")]
+pub use fast_brief::FastBriefExtractor;
+
+/// How descriptors of an extractor are compared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorKind {
+    /// bit strings (ORB, BRIEF, BRISK, AKAZE), compared with the Hamming distance
+    Binary,
+    /// float vectors (SIFT), compared with the L2 distance
+    Float,
+}
+
+impl DescriptorKind {
+    pub fn norm_type(&self) -> i32 {
+        match self {
+            DescriptorKind::Binary => core::NORM_HAMMING,
+            DescriptorKind::Float => core::NORM_L2,
+        }
+    }
+
+    /// Largest descriptor distance of a match.
+    pub fn max_distance(&self, config: &MatcherConfig) -> f32 {
+        match self {
+            DescriptorKind::Binary => config.max_hamming_distance,
+            DescriptorKind::Float => config.max_l2_distance,
+        }
+    }
+}
+
+pub trait FeatureExtractor {
+    /// Detects keypoints in a grayscale image and computes one descriptor row per keypoint.
+    fn extract(
+        &mut self,
+        img: &core::Mat,
+    ) -> Result<(core::Vector<core::KeyPoint>, core::Mat), Box<dyn Error>>;
+
+    fn descriptor_kind(&self) -> DescriptorKind;
+}
+
+pub fn create(config: &FeatureConfig) -> Result<Box<dyn FeatureExtractor>, Box<dyn Error>> {
+    Ok(match config.extractor {
+        ExtractorKind::Grid => Box::new(GridExtractor::new(config)?),
+        ExtractorKind::Orb => Box::new(Feature2dExtractor::orb(config)?),
+        ExtractorKind::ShiTomasi => Box::new(ShiTomasiOrbExtractor::new(config)?),
+        #[cfg(feature = "contrib")]
+        ExtractorKind::FastBrief => Box::new(FastBriefExtractor::new(config)?),
+        #[cfg(not(feature = "contrib"))]
+        ExtractorKind::FastBrief => return Err("the fast_brief extractor needs the `contrib` feature".into()),
+        ExtractorKind::Akaze => Box::new(Feature2dExtractor::akaze(config)?),
+        ExtractorKind::Brisk => Box::new(Feature2dExtractor::brisk(config)?),
+        ExtractorKind::Sift => Box::new(Feature2dExtractor::sift(config)?),
+    })
+}
+
+/// Distance between two descriptor rows.
+pub fn descriptor_distance(
+    desc1: &core::Mat,
+    desc2: &core::Mat,
+    kind: DescriptorKind,
+) -> Result<f64, Box<dyn Error>> {
+    Ok(core::norm2(desc1, desc2, kind.norm_type(), &core::no_array())?)
+}
+
+/// Picks the descriptor with the smallest median distance to all the others,
+/// which is the most representative one for matching. See ORB-SLAM.
+pub fn distinctive_descriptor(
+    descriptors: &[core::Mat],
+    kind: DescriptorKind,
+) -> Result<Option<core::Mat>, Box<dyn Error>> {
+    let n = descriptors.len();
+    if n == 0 {
+        return Ok(None);
+    }
+
+    let mut distances = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in i + 1..n {
+            let d = descriptor_distance(&descriptors[i], &descriptors[j], kind)?;
+            distances[i][j] = d;
+            distances[j][i] = d;
+        }
+    }
+
+    let best = distances.into_iter()
+        .map(|mut row| {
+            row.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            row[(n - 1) / 2]
+        })
+        .enumerate()
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(idx, _)| idx)
+        .unwrap();
+
+    Ok(Some(descriptors[best].try_clone()?))
+}
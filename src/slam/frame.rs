@@ -6,17 +6,30 @@ use std::{
 use opencv::{
     core,
     features2d,
-    calib3d, prelude::{DescriptorMatcherTrait, KeyPointTraitConst},
+    prelude::{DescriptorMatcherTrait, KeyPointTraitConst},
 };
 use nalgebra as na;
 
+use crate::protocol::KeypointStatus;
+
+use super::config::{MatcherConfig, RansacConfig};
+use super::cv_convert;
+use super::extractor::DescriptorKind;
+use super::ransac::{self, FundamentalEstimator, RansacParams};
+
+/// Matches needed to estimate a fundamental matrix.
+const MIN_EPIPOLAR_MATCHES: usize = 8;
+
 #[derive(Clone)]
 pub struct Frame {
     pub timestamp: time::Duration,
     pub img: core::Mat,
     pub keypoints: core::Vector<core::KeyPoint>,
     pub descriptors: core::Mat,
+    pub descriptor_kind: DescriptorKind,
     pub pose: na::Isometry3<f64>, 
+    /// status of every keypoint once the frame is tracked, empty before
+    pub status: Vec<KeypointStatus>,
 }
 
 impl Frame {
@@ -25,6 +38,7 @@ impl Frame {
         img: core::Mat,
         keypoints: core::Vector<core::KeyPoint>,
         descriptors: core::Mat,
+        descriptor_kind: DescriptorKind,
         pose: na::Isometry3<f64>,
     ) -> Self {
         Frame {
@@ -32,7 +46,9 @@ impl Frame {
             img,
             keypoints,
             descriptors,
+            descriptor_kind,
             pose,
+            status: Vec::new(),
         }
     }
 
@@ -42,13 +58,18 @@ impl Frame {
             img: core::Mat::default(),
             keypoints: core::Vector::default(),
             descriptors: core::Mat::default(),
+            descriptor_kind: DescriptorKind::Binary,
             pose: na::Isometry3::identity(),
+            status: Vec::new(),
         }
     }
 
-    pub fn match_other(&self, other: &Self) -> Result<Vec<core::DMatch>, Box<dyn Error>> {
+    pub fn match_other(&self, other: &Self, config: &MatcherConfig, ransac: &RansacConfig) -> Result<Vec<core::DMatch>, Box<dyn Error>> {
+        if self.descriptor_kind != other.descriptor_kind {
+            return Err("cannot match descriptors of different kinds".into());
+        }
         // 创建 BFMatcher
-        let mut bf_matcher = match features2d::BFMatcher::create(core::NORM_HAMMING, false) {
+        let mut bf_matcher = match features2d::BFMatcher::create(self.descriptor_kind.norm_type(), false) {
             Ok(bf_matcher) => bf_matcher,
             Err(e) => {
                 println!("Create BFMatcher failed: {}", e);
@@ -61,25 +82,63 @@ impl Frame {
 
         bf_matcher.match_(&other.descriptors, &mut matches, &core::Mat::default())?;
 
-        let matches = matches.into_iter().filter(|m| m.distance < 30.0).collect::<Vec<_>>();
+        let matches = matches.into_iter().filter(|m| m.distance < self.descriptor_kind.max_distance(config)).collect::<Vec<_>>();
+
+        self.filter_epipolar(other, matches, config, ransac)
+    }
+
+    /// Matches keypoints carrying the same track id in `class_id`, as produced by the KLT tracker.
+    pub fn match_by_track_id(&self, other: &Self, config: &MatcherConfig, ransac: &RansacConfig) -> Result<Vec<core::DMatch>, Box<dyn Error>> {
+        let ids = self.keypoints.iter().enumerate()
+            .filter(|(_, kp)| kp.class_id() >= 0)
+            .map(|(idx, kp)| (kp.class_id(), idx))
+            .collect::<std::collections::HashMap<_, _>>();
+
+        let mut matches = Vec::new();
+        for (query_idx, kp) in other.keypoints.iter().enumerate() {
+            if let Some(train_idx) = ids.get(&kp.class_id()) {
+                matches.push(core::DMatch::new(query_idx as i32, *train_idx as i32, 0.0)?);
+            }
+        }
+
+        // too few tracks for a fundamental matrix, they already passed the forward-backward check
+        if matches.len() < MIN_EPIPOLAR_MATCHES {
+            return Ok(matches);
+        }
+        self.filter_epipolar(other, matches, config, ransac)
+    }
 
+    /// Drops matches violating the epipolar constraint of a robustly estimated fundamental matrix.
+    fn filter_epipolar(
+        &self,
+        other: &Self,
+        matches: Vec<core::DMatch>,
+        config: &MatcherConfig,
+        ransac: &RansacConfig,
+    ) -> Result<Vec<core::DMatch>, Box<dyn Error>> {
+        if matches.len() < MIN_EPIPOLAR_MATCHES {
+            return Err(format!("too few matches: {}", matches.len()).into());
+        }
         let (points1, points2) = matches2points(&matches, &self.keypoints, &other.keypoints)?;
+        let points1 = cv_convert::cv_points_to_na_points(&points1);
+        let points2 = cv_convert::cv_points_to_na_points(&points2);
+
+        let estimator = FundamentalEstimator { points1: &points1, points2: &points2 };
+        let params = RansacParams::new(config.ransac_threshold, config.ransac_confidence, ransac);
+        let order = matches_by_distance(&matches);
+        let result = ransac::estimate(&estimator, &params, Some(&order))
+            .ok_or("no fundamental matrix explains the matches")?;
 
-        let mut mask: core::Vector<u8> = core::Vector::default();
-        let _matrix = calib3d::find_fundamental_mat(
-            &points1, 
-            &points2, 
-            calib3d::FM_RANSAC, 1.0, 0.99, &mut mask)?;
-        
-        let matches = matches.into_iter().enumerate().filter(|m| mask.get(m.0).unwrap() != 0).map(|m| m.1).collect::<Vec<_>>();
+        let matches = matches.into_iter().zip(result.inliers.iter()).filter(|(_, inlier)| **inlier).map(|(m, _)| m).collect::<Vec<_>>();
 
         Ok(matches)
     }
 
+    /// Mean displacement in pixels of the matched keypoints.
     pub fn parallax_other(&self, other: &Self, matches: &Vec<core::DMatch>) -> Result<f64, Box<dyn Error>> {
         let (points1, points2) = matches2points(&matches, &self.keypoints, &other.keypoints)?;
         let parallax: f64 = points1.iter().zip(points2.iter())
-            .fold(0.0, |acc, (x, y)| acc + ((x.x - y.x).powi(2) + (x.y - y.y).powi(2)) as f64);
+            .fold(0.0, |acc, (x, y)| acc + (((x.x - y.x).powi(2) + (x.y - y.y).powi(2)) as f64).sqrt());
 
         Ok(parallax / matches.len() as f64)
     }
@@ -101,6 +160,14 @@ pub fn matches2points(
     Ok((points1, points2))
 }
 
+/// Match indices from the smallest to the largest descriptor distance, the sampling
+/// order of PROSAC.
+pub fn matches_by_distance(matches: &[core::DMatch]) -> Vec<usize> {
+    let mut order = (0..matches.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| matches[a].distance.partial_cmp(&matches[b].distance).unwrap());
+    order
+}
+
 pub fn matches2indices(matches: &Vec<core::DMatch>) -> (Vec<usize>, Vec<usize>) {
     (matches.iter().map(|m| m.train_idx as usize).collect::<Vec<_>>(),
     matches.iter().map(|m| m.query_idx as usize).collect::<Vec<_>>())
@@ -6,38 +6,55 @@ use std::{
 
 use opencv::{
     core,
-    calib3d,
-    imgproc, prelude::MatTraitConst,
+    prelude::MatTraitConst,
 };
+use nalgebra as na;
 
 
 use super::{
     frame::{self, Frame},
     map::{ Map, mappoint::*, keyframe::* },
     camera,
+    config::{SlamConfig, TrackingMode},
+    cv_convert,
+    debug::{self, DebugOutput, DebugStage},
+    ransac::{self, EssentialEstimator, HomographyEstimator, RansacParams},
     recover_pose,
 };
 
+/// chi-square thresholds at 95% for one and two degrees of freedom
+const CHI2_1D: f64 = 3.841;
+const CHI2_2D: f64 = 5.991;
+
 pub struct Init {
     first_frame: Option<Frame>,
     second_frame: Option<Frame>,
     matches: Vec<core::DMatch>,
     intrinsics: camera::CameraIntrinsics, 
+    config: SlamConfig,
+    pub debug: DebugOutput,
     pub map: Map,
     done: bool,
+    /// failed attempts since the first frame was anchored
+    failures: usize,
 }
 
 impl Init {
     pub fn new(
         intrinsics: &camera::CameraIntrinsics,
+        config: &SlamConfig,
+        debug: DebugOutput,
     ) -> Self {
         Self {
             first_frame: None,
             second_frame: None,
             matches: Vec::new(),
             intrinsics: intrinsics.clone(),
+            config: config.clone(),
+            debug,
             map: Map::new(),
             done: false,
+            failures: 0,
         }
     }
 
@@ -51,91 +68,171 @@ impl Init {
             return false;
         }
         let first_frame = self.first_frame.clone().unwrap();
-        let matches = match first_frame.match_other(&inframe) {
-            Ok(matches) => matches,
+        let matches = match self.config.tracking.mode {
+            TrackingMode::Descriptor => first_frame.match_other(&inframe, &self.config.matcher, &self.config.ransac),
+            TrackingMode::Klt => first_frame.match_by_track_id(&inframe, &self.config.matcher, &self.config.ransac),
+        };
+        let matches = match matches {
+            Ok(matches) if matches.len() >= self.config.init.min_matches => matches,
+            Ok(matches) => {
+                println!("too few matches: {}, re-anchoring first frame", matches.len());
+                self.anchor(inframe);
+                return false;
+            },
             Err(e) => {
-                println!("match failed: {}", e);
+                println!("match failed: {}, re-anchoring first frame", e);
+                self.anchor(inframe);
                 return false;
             }
         };
 
-        // if first_frame.parallax_other(&inframe, &matches).expect("compute parallax falied") < 1.0 {
-        //     return false;
-        // }
+        match first_frame.parallax_other(&inframe, &matches) {
+            Ok(parallax) if parallax < self.config.init.min_pixel_parallax => {
+                // wait for the camera to move further
+                return false;
+            },
+            Ok(_) => {},
+            Err(e) => {
+                println!("compute parallax failed: {}", e);
+                return false;
+            },
+        }
 
         self.second_frame = Some(inframe);
         self.matches = matches;
 
-        self.initialize().is_ok()
+        match self.initialize() {
+            Ok(()) => true,
+            Err(e) => {
+                self.failures += 1;
+                println!("initialization failed ({}/{}): {}", self.failures, self.config.init.max_attempts, e);
+                if self.failures >= self.config.init.max_attempts {
+                    println!("re-anchoring first frame");
+                    let frame = self.second_frame.take().unwrap();
+                    self.anchor(frame);
+                }
+                false
+            },
+        }
+    }
+
+    /// Restarts initialization with `frame` as the first frame.
+    fn anchor(&mut self, frame: Frame) {
+        self.first_frame = Some(frame);
+        self.second_frame = None;
+        self.matches.clear();
+        self.failures = 0;
     }
 
     pub fn initialize(&mut self) -> Result<(), Box<dyn Error>> {
         let first_frame = self.first_frame.clone().unwrap();
         let mut second_frame = self.second_frame.as_mut().unwrap();
-        let (inliers1, inliers2) = frame::matches2points(
+        let (points1, points2) = frame::matches2points(
             &self.matches, 
             &first_frame.keypoints, 
             &second_frame.keypoints
         )?;
-        let mut mask: core::Vector<u8> = core::Vector::default();
-        // in opencv, find_essential_mat returns Essential Matrix E, 
-        // which subjects to x2^T * E * x1 = 0
-        // It is important!
-        // So E = R_{21} * [t_{21}]_x, not R_{12} * [t_{12}]_x !!!
+        let timestamp = second_frame.timestamp;
+        self.debug.emit(DebugStage::Matches, timestamp, || {
+            debug::draw_tracks(&second_frame.img, &points1, &points2, core::Scalar::new(0.0, 255.0, 255.0, 255.0))
+        });
+
+        // estimate both models in parallel, as ORB-SLAM does: a homography explains
+        // planar scenes and pure rotation, where the fundamental matrix is degenerate
+        let na_points1 = cv_convert::cv_points_to_na_points(&points1);
+        let na_points2 = cv_convert::cv_points_to_na_points(&points2);
+        let params = RansacParams::new(self.config.init.ransac_threshold, self.config.init.ransac_confidence, &self.config.ransac);
+        let order = frame::matches_by_distance(&self.matches);
+        let h = ransac::estimate(
+            &HomographyEstimator { points1: &na_points1, points2: &na_points2 },
+            &params,
+            Some(&order)).map(|result| result.model);
+        // E subjects to x2^T * E * x1 = 0 in normalized coordinates,
+        // so E = R_{21} * [t_{21}]_x, not R_{12} * [t_{12}]_x !!!
         // where R_{21} is the rotation matrix from frame 1 to frame 2
         // and [t_{21}]_x is the skew-symmetric matrix of translation vector t_{21}
-        let essential_mat = calib3d::find_essential_mat(
-            &inliers1, 
-            &inliers2, 
-            self.intrinsics.fx, 
-            core::Point_ { x: self.intrinsics.cx, y: self.intrinsics.cy }, 
-            calib3d::RANSAC, 
-            0.999, 
-            1.0, 
-            &mut mask)?;
-
-        let matches = self.matches.iter().zip(mask.iter()).filter(|(_, status)| *status != 0).map(|(m, _)| *m).collect::<Vec<_>>();
+        let normalized1 = na_points1.iter().map(|p| self.intrinsics.inv_projection(p)).collect::<Vec<_>>();
+        let normalized2 = na_points2.iter().map(|p| self.intrinsics.inv_projection(p)).collect::<Vec<_>>();
+        let e = ransac::estimate(
+            &EssentialEstimator {
+                points1: &normalized1,
+                points2: &normalized2,
+                focal: 0.5 * (self.intrinsics.fx + self.intrinsics.fy),
+            },
+            &params,
+            Some(&order)).map(|result| result.model);
+        // scored as F = K^-T * E * K^-1 against the homography in pixels
+        let k = self.intrinsics.k_mat;
+        let k_inv = k.try_inverse().ok_or("singular camera matrix")?;
+        let f = e.map(|e| k_inv.transpose() * e * k_inv);
+
+        let sigma = self.config.init.sigma;
+        let (score_h, inliers_h) = match h.as_ref() {
+            Some(h) => score_homography(h, &points1, &points2, sigma),
+            None => (0.0, vec![false; points1.len()]),
+        };
+        let (score_f, inliers_f) = match f.as_ref() {
+            Some(f) => score_fundamental(f, &points1, &points2, sigma),
+            None => (0.0, vec![false; points1.len()]),
+        };
+        if score_h + score_f <= 0.0 {
+            return Err("no model explains the matches".into());
+        }
+        let ratio = score_h / (score_h + score_f);
+        let use_homography = ratio > self.config.init.homography_ratio;
+        println!("score H: {:.1}, score F: {:.1}, ratio: {:.3}, model: {}", score_h, score_f, ratio, if use_homography { "H" } else { "F" });
+
+        let mask = if use_homography { inliers_h } else { inliers_f };
+        let matches = self.matches.iter().zip(mask.iter()).filter(|(_, status)| **status).map(|(m, _)| *m).collect::<Vec<_>>();
         let (inliers1, inliers2) = frame::matches2points(
             &matches, 
             &first_frame.keypoints, 
             &second_frame.keypoints
         )?;
 
-        let out_img = second_frame.img.clone();
-        let mut out_img_color = core::Mat::default();
-        imgproc::cvt_color(&out_img, &mut out_img_color, imgproc::COLOR_GRAY2BGR, 0)?;
-
-        for (last_pt, curr_pt) in inliers1.iter().zip(inliers2.iter()) {
-            let last_pt = core::Point2i::new(last_pt.x as i32, last_pt.y as i32);
-            let curr_pt = core::Point2i::new(curr_pt.x as i32, curr_pt.y as i32);
-            imgproc::line(
-                &mut out_img_color, 
-                last_pt,
-                curr_pt,
-                core::Scalar::new(255.0, 0.0, 255.0, 255.0),
-                2, 
-                8, 
-                0)?;
-        }
-
-        opencv::highgui::imshow("matches", &out_img_color)?;
-        opencv::highgui::wait_key(0)?;
+        self.debug.emit(DebugStage::Inliers, timestamp, || {
+            debug::draw_tracks(&second_frame.img, &inliers1, &inliers2, core::Scalar::new(255.0, 0.0, 255.0, 255.0))
+        });
 
         // NOTE: type of mask is Vec<bool>, not opencv::core::Vector<u8>
         // and type of points3d is Vec<na::Point3<f64>>, not opencv::core::Vector<core::Point3f>
         // since we implement the function by ourselves
-        let (pose, mask, points3d) = recover_pose::from_essential(
-            &essential_mat, 
-            &inliers1, 
-            &inliers2, 
-            &self.intrinsics)?;
+        let (pose, mask, points3d) = if use_homography {
+            recover_pose::from_homography(
+                &h.ok_or("homography selected without a model")?, 
+                &inliers1, 
+                &inliers2, 
+                &self.intrinsics,
+                &self.config.init)?
+        } else {
+            recover_pose::from_essential(
+                &e.ok_or("essential matrix selected without a model")?, 
+                &inliers1, 
+                &inliers2, 
+                &self.intrinsics,
+                &self.config.init)?
+        };
         second_frame.pose = pose;
+        self.debug.emit(DebugStage::Reprojections, timestamp, || {
+            let observed = inliers2.iter().zip(mask.iter())
+                .filter(|(_, status)| **status)
+                .map(|(pt, _)| pt)
+                .collect::<Vec<_>>();
+            let projected = points3d.iter()
+                .map(|p| {
+                    let p = self.intrinsics.projection(&(pose * p).coords);
+                    core::Point2f::new(p.x as f32, p.y as f32)
+                })
+                .collect::<Vec<_>>();
+            debug::draw_reprojections(&second_frame.img, &observed, &projected)
+        });
 
         let matches = matches.into_iter().zip(mask.iter()).filter(|(_, status)| **status).map(|(m, _)| m.clone()).collect::<Vec<_>>();
         let (idx1, idx2) = frame::matches2indices(&matches);
 
         let mut kf1 = KeyFrame::from_frame(&first_frame, &self.intrinsics);
         let mut kf2 = KeyFrame::from_frame(&second_frame, &self.intrinsics);
+        kf2.set_parent(kf1.id);
         let mut map = Map::new();
 
         let mut idx_point3d = 0;
@@ -151,18 +248,99 @@ impl Init {
             let mp = Rc::new(RefCell::new(MapPoint::from_point(point, &des2)));
             kf1.add_observation(mp.clone());
             kf2.add_observation(mp.clone());
-            mp.borrow_mut().add_reference(MapPointReference::new_with_kf(&kf1, &kp1, &des1));
-            mp.borrow_mut().add_reference(MapPointReference::new_with_kf(&kf2, &kp2, &des2));
+            mp.borrow_mut().add_reference(MapPointReference::new_with_kf(&kf1, idx1[idx_point3d], &kp1, &des1));
+            mp.borrow_mut().add_reference(MapPointReference::new_with_kf(&kf2, idx2[idx_point3d], &kp2, &des2));
+            mp.borrow_mut().update_descriptor(first_frame.descriptor_kind)?;
             map.insert_mappoint(mp.clone());
 
             idx_point3d += 1;
         }
 
+        // monocular scale is arbitrary, fix it so the median scene depth of the
+        // first keyframe is 1, as ORB-SLAM does
+        let (id1, id2) = (kf1.id, kf2.id);
         map.insert_keyframe(kf1);
         map.insert_keyframe(kf2);
+        let median_depth = map.median_depth(id1).ok_or("no map point to normalize the scale")?;
+        if median_depth <= 0.0 {
+            return Err(format!("invalid median depth: {}", median_depth).into());
+        }
+        let mappoints = map.mappoints.keys().cloned().collect::<Vec<_>>();
+        map.rescale(id1, 1.0 / median_depth, &[id1, id2], &mappoints);
+        if let Some(kf2) = map.keyframe(id2) {
+            second_frame.pose = kf2.pose;
+        }
         self.map = map;
         self.done = true;
 
         Ok(())
     }
 }
+
+/// Scores a homography `x2 ~ H * x1` by the symmetric transfer error of every match,
+/// see ORB-SLAM. Returns the score and which matches are inliers in both directions.
+fn score_homography(
+    h: &na::Matrix3<f64>,
+    points1: &core::Vector<core::Point2f>,
+    points2: &core::Vector<core::Point2f>,
+    sigma: f64,
+) -> (f64, Vec<bool>) {
+    let h_inv = match h.try_inverse() {
+        Some(h_inv) => h_inv,
+        None => return (0.0, vec![false; points1.len()]),
+    };
+    let inv_sigma2 = 1.0 / (sigma * sigma);
+    let transfer_error = |h: &na::Matrix3<f64>, from: &na::Vector2<f64>, to: &na::Vector2<f64>| {
+        let p = h * from.push(1.0);
+        (to - p.xy() / p.z).norm_squared() * inv_sigma2
+    };
+
+    let mut score = 0.0;
+    let mut inliers = Vec::with_capacity(points1.len());
+    for (pt1, pt2) in points1.iter().zip(points2.iter()) {
+        let x1 = na::Vector2::<f64>::new(pt1.x as f64, pt1.y as f64);
+        let x2 = na::Vector2::<f64>::new(pt2.x as f64, pt2.y as f64);
+        let mut inlier = true;
+        for chi2 in [transfer_error(h, &x1, &x2), transfer_error(&h_inv, &x2, &x1)] {
+            if chi2.is_nan() || chi2 > CHI2_2D {
+                inlier = false;
+            } else {
+                score += CHI2_2D - chi2;
+            }
+        }
+        inliers.push(inlier);
+    }
+    (score, inliers)
+}
+
+/// Scores a fundamental matrix `x2^T * F * x1 = 0` by the distances of every match
+/// to its epipolar lines in both images, see ORB-SLAM.
+fn score_fundamental(
+    f: &na::Matrix3<f64>,
+    points1: &core::Vector<core::Point2f>,
+    points2: &core::Vector<core::Point2f>,
+    sigma: f64,
+) -> (f64, Vec<bool>) {
+    let inv_sigma2 = 1.0 / (sigma * sigma);
+    let line_error = |line: &na::Vector3<f64>, x: &na::Vector3<f64>| {
+        line.dot(x).powi(2) / (line.x * line.x + line.y * line.y) * inv_sigma2
+    };
+
+    let mut score = 0.0;
+    let mut inliers = Vec::with_capacity(points1.len());
+    for (pt1, pt2) in points1.iter().zip(points2.iter()) {
+        let x1 = na::Vector3::<f64>::new(pt1.x as f64, pt1.y as f64, 1.0);
+        let x2 = na::Vector3::<f64>::new(pt2.x as f64, pt2.y as f64, 1.0);
+        let mut inlier = true;
+        for chi2 in [line_error(&(f * x1), &x2), line_error(&(f.transpose() * x2), &x1)] {
+            if chi2.is_nan() || chi2 > CHI2_1D {
+                inlier = false;
+            } else {
+                // same score scale as the homography, which has two degrees of freedom
+                score += CHI2_2D - chi2;
+            }
+        }
+        inliers.push(inlier);
+    }
+    (score, inliers)
+}
@@ -0,0 +1,201 @@
+use std::error::Error;
+
+use opencv::{
+    prelude::*,
+    core,
+    features2d,
+    imgproc,
+    video,
+};
+
+use super::config::{FeatureConfig, SlamConfig, TrackingConfig};
+
+/// Frame-to-frame feature tracker based on pyramidal Lucas-Kanade optical flow.
+///
+/// Points are tracked forward into the new image and back again; a track survives only
+/// if it returns within `klt_max_fb_error` of where it started. New corners are detected
+/// only in grid cells holding fewer than `klt_min_features_per_cell` surviving tracks.
+/// Each keypoint carries its track id in `class_id`, which associates it with the previous
+/// frame, see `Frame::match_by_track_id` and the tracker.
+pub struct KltTracker {
+    tracking: TrackingConfig,
+    feature: FeatureConfig,
+    orb: core::Ptr<features2d::ORB>,
+    prev_img: Option<core::Mat>,
+    prev_points: core::Vector<core::Point2f>,
+    prev_ids: Vec<i32>,
+    next_id: i32,
+}
+
+impl KltTracker {
+    pub fn new(config: &SlamConfig) -> Result<Self, Box<dyn Error>> {
+        let feature = &config.feature;
+        let orb = features2d::ORB::create(
+            feature.num_features() as i32,
+            feature.scale_factor,
+            feature.n_levels as i32,
+            feature.edge_threshold,
+            0,
+            2,
+            features2d::ORB_ScoreType::HARRIS_SCORE,
+            feature.patch_size,
+            feature.fast_threshold,
+        )?;
+
+        Ok(Self {
+            tracking: config.tracking.clone(),
+            feature: config.feature.clone(),
+            orb,
+            prev_img: None,
+            prev_points: core::Vector::default(),
+            prev_ids: Vec::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Tracks the features of the previous image into `img`, tops up sparse grid cells with
+    /// new corners and computes ORB descriptors for all of them.
+    pub fn track(
+        &mut self,
+        img: &core::Mat,
+    ) -> Result<(core::Vector<core::KeyPoint>, core::Mat), Box<dyn Error>> {
+        let (mut points, mut ids) = match self.prev_img.take() {
+            Some(prev_img) if !self.prev_points.is_empty() => self.track_points(&prev_img, img)?,
+            _ => (core::Vector::default(), Vec::new()),
+        };
+
+        let new_points = self.detect_in_sparse_cells(img, &points)?;
+        for pt in new_points {
+            points.push(pt);
+            ids.push(self.next_id);
+            self.next_id += 1;
+        }
+
+        let mut keypoints = core::Vector::<core::KeyPoint>::default();
+        for (pt, id) in points.iter().zip(ids.iter()) {
+            keypoints.push(core::KeyPoint::new_point(pt, self.feature.patch_size as f32, -1.0, 0.0, 0, *id)?);
+        }
+        // ORB drops keypoints too close to the border, track ids keep the association intact
+        let mut descriptors = core::Mat::default();
+        self.orb.compute(img, &mut keypoints, &mut descriptors)?;
+
+        self.prev_img = Some(img.clone());
+        self.prev_points = points;
+        self.prev_ids = ids;
+
+        Ok((keypoints, descriptors))
+    }
+
+    fn track_points(
+        &self,
+        prev_img: &core::Mat,
+        img: &core::Mat,
+    ) -> Result<(core::Vector<core::Point2f>, Vec<i32>), Box<dyn Error>> {
+        let win_size = core::Size::new(self.tracking.klt_window, self.tracking.klt_window);
+        let criteria = core::TermCriteria::new(
+            core::TermCriteria_Type::COUNT as i32 + core::TermCriteria_Type::EPS as i32,
+            self.tracking.klt_iterations,
+            0.01,
+        )?;
+
+        let mut forward = core::Vector::<core::Point2f>::default();
+        let mut forward_status = core::Vector::<u8>::default();
+        let mut forward_err = core::Vector::<f32>::default();
+        video::calc_optical_flow_pyr_lk(
+            prev_img, img, &self.prev_points, &mut forward, &mut forward_status, &mut forward_err,
+            win_size, self.tracking.klt_levels, criteria, 0, 1e-4)?;
+
+        let mut backward = core::Vector::<core::Point2f>::default();
+        let mut backward_status = core::Vector::<u8>::default();
+        let mut backward_err = core::Vector::<f32>::default();
+        video::calc_optical_flow_pyr_lk(
+            img, prev_img, &forward, &mut backward, &mut backward_status, &mut backward_err,
+            win_size, self.tracking.klt_levels, criteria, 0, 1e-4)?;
+
+        let (width, height) = (img.cols() as f32, img.rows() as f32);
+        let mut points = core::Vector::<core::Point2f>::default();
+        let mut ids = Vec::new();
+        for idx in 0..self.prev_points.len() {
+            if forward_status.get(idx)? == 0 || backward_status.get(idx)? == 0 {
+                continue;
+            }
+            let start = self.prev_points.get(idx)?;
+            let end = forward.get(idx)?;
+            let back = backward.get(idx)?;
+            let fb_error = ((start.x - back.x).powi(2) + (start.y - back.y).powi(2)).sqrt();
+            if fb_error > self.tracking.klt_max_fb_error {
+                continue;
+            }
+            if end.x < 0.0 || end.y < 0.0 || end.x >= width || end.y >= height {
+                continue;
+            }
+            points.push(end);
+            ids.push(self.prev_ids[idx]);
+        }
+
+        Ok((points, ids))
+    }
+
+    /// Detects corners in the grid cells holding fewer than `klt_min_features_per_cell`
+    /// tracks, enough to bring them back to `features_per_cell`, and away from the tracks
+    /// already in them.
+    fn detect_in_sparse_cells(
+        &self,
+        img: &core::Mat,
+        points: &core::Vector<core::Point2f>,
+    ) -> Result<core::Vector<core::Point2f>, Box<dyn Error>> {
+        let cols = self.feature.grid_cols;
+        let rows = self.feature.grid_rows;
+        let cell_w = img.cols() as f32 / cols as f32;
+        let cell_h = img.rows() as f32 / rows as f32;
+
+        let mut counts = vec![0; cols * rows];
+        for pt in points.iter() {
+            let col = ((pt.x / cell_w) as usize).min(cols - 1);
+            let row = ((pt.y / cell_h) as usize).min(rows - 1);
+            counts[row * cols + col] += 1;
+        }
+
+        let mut mask = core::Mat::new_rows_cols_with_default(img.rows(), img.cols(), core::CV_8UC1, core::Scalar::all(0.0))?;
+        let mut missing = 0;
+        for row in 0..rows {
+            for col in 0..cols {
+                let count = counts[row * cols + col];
+                if count >= self.tracking.klt_min_features_per_cell {
+                    continue;
+                }
+                let rect = core::Rect::new(
+                    (col as f32 * cell_w) as i32,
+                    (row as f32 * cell_h) as i32,
+                    cell_w.ceil() as i32,
+                    cell_h.ceil() as i32,
+                );
+                imgproc::rectangle(&mut mask, rect, core::Scalar::all(255.0), imgproc::FILLED, imgproc::LINE_8, 0)?;
+                missing += self.feature.features_per_cell.saturating_sub(count);
+            }
+        }
+
+        let mut corners = core::Vector::<core::Point2f>::default();
+        if missing == 0 {
+            return Ok(corners);
+        }
+        // keep new corners from duplicating the tracks of the cells being topped up
+        let radius = self.feature.min_distance.ceil() as i32;
+        for pt in points.iter() {
+            let center = core::Point::new(pt.x.round() as i32, pt.y.round() as i32);
+            imgproc::circle(&mut mask, center, radius, core::Scalar::all(0.0), imgproc::FILLED, imgproc::LINE_8, 0)?;
+        }
+        imgproc::good_features_to_track(
+            img,
+            &mut corners,
+            missing as i32,
+            self.feature.quality_level,
+            self.feature.min_distance,
+            &mask,
+            3,
+            false,
+            0.04)?;
+
+        Ok(corners)
+    }
+}
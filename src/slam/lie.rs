@@ -0,0 +1,450 @@
+//! Exponential and logarithm maps, adjoints and Jacobians of SO3, SE3 and Sim3.
+//!
+//! Tangent vectors are ordered translation first: `[rho, omega]` for SE3 and
+//! `[rho, omega, sigma]` for Sim3, where `omega` is the rotation vector and `sigma` the
+//! log of the scale. Increments are applied on the left, `exp(delta) * T`, and the left
+//! Jacobian relates them to the tangent space: `exp(xi + d) ~ exp(J_l(xi) * d) * exp(xi)`.
+//!
+//! The closed forms divide by powers of the rotation angle, so below `SERIES_ANGLE` their
+//! Taylor series are used instead.
+
+use nalgebra as na;
+
+/// Angle below which the Jacobian coefficients are evaluated from their series.
+const SERIES_ANGLE: f64 = 0.1;
+
+/// Sim3 tangent vector `[rho, omega, sigma]`.
+pub type Vector7 = na::SVector<f64, 7>;
+
+/// Scale or angle below which the Sim3 coefficients take their limits.
+const SIM3_EPSILON: f64 = 1e-6;
+
+/// Cross product matrix, `skew(v) * w = v x w`.
+pub fn skew(v: &na::Vector3<f64>) -> na::Matrix3<f64> {
+    na::Matrix3::new(
+        0.0, -v.z, v.y,
+        v.z, 0.0, -v.x,
+        -v.y, v.x, 0.0,
+    )
+}
+
+/// Inverse of `skew`.
+pub fn vee(m: &na::Matrix3<f64>) -> na::Vector3<f64> {
+    na::Vector3::new(m[(2, 1)], m[(0, 2)], m[(1, 0)])
+}
+
+/// `(1 - cos(theta)) / theta^2` and `(theta - sin(theta)) / theta^3`.
+fn rotation_coefficients(theta: f64) -> (f64, f64) {
+    if theta < SERIES_ANGLE {
+        let t2 = theta * theta;
+        (
+            0.5 - t2 / 24.0 + t2 * t2 / 720.0,
+            1.0 / 6.0 - t2 / 120.0 + t2 * t2 / 5040.0,
+        )
+    } else {
+        let t2 = theta * theta;
+        ((1.0 - theta.cos()) / t2, (theta - theta.sin()) / (t2 * theta))
+    }
+}
+
+pub fn so3_exp(omega: &na::Vector3<f64>) -> na::UnitQuaternion<f64> {
+    let theta = omega.norm();
+    let half_theta = 0.5 * theta;
+    // sin(theta / 2) / theta
+    let k = if theta < SERIES_ANGLE {
+        0.5 - theta * theta / 48.0 + theta.powi(4) / 3840.0
+    } else {
+        half_theta.sin() / theta
+    };
+    na::UnitQuaternion::new_unchecked(na::Quaternion::from_parts(half_theta.cos(), omega * k))
+}
+
+/// Rotation vector of `q`, with an angle in `[0, pi]`.
+pub fn so3_log(q: &na::UnitQuaternion<f64>) -> na::Vector3<f64> {
+    // q and -q are the same rotation, pick the one with the shorter angle
+    let (w, v) = if q.w < 0.0 { (-q.w, -q.vector()) } else { (q.w, q.vector().clone_owned()) };
+    let n = v.norm();
+    if n < 1e-8 {
+        // theta / sin(theta / 2) ~ 2 / w * (1 - n^2 / (3 * w^2))
+        v * (2.0 / w) * (1.0 - n * n / (3.0 * w * w))
+    } else {
+        v * (2.0 * n.atan2(w) / n)
+    }
+}
+
+pub fn so3_left_jacobian(omega: &na::Vector3<f64>) -> na::Matrix3<f64> {
+    let (a, b) = rotation_coefficients(omega.norm());
+    let omega_hat = skew(omega);
+    na::Matrix3::identity() + omega_hat * a + omega_hat * omega_hat * b
+}
+
+pub fn so3_left_jacobian_inv(omega: &na::Vector3<f64>) -> na::Matrix3<f64> {
+    let theta = omega.norm();
+    // 1 / theta^2 - cot(theta / 2) / (2 * theta), finite at pi
+    let c = if theta < SERIES_ANGLE {
+        let t2 = theta * theta;
+        1.0 / 12.0 + t2 / 720.0 + t2 * t2 / 30240.0
+    } else {
+        1.0 / (theta * theta) - 1.0 / ((0.5 * theta).tan() * 2.0 * theta)
+    };
+    let omega_hat = skew(omega);
+    na::Matrix3::identity() - omega_hat * 0.5 + omega_hat * omega_hat * c
+}
+
+pub fn so3_right_jacobian(omega: &na::Vector3<f64>) -> na::Matrix3<f64> {
+    so3_left_jacobian(&-omega)
+}
+
+pub fn so3_right_jacobian_inv(omega: &na::Vector3<f64>) -> na::Matrix3<f64> {
+    so3_left_jacobian_inv(&-omega)
+}
+
+/// Geodesic interpolation, `a` at `alpha = 0` and `b` at `alpha = 1`.
+pub fn so3_interpolate(a: &na::UnitQuaternion<f64>, b: &na::UnitQuaternion<f64>, alpha: f64) -> na::UnitQuaternion<f64> {
+    so3_exp(&(so3_log(&(b * a.inverse())) * alpha)) * a
+}
+
+pub fn se3_exp(xi: &na::Vector6<f64>) -> na::Isometry3<f64> {
+    let rho = xi.fixed_rows::<3>(0).into_owned();
+    let omega = xi.fixed_rows::<3>(3).into_owned();
+    na::Isometry3::from_parts(
+        na::Translation3::from(so3_left_jacobian(&omega) * rho),
+        so3_exp(&omega),
+    )
+}
+
+pub fn se3_log(pose: &na::Isometry3<f64>) -> na::Vector6<f64> {
+    let omega = so3_log(&pose.rotation);
+    let rho = so3_left_jacobian_inv(&omega) * pose.translation.vector;
+    let mut xi = na::Vector6::zeros();
+    xi.fixed_rows_mut::<3>(0).copy_from(&rho);
+    xi.fixed_rows_mut::<3>(3).copy_from(&omega);
+    xi
+}
+
+/// `Ad(T)` with `T * exp(xi) * T^{-1} = exp(Ad(T) * xi)`.
+pub fn se3_adjoint(pose: &na::Isometry3<f64>) -> na::Matrix6<f64> {
+    let r = pose.rotation.to_rotation_matrix().into_inner();
+    let mut adjoint = na::Matrix6::zeros();
+    adjoint.fixed_view_mut::<3, 3>(0, 0).copy_from(&r);
+    adjoint.fixed_view_mut::<3, 3>(0, 3).copy_from(&(skew(&pose.translation.vector) * r));
+    adjoint.fixed_view_mut::<3, 3>(3, 3).copy_from(&r);
+    adjoint
+}
+
+/// Upper right block of the SE3 left Jacobian (Barfoot, State Estimation for Robotics, 7.86).
+fn se3_q(xi: &na::Vector6<f64>) -> na::Matrix3<f64> {
+    let rho = skew(&xi.fixed_rows::<3>(0).into_owned());
+    let omega = xi.fixed_rows::<3>(3).into_owned();
+    let theta = omega.norm();
+    let w = skew(&omega);
+    let (c1, c2, c3) = if theta < SERIES_ANGLE {
+        let t2 = theta * theta;
+        (
+            1.0 / 6.0 - t2 / 120.0 + t2 * t2 / 5040.0,
+            1.0 / 24.0 - t2 / 720.0 + t2 * t2 / 40320.0,
+            1.0 / 120.0 - t2 / 2520.0 + t2 * t2 / 120960.0,
+        )
+    } else {
+        let (s, c) = theta.sin_cos();
+        (
+            (theta - s) / theta.powi(3),
+            (theta * theta + 2.0 * c - 2.0) / (2.0 * theta.powi(4)),
+            (2.0 * theta - 3.0 * s + theta * c) / (2.0 * theta.powi(5)),
+        )
+    };
+    let wr = w * rho;
+    let rw = rho * w;
+    let wrw = wr * w;
+    rho * 0.5
+        + (wr + rw + wrw) * c1
+        + (w * wr + rw * w - wrw * 3.0) * c2
+        + (wrw * w + w * wrw) * c3
+}
+
+pub fn se3_left_jacobian(xi: &na::Vector6<f64>) -> na::Matrix6<f64> {
+    let j = so3_left_jacobian(&xi.fixed_rows::<3>(3).into_owned());
+    let mut jacobian = na::Matrix6::zeros();
+    jacobian.fixed_view_mut::<3, 3>(0, 0).copy_from(&j);
+    jacobian.fixed_view_mut::<3, 3>(0, 3).copy_from(&se3_q(xi));
+    jacobian.fixed_view_mut::<3, 3>(3, 3).copy_from(&j);
+    jacobian
+}
+
+pub fn se3_left_jacobian_inv(xi: &na::Vector6<f64>) -> na::Matrix6<f64> {
+    let j_inv = so3_left_jacobian_inv(&xi.fixed_rows::<3>(3).into_owned());
+    let mut jacobian = na::Matrix6::zeros();
+    jacobian.fixed_view_mut::<3, 3>(0, 0).copy_from(&j_inv);
+    jacobian.fixed_view_mut::<3, 3>(0, 3).copy_from(&(-j_inv * se3_q(xi) * j_inv));
+    jacobian.fixed_view_mut::<3, 3>(3, 3).copy_from(&j_inv);
+    jacobian
+}
+
+pub fn se3_right_jacobian(xi: &na::Vector6<f64>) -> na::Matrix6<f64> {
+    se3_left_jacobian(&-xi)
+}
+
+pub fn se3_right_jacobian_inv(xi: &na::Vector6<f64>) -> na::Matrix6<f64> {
+    se3_left_jacobian_inv(&-xi)
+}
+
+/// Applies the left increment `delta`, `exp(delta) * pose`.
+pub fn se3_plus(pose: &na::Isometry3<f64>, delta: &na::Vector6<f64>) -> na::Isometry3<f64> {
+    se3_exp(delta) * pose
+}
+
+/// The left increment taking `b` to `a`, `log(a * b^{-1})`.
+pub fn se3_minus(a: &na::Isometry3<f64>, b: &na::Isometry3<f64>) -> na::Vector6<f64> {
+    se3_log(&(a * b.inverse()))
+}
+
+/// `motion` raised to the power `factor` along its screw axis, e.g. half of it for 0.5.
+pub fn se3_scale(motion: &na::Isometry3<f64>, factor: f64) -> na::Isometry3<f64> {
+    se3_exp(&(se3_log(motion) * factor))
+}
+
+/// Geodesic interpolation, `a` at `alpha = 0` and `b` at `alpha = 1`.
+pub fn se3_interpolate(a: &na::Isometry3<f64>, b: &na::Isometry3<f64>, alpha: f64) -> na::Isometry3<f64> {
+    se3_plus(a, &(se3_minus(b, a) * alpha))
+}
+
+/// Similarity `p -> scale * R * p + t`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sim3 {
+    pub pose: na::Isometry3<f64>,
+    pub scale: f64,
+}
+
+impl Sim3 {
+    pub fn identity() -> Self {
+        Self::from_isometry(na::Isometry3::identity())
+    }
+
+    pub fn from_isometry(pose: na::Isometry3<f64>) -> Self {
+        Self { pose, scale: 1.0 }
+    }
+
+    pub fn compose(&self, other: &Self) -> Self {
+        let rotation = self.pose.rotation * other.pose.rotation;
+        let translation = self.pose.rotation * other.pose.translation.vector * self.scale + self.pose.translation.vector;
+        Self {
+            pose: na::Isometry3::from_parts(na::Translation3::from(translation), rotation),
+            scale: self.scale * other.scale,
+        }
+    }
+
+    pub fn inverse(&self) -> Self {
+        let rotation = self.pose.rotation.inverse();
+        let translation = -(rotation * self.pose.translation.vector) / self.scale;
+        Self {
+            pose: na::Isometry3::from_parts(na::Translation3::from(translation), rotation),
+            scale: 1.0 / self.scale,
+        }
+    }
+
+    pub fn transform_point(&self, p: &na::Vector3<f64>) -> na::Vector3<f64> {
+        self.pose.rotation * p * self.scale + self.pose.translation.vector
+    }
+
+    /// For a world to camera similarity, the rigid world to camera pose of the same camera
+    /// once the world has been scaled by `1 / scale`.
+    pub fn to_isometry(&self) -> na::Isometry3<f64> {
+        na::Isometry3::from_parts(na::Translation3::from(self.pose.translation.vector / self.scale), self.pose.rotation)
+    }
+
+    pub fn exp(xi: &Vector7) -> Self {
+        let rho = xi.fixed_rows::<3>(0).into_owned();
+        let omega = xi.fixed_rows::<3>(3).into_owned();
+        let sigma = xi[6];
+        Self {
+            pose: na::Isometry3::from_parts(
+                na::Translation3::from(sim3_w(&omega, sigma) * rho),
+                so3_exp(&omega),
+            ),
+            scale: sigma.exp(),
+        }
+    }
+
+    pub fn log(&self) -> Vector7 {
+        let omega = so3_log(&self.pose.rotation);
+        let sigma = self.scale.ln();
+        let w = sim3_w(&omega, sigma);
+        let rho = w.lu().solve(&self.pose.translation.vector).unwrap_or(self.pose.translation.vector);
+        let mut xi = Vector7::zeros();
+        xi.fixed_rows_mut::<3>(0).copy_from(&rho);
+        xi.fixed_rows_mut::<3>(3).copy_from(&omega);
+        xi[6] = sigma;
+        xi
+    }
+
+    /// `Ad(S)` with `S * exp(xi) * S^{-1} = exp(Ad(S) * xi)`.
+    pub fn adjoint(&self) -> na::SMatrix<f64, 7, 7> {
+        let r = self.pose.rotation.to_rotation_matrix().into_inner();
+        let t = self.pose.translation.vector;
+        let mut adjoint = na::SMatrix::<f64, 7, 7>::zeros();
+        adjoint.fixed_view_mut::<3, 3>(0, 0).copy_from(&(r * self.scale));
+        adjoint.fixed_view_mut::<3, 3>(0, 3).copy_from(&(skew(&t) * r));
+        adjoint.fixed_view_mut::<3, 1>(0, 6).copy_from(&-t);
+        adjoint.fixed_view_mut::<3, 3>(3, 3).copy_from(&r);
+        adjoint[(6, 6)] = 1.0;
+        adjoint
+    }
+
+    /// Applies the left increment `delta`, `exp(delta) * self`.
+    pub fn plus(&self, delta: &Vector7) -> Self {
+        Self::exp(delta).compose(self)
+    }
+
+    /// The left increment taking `other` to `self`, `log(self * other^{-1})`.
+    pub fn minus(&self, other: &Self) -> Vector7 {
+        self.compose(&other.inverse()).log()
+    }
+
+    /// Geodesic interpolation, `self` at `alpha = 0` and `other` at `alpha = 1`.
+    pub fn interpolate(&self, other: &Self, alpha: f64) -> Self {
+        self.plus(&(other.minus(self) * alpha))
+    }
+}
+
+/// Maps `rho` to the translation of `exp([rho, omega, sigma])`, the integral of
+/// `exp(sigma * s) * R(s * omega)` over `s` in `[0, 1]` (Strasdat's thesis, 3.4).
+fn sim3_w(omega: &na::Vector3<f64>, sigma: f64) -> na::Matrix3<f64> {
+    let theta = omega.norm();
+    let omega_hat = skew(omega);
+    let scale = sigma.exp();
+    let (a, b, c) = if sigma.abs() < SIM3_EPSILON {
+        let (a, b) = rotation_coefficients(theta);
+        (a, b, 1.0)
+    } else {
+        let c = sigma.exp_m1() / sigma;
+        let sigma2 = sigma * sigma;
+        if theta < SIM3_EPSILON {
+            (
+                ((sigma - 1.0) * scale + 1.0) / sigma2,
+                (scale * 0.5 * sigma2 + scale - 1.0 - sigma * scale) / (sigma2 * sigma),
+                c,
+            )
+        } else {
+            let (s, co) = theta.sin_cos();
+            let x = scale * s;
+            let y = scale * co;
+            let z = theta * theta + sigma2;
+            (
+                (x * sigma + (1.0 - y) * theta) / (theta * z),
+                (c - ((y - 1.0) * sigma + x * theta) / z) / (theta * theta),
+                c,
+            )
+        }
+    };
+    na::Matrix3::identity() * c + omega_hat * a + omega_hat * omega_hat * b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tangents() -> Vec<na::Vector6<f64>> {
+        vec![
+            na::Vector6::zeros(),
+            na::Vector6::new(0.3, -0.2, 0.1, 1e-9, -2e-9, 1e-9),
+            na::Vector6::new(1.0, 2.0, -0.5, 0.01, 0.05, -0.03),
+            na::Vector6::new(-0.4, 0.1, 0.7, 0.5, -1.2, 0.8),
+            na::Vector6::new(0.2, 0.0, -0.1, 0.0, 0.0, std::f64::consts::PI - 1e-7),
+        ]
+    }
+
+    #[test]
+    fn test_exp_log() {
+        for xi in tangents() {
+            assert!((se3_log(&se3_exp(&xi)) - xi).norm() < 1e-6, "{:?}", xi);
+            let pose = se3_exp(&xi);
+            let omega = xi.fixed_rows::<3>(3).into_owned();
+            assert!((pose.rotation.to_rotation_matrix().into_inner()
+                - na::Rotation3::from_scaled_axis(omega).into_inner()).norm() < 1e-12);
+
+            for sigma in [0.0, 1e-9, 0.3, -1.5] {
+                let mut xi7 = Vector7::zeros();
+                xi7.fixed_rows_mut::<6>(0).copy_from(&xi);
+                xi7[6] = sigma;
+                assert!((Sim3::exp(&xi7).log() - xi7).norm() < 1e-6, "{:?}", xi7);
+            }
+        }
+        // the Sim3 exponential agrees with SE3 for a unit scale
+        let xi = tangents()[3];
+        let mut xi7 = Vector7::zeros();
+        xi7.fixed_rows_mut::<6>(0).copy_from(&xi);
+        let difference = Sim3::exp(&xi7).pose.to_homogeneous() - se3_exp(&xi).to_homogeneous();
+        assert!(difference.norm() < 1e-12);
+        assert!((skew(&na::Vector3::new(1.0, 2.0, 3.0)) * na::Vector3::new(-1.0, 0.5, 2.0)
+            - na::Vector3::new(1.0, 2.0, 3.0).cross(&na::Vector3::new(-1.0, 0.5, 2.0))).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_jacobians() {
+        let step = 1e-6;
+        for xi in tangents().into_iter().take(4) {
+            let left = se3_left_jacobian(&xi);
+            let right = se3_right_jacobian(&xi);
+            let pose = se3_exp(&xi);
+            for k in 0..6 {
+                let mut d = na::Vector6::zeros();
+                d[k] = step;
+                let moved = se3_exp(&(xi + d));
+                // exp(xi + d) ~ exp(J_l * d) * exp(xi) ~ exp(xi) * exp(J_r * d)
+                let numeric_left = se3_minus(&moved, &pose) / step;
+                let numeric_right = se3_log(&(pose.inverse() * moved)) / step;
+                assert!((numeric_left - left.column(k)).norm() < 1e-5, "{:?} {}", xi, k);
+                assert!((numeric_right - right.column(k)).norm() < 1e-5, "{:?} {}", xi, k);
+            }
+            assert!((se3_left_jacobian_inv(&xi) * left - na::Matrix6::identity()).norm() < 1e-9);
+            assert!((se3_right_jacobian_inv(&xi) * right - na::Matrix6::identity()).norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_adjoint() {
+        let pose = se3_exp(&na::Vector6::new(0.5, -1.0, 2.0, 0.3, 0.2, -0.4));
+        let xi = na::Vector6::new(0.1, 0.2, -0.3, -0.2, 0.1, 0.05);
+        let conjugated = pose * se3_exp(&xi) * pose.inverse();
+        assert!((se3_log(&conjugated) - se3_adjoint(&pose) * xi).norm() < 1e-9);
+
+        let s = Sim3 { pose, scale: 1.7 };
+        let xi7 = Vector7::from_column_slice(&[0.1, 0.2, -0.3, -0.2, 0.1, 0.05, 0.2]);
+        let conjugated = s.compose(&Sim3::exp(&xi7)).compose(&s.inverse());
+        assert!((conjugated.log() - s.adjoint() * xi7).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let a = se3_exp(&na::Vector6::new(0.5, -1.0, 2.0, 0.3, 0.2, -0.4));
+        let b = se3_exp(&na::Vector6::new(-0.5, 0.0, 1.0, 0.1, -0.2, 0.4));
+        assert!((se3_interpolate(&a, &b, 0.0).to_homogeneous() - a.to_homogeneous()).norm() < 1e-12);
+        assert!((se3_interpolate(&a, &b, 1.0).to_homogeneous() - b.to_homogeneous()).norm() < 1e-9);
+        // the midpoint is half way along the geodesic from either end
+        let middle = se3_interpolate(&a, &b, 0.5);
+        assert!((se3_minus(&middle, &a) - se3_minus(&b, &middle)).norm() < 1e-9);
+        let q = so3_interpolate(&a.rotation, &b.rotation, 0.5);
+        assert!(q.angle_to(&middle.rotation) < 1e-9);
+
+        let s = Sim3 { pose: a, scale: 2.0 };
+        let t = Sim3 { pose: b, scale: 0.5 };
+        let middle = s.interpolate(&t, 0.5);
+        assert!((middle.scale - 1.0).abs() < 1e-12);
+        assert!((middle.minus(&s) - t.minus(&middle)).norm() < 1e-9);
+        let plus = s.plus(&t.minus(&s));
+        assert!((plus.pose.to_homogeneous() - t.pose.to_homogeneous()).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_sim3_inverse() {
+        let s = Sim3 {
+            pose: na::Isometry3::new(na::Vector3::new(1.0, -2.0, 0.5), na::Vector3::new(0.3, -0.1, 0.2)),
+            scale: 2.5,
+        };
+        let identity = s.compose(&s.inverse());
+        assert!((identity.pose.to_homogeneous() - na::Matrix4::identity()).norm() < 1e-12);
+        assert!((identity.scale - 1.0).abs() < 1e-12);
+        let p = na::Vector3::new(0.1, 0.2, 0.3);
+        assert!((s.inverse().transform_point(&s.transform_point(&p)) - p).norm() < 1e-12);
+    }
+}
@@ -9,6 +9,28 @@ pub struct EurocData {
 
 pub type EurocDataSet = Vec<EurocData>;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DatasetKind {
+    Euroc,
+}
+
+impl std::str::FromStr for DatasetKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "euroc" => Ok(DatasetKind::Euroc),
+            _ => Err(format!("unknown dataset type: {}", s)),
+        }
+    }
+}
+
+pub fn load_data(kind: DatasetKind, path: &str) -> Result<EurocDataSet, Box<dyn Error>> {
+    match kind {
+        DatasetKind::Euroc => load_euroc_data(path),
+    }
+}
+
 pub fn load_euroc_data(path: &str) -> Result<EurocDataSet, Box<dyn Error>> {
     let csv_path = format!("{}/data.csv", path);
     let img_path = format!("{}/data", path);
@@ -1,4 +1,5 @@
 use std::time;
+use std::collections::HashSet;
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -11,6 +12,7 @@ use nalgebra as na;
 use super::super::{
     frame,
     camera,
+    extractor::DescriptorKind,
 };
 use super::mappoint::*;
 
@@ -23,10 +25,13 @@ pub struct KeyFrame {
     pub img: Mat,
     pub keypoints: core::Vector<core::KeyPoint>,
     pub descriptors: Mat,
+    pub descriptor_kind: DescriptorKind,
     pub intrinsics: camera::CameraIntrinsics,
     pub pose: na::Isometry3<f64>, 
     pub observations: Vec<Rc<RefCell<MapPoint>>>,
     pub connections: Vec<KeyFrameId>,
+    /// parent in the spanning tree of keyframes, `None` for the root
+    pub parent: Option<KeyFrameId>,
 }
 
 impl KeyFrame {
@@ -36,6 +41,7 @@ impl KeyFrame {
         img: Mat,
         keypoints: core::Vector<core::KeyPoint>,
         descriptors: Mat,
+        descriptor_kind: DescriptorKind,
         intrinsics: camera::CameraIntrinsics,
         pose: na::Isometry3<f64>,
     ) -> Self {
@@ -46,10 +52,12 @@ impl KeyFrame {
             img,
             keypoints,
             descriptors,
+            descriptor_kind,
             intrinsics,
             pose,
             observations: Vec::new(),
             connections: Vec::new(),
+            parent: None,
         }
     }
 
@@ -59,6 +67,7 @@ impl KeyFrame {
         img: Mat,
         keypoints: core::Vector<core::KeyPoint>,
         descriptors: Mat,
+        descriptor_kind: DescriptorKind,
         intrinsics: camera::CameraIntrinsics,
         pose: na::Isometry3<f64>,
     ) -> Self {
@@ -68,10 +77,12 @@ impl KeyFrame {
             img,
             keypoints,
             descriptors,
+            descriptor_kind,
             intrinsics,
             pose,
             observations: Vec::new(),
             connections: Vec::new(),
+            parent: None,
         }
     }
 
@@ -84,6 +95,7 @@ impl KeyFrame {
             frame.img.clone(),
             frame.keypoints.clone(),
             frame.descriptors.clone(),
+            frame.descriptor_kind,
             intrinsics.clone(),
             frame.pose.clone(),
         )
@@ -105,7 +117,18 @@ impl KeyFrame {
         self.connections.extend(ids);
     }
 
+    pub fn set_parent(&mut self, id: KeyFrameId) {
+        self.parent = Some(id);
+    }
+
     pub fn observation(&self, id: MapPointId) -> Option<Rc<RefCell<MapPoint>>> {
         self.observations.iter().find(|x| x.borrow().id == id).cloned()
     }
+
+    /// Indices of the keypoints observing a map point.
+    pub fn matched_keypoints(&self) -> HashSet<usize> {
+        self.observations.iter()
+            .filter_map(|mp| mp.borrow().reference(self.id).map(|reference| reference.index))
+            .collect()
+    }
 }
\ No newline at end of file
@@ -1,16 +1,21 @@
 
+use std::error::Error;
+
 use opencv::{
     prelude::*,
     core,
 };
 use nalgebra as na;
 
+use super::super::extractor::{self, DescriptorKind};
 use super::keyframe::*;
 
 pub type MapPointId = usize;
 
 pub struct MapPointReference {
     pub id: KeyFrameId,
+    /// index of the keypoint in the keyframe
+    pub index: usize,
     pub keypoint: core::KeyPoint,
     pub descriptor: Mat,
 }
@@ -18,22 +23,26 @@ pub struct MapPointReference {
 impl MapPointReference {
     pub fn new(
         id: KeyFrameId,
+        index: usize,
         keypoint: &core::KeyPoint,
         descriptor: &Mat,
     ) -> Self {
         MapPointReference {
             id,
+            index,
             keypoint: keypoint.clone(),
             descriptor: descriptor.clone(),
         }
     }
     pub fn new_with_kf(
         keyframe: &KeyFrame,
+        index: usize,
         keypoint: &core::KeyPoint,
         descriptor: &Mat,
     ) -> Self {
         MapPointReference::new(
             keyframe.id,
+            index,
             keypoint,
             descriptor,
         )
@@ -92,4 +101,13 @@ impl MapPoint {
     pub fn reference(&self, id: KeyFrameId) -> Option<&MapPointReference> {
         self.references.iter().find(|x| x.id == id)
     }
+
+    /// Replaces the descriptor with the most distinctive descriptor among the references.
+    pub fn update_descriptor(&mut self, kind: DescriptorKind) -> Result<(), Box<dyn Error>> {
+        let descriptors = self.references.iter().map(|r| r.descriptor.clone()).collect::<Vec<_>>();
+        if let Some(descriptor) = extractor::distinctive_descriptor(&descriptors, kind)? {
+            self.desctriptor = descriptor;
+        }
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -6,6 +6,8 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::sync::atomic::{Ordering, AtomicUsize};
 
+use nalgebra as na;
+
 use keyframe::*;
 use mappoint::*;
 
@@ -61,6 +63,11 @@ impl Map {
         self.keyframes.get(&id)
     }
 
+    /// The most recently created keyframe.
+    pub fn latest_keyframe(&self) -> Option<&KeyFrame> {
+        self.keyframes.values().max_by_key(|kf| kf.id)
+    }
+
     pub fn keyframe_mut(&mut self, id: KeyFrameId) -> Option<&mut KeyFrame> {
         self.keyframes.get_mut(&id)
     }
@@ -68,4 +75,82 @@ impl Map {
     pub fn mappoint(&self, id: MapPointId) -> Option<Rc<RefCell<MapPoint>>> {
         self.mappoints.get(&id).cloned()
     } 
+
+    /// Drops the observation of map point `id_mp` by keyframe `id_kf` on both sides.
+    pub fn erase_observation(&mut self, id_kf: KeyFrameId, id_mp: MapPointId) {
+        if let Some(mp) = self.mappoints.get(&id_mp) {
+            mp.borrow_mut().references.retain(|r| r.id != id_kf);
+        }
+        if let Some(kf) = self.keyframes.get_mut(&id_kf) {
+            kf.observations.retain(|mp| mp.borrow().id != id_mp);
+        }
+    }
+
+    /// Removes map point `id` and all its observations.
+    pub fn remove_mappoint(&mut self, id: MapPointId) {
+        let mp = match self.mappoints.remove(&id) {
+            Some(mp) => mp,
+            None => return,
+        };
+        for reference in mp.borrow().references.iter() {
+            if let Some(kf) = self.keyframes.get_mut(&reference.id) {
+                kf.observations.retain(|other| !Rc::ptr_eq(other, &mp));
+            }
+        }
+    }
+
+    /// Keyframes sharing map points with keyframe `id` and the number of points they share,
+    /// most shared first.
+    pub fn covisibility(&self, id: KeyFrameId) -> Vec<(KeyFrameId, usize)> {
+        let kf = match self.keyframe(id) {
+            Some(kf) => kf,
+            None => return Vec::new(),
+        };
+        let mut counts = HashMap::<KeyFrameId, usize>::new();
+        for mp in kf.observations.iter() {
+            for reference in mp.borrow().references.iter() {
+                if reference.id != id && self.keyframes.contains_key(&reference.id) {
+                    *counts.entry(reference.id).or_insert(0) += 1;
+                }
+            }
+        }
+        let mut covisibility = counts.into_iter().collect::<Vec<_>>();
+        covisibility.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        covisibility
+    }
+
+    /// Median depth of the map points observed by keyframe `id`, in that keyframe.
+    pub fn median_depth(&self, id: KeyFrameId) -> Option<f64> {
+        let kf = self.keyframe(id)?;
+        let mut depths = kf.observations.iter()
+            .map(|mp| (kf.pose * na::Point3::from(mp.borrow().position)).z)
+            .collect::<Vec<_>>();
+        if depths.is_empty() {
+            return None;
+        }
+        depths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Some(depths[depths.len() / 2])
+    }
+
+    /// Scales the given keyframes and map points by `scale` about the camera center of
+    /// keyframe `origin`, whose pose stays unchanged, as do all orientations.
+    pub fn rescale(&mut self, origin: KeyFrameId, scale: f64, keyframes: &[KeyFrameId], mappoints: &[MapPointId]) {
+        let center = match self.keyframe(origin) {
+            Some(kf) => kf.pose.inverse().translation.vector,
+            None => return,
+        };
+        for id in keyframes {
+            if let Some(kf) = self.keyframe_mut(*id) {
+                let kf_center = kf.pose.inverse().translation.vector;
+                let kf_center = center + (kf_center - center) * scale;
+                kf.pose.translation.vector = -(kf.pose.rotation * kf_center);
+            }
+        }
+        for id in mappoints {
+            if let Some(mp) = self.mappoint(*id) {
+                let mut mp = mp.borrow_mut();
+                mp.position = center + (mp.position - center) * scale;
+            }
+        }
+    }
 }
\ No newline at end of file
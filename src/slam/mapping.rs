@@ -0,0 +1,222 @@
+//! Keyframe insertion: when a tracked frame becomes a keyframe, and the map points it
+//! triangulates with its covisible keyframes so tracking keeps finding points to match
+//! once the camera leaves the area seen at initialization.
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::HashSet,
+    error::Error,
+};
+
+use opencv::{
+    prelude::*,
+    core,
+    features2d,
+};
+use nalgebra as na;
+
+use super::camera::CameraIntrinsics;
+use super::config::{MappingConfig, SlamConfig};
+use super::frame::Frame;
+use super::lie;
+use super::map::{
+    Map,
+    keyframe::{KeyFrame, KeyFrameId},
+    mappoint::{MapPoint, MapPointReference},
+};
+use super::triangulation;
+
+/// 95% chi-square thresholds of one and two degrees of freedom, for the epipolar
+/// distance and the reprojection error of a new point
+const CHI2_1DOF: f64 = 3.841;
+const CHI2_2DOF: f64 = 5.991;
+/// keyframe pairs whose baseline is smaller than this fraction of the scene depth
+/// triangulate too poorly to be worth matching
+const MIN_BASELINE_DEPTH_RATIO: f64 = 0.01;
+
+/// Whether a frame tracking `tracked` map points, `frames_since` frames after the latest
+/// keyframe, should become a keyframe.
+pub fn need_keyframe(map: &Map, tracked: usize, frames_since: usize, config: &MappingConfig) -> bool {
+    if frames_since < config.keyframe_min_frames {
+        return false;
+    }
+    if frames_since >= config.keyframe_max_frames {
+        return true;
+    }
+    match map.latest_keyframe() {
+        Some(reference) => (tracked as f64) < config.keyframe_tracked_ratio * reference.observations.len() as f64,
+        None => false,
+    }
+}
+
+/// Adds `frame` to the map as a keyframe observing the map points it tracked, given as
+/// keypoint index and map point, then triangulates new map points between its unmatched
+/// keypoints and those of its covisible keyframes. Returns the id of the keyframe and
+/// the number of new map points.
+pub fn insert_keyframe(
+    map: &mut Map,
+    frame: &Frame,
+    tracked: &[(usize, Rc<RefCell<MapPoint>>)],
+    intrinsics: &CameraIntrinsics,
+    config: &SlamConfig,
+) -> Result<(KeyFrameId, usize), Box<dyn Error>> {
+    let mut kf = KeyFrame::from_frame(frame, intrinsics);
+    if let Some(reference) = map.latest_keyframe() {
+        kf.set_parent(reference.id);
+    }
+    for (idx, mappoint) in tracked {
+        let mut mp = mappoint.borrow_mut();
+        // the reference keyframe fallback can match several keypoints to one point
+        if mp.reference(kf.id).is_some() {
+            continue;
+        }
+        let keypoint = frame.keypoints.get(*idx)?;
+        let descriptor = frame.descriptors.row(*idx as i32)?;
+        mp.add_reference(MapPointReference::new_with_kf(&kf, *idx, &keypoint, &descriptor));
+        mp.update_descriptor(frame.descriptor_kind)?;
+        kf.add_observation(mappoint.clone());
+    }
+    let id = kf.id;
+    map.insert_keyframe(kf);
+
+    let mut created = 0;
+    let neighbors = map.covisibility(id).into_iter()
+        .take(config.mapping.triangulation_neighbors)
+        .map(|(neighbor, _)| neighbor)
+        .collect::<Vec<_>>();
+    for neighbor in neighbors {
+        created += triangulate_pair(map, id, neighbor, config)?;
+    }
+
+    Ok((id, created))
+}
+
+/// Matches the keypoints of keyframes `id1` and `id2` that observe no map point yet and
+/// adds a map point for every match that satisfies the epipolar constraint and
+/// triangulates in front of both cameras with enough parallax.
+fn triangulate_pair(
+    map: &mut Map,
+    id1: KeyFrameId,
+    id2: KeyFrameId,
+    config: &SlamConfig,
+) -> Result<usize, Box<dyn Error>> {
+    let (kf1, kf2) = match (map.keyframe(id1), map.keyframe(id2)) {
+        (Some(kf1), Some(kf2)) => (kf1, kf2),
+        _ => return Ok(0),
+    };
+    let baseline = (kf1.pose.inverse().translation.vector - kf2.pose.inverse().translation.vector).norm();
+    match map.median_depth(id2) {
+        Some(depth) if baseline >= MIN_BASELINE_DEPTH_RATIO * depth => {},
+        _ => return Ok(0),
+    }
+
+    let unmatched = |kf: &KeyFrame| {
+        let matched = kf.matched_keypoints();
+        (0..kf.keypoints.len()).filter(|idx| !matched.contains(idx)).collect::<Vec<_>>()
+    };
+    let candidates1 = unmatched(kf1);
+    let candidates2 = unmatched(kf2);
+    if candidates1.is_empty() || candidates2.is_empty() {
+        return Ok(0);
+    }
+
+    let kind = kf1.descriptor_kind;
+    let mut bf_matcher = features2d::BFMatcher::create(kind.norm_type(), false)?;
+    bf_matcher.add(&select_rows(&kf2.descriptors, &candidates2)?)?;
+    let mut knn_matches = core::Vector::<core::Vector<core::DMatch>>::default();
+    bf_matcher.knn_match(&select_rows(&kf1.descriptors, &candidates1)?, &mut knn_matches, 2, &core::Mat::default(), false)?;
+
+    let max_distance = kind.max_distance(&config.matcher);
+    let mut matches = Vec::new();
+    for candidates in knn_matches.iter() {
+        let best = match candidates.get(0) {
+            Ok(best) => best,
+            Err(_) => continue,
+        };
+        let passes_ratio = match candidates.get(1) {
+            Ok(second) => (best.distance as f64) < config.matcher.nn_ratio * second.distance as f64,
+            Err(_) => true,
+        };
+        if best.distance <= max_distance && passes_ratio {
+            matches.push((best.distance, candidates1[best.query_idx as usize], candidates2[best.train_idx as usize]));
+        }
+    }
+    // the closest match claims a keypoint of the second keyframe first
+    matches.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let intrinsics = &kf1.intrinsics;
+    let relative = kf2.pose * kf1.pose.inverse();
+    let essential = lie::skew(&relative.translation.vector) * relative.rotation.to_rotation_matrix().matrix();
+    let level_sigma = |kp: &core::KeyPoint| {
+        config.optimize.pixel_sigma * (config.feature.scale_factor as f64).powi(kp.octave())
+    };
+
+    let mut taken = HashSet::new();
+    let mut new_points = Vec::new();
+    for (_, idx1, idx2) in matches {
+        if taken.contains(&idx2) {
+            continue;
+        }
+        let kp1 = kf1.keypoints.get(idx1)?;
+        let kp2 = kf2.keypoints.get(idx2)?;
+        let x1 = na::Point2::new(kp1.pt().x as f64, kp1.pt().y as f64);
+        let x2 = na::Point2::new(kp2.pt().x as f64, kp2.pt().y as f64);
+        let (sigma1, sigma2) = (level_sigma(&kp1), level_sigma(&kp2));
+
+        // distance of the second keypoint to the epipolar line of the first, in pixels
+        let n1 = intrinsics.inv_projection(&x1).to_homogeneous();
+        let n2 = intrinsics.inv_projection(&x2).to_homogeneous();
+        let line = essential * n1;
+        let epipolar_distance = n2.dot(&line).abs() / line.xy().norm() * intrinsics.fx;
+        if epipolar_distance.is_nan() || epipolar_distance.powi(2) > CHI2_1DOF * sigma2 * sigma2 {
+            continue;
+        }
+
+        let triangulated = match triangulation::triangulate(
+            config.mapping.triangulation, &kf1.pose, &kf2.pose, &x1, &x2, intrinsics, sigma1,
+        ) {
+            Some(triangulated) => triangulated,
+            None => continue,
+        };
+        if triangulated.depth1 <= 0.0
+            || triangulated.depth2 <= 0.0
+            || triangulated.parallax_deg < config.mapping.min_parallax_deg
+            || triangulated.reprojection_error1.powi(2) > CHI2_2DOF * sigma1 * sigma1
+            || triangulated.reprojection_error2.powi(2) > CHI2_2DOF * sigma2 * sigma2
+        {
+            continue;
+        }
+
+        taken.insert(idx2);
+        let des1 = kf1.descriptors.row(idx1 as i32)?;
+        let des2 = kf2.descriptors.row(idx2 as i32)?;
+        let mut mp = MapPoint::from_point(triangulated.point, &des1);
+        mp.add_reference(MapPointReference::new_with_kf(kf1, idx1, &kp1, &des1));
+        mp.add_reference(MapPointReference::new_with_kf(kf2, idx2, &kp2, &des2));
+        mp.update_descriptor(kind)?;
+        new_points.push(Rc::new(RefCell::new(mp)));
+    }
+
+    let created = new_points.len();
+    for mp in new_points {
+        for id in [id1, id2] {
+            if let Some(kf) = map.keyframe_mut(id) {
+                kf.add_observation(mp.clone());
+            }
+        }
+        map.insert_mappoint(mp);
+    }
+    Ok(created)
+}
+
+/// Copies the given rows of a descriptor matrix into a new matrix.
+fn select_rows(descriptors: &core::Mat, rows: &[usize]) -> Result<core::Mat, Box<dyn Error>> {
+    let mut selected = core::Vector::<core::Mat>::default();
+    for row in rows {
+        selected.push(descriptors.row(*row as i32)?);
+    }
+    let mut out = core::Mat::default();
+    core::vconcat(&selected, &mut out)?;
+    Ok(out)
+}
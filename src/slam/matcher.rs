@@ -0,0 +1,313 @@
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    error::Error,
+    collections::HashMap,
+};
+
+use opencv::{
+    prelude::*,
+    core,
+};
+use nalgebra as na;
+
+use super::camera;
+use super::config::MatcherConfig;
+use super::extractor;
+use super::frame::Frame;
+use super::map::mappoint::MapPoint;
+
+/// size in pixels of the cells used to look up keypoints around a projection
+const CELL_SIZE: f32 = 16.0;
+
+pub struct ProjectionMatch {
+    pub keypoint_idx: usize,
+    pub mappoint: Rc<RefCell<MapPoint>>,
+    pub distance: f64,
+}
+
+/// Keypoints of a frame bucketed into square cells for radius queries.
+struct KeypointGrid {
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<usize>>,
+}
+
+impl KeypointGrid {
+    fn new(frame: &Frame) -> Self {
+        let cols = ((frame.img.cols() as f32 / CELL_SIZE).ceil() as usize).max(1);
+        let rows = ((frame.img.rows() as f32 / CELL_SIZE).ceil() as usize).max(1);
+        let mut cells = vec![Vec::new(); cols * rows];
+        for (idx, kp) in frame.keypoints.iter().enumerate() {
+            let col = ((kp.pt().x / CELL_SIZE).max(0.0) as usize).min(cols - 1);
+            let row = ((kp.pt().y / CELL_SIZE).max(0.0) as usize).min(rows - 1);
+            cells[row * cols + col].push(idx);
+        }
+        Self { cols, rows, cells }
+    }
+
+    fn query(&self, frame: &Frame, u: f32, v: f32, radius: f32) -> Vec<usize> {
+        let min_col = ((u - radius) / CELL_SIZE).floor().max(0.0) as usize;
+        let max_col = (((u + radius) / CELL_SIZE).floor().max(0.0) as usize).min(self.cols - 1);
+        let min_row = ((v - radius) / CELL_SIZE).floor().max(0.0) as usize;
+        let max_row = (((v + radius) / CELL_SIZE).floor().max(0.0) as usize).min(self.rows - 1);
+
+        let mut indices = Vec::new();
+        for row in min_row..=max_row {
+            for col in min_col..=max_col {
+                for &idx in self.cells[row * self.cols + col].iter() {
+                    let pt = frame.keypoints.get(idx).unwrap().pt();
+                    if (pt.x - u).powi(2) + (pt.y - v).powi(2) <= radius * radius {
+                        indices.push(idx);
+                    }
+                }
+            }
+        }
+        indices
+    }
+}
+
+/// Matches map points to the keypoints of `frame` by projecting them with the pose prior `pose`
+/// (world to camera).
+///
+/// Only keypoints within `projection_radius` (scaled by the octave the point was observed at)
+/// of the projection, and at most one octave away, are compared. A match must pass the
+/// descriptor threshold and the ratio test, and each keypoint keeps its best match only.
+/// With `check_orientation`, matches whose rotation relative to the reference observation
+/// falls outside the three dominant histogram bins are dropped.
+pub fn search_by_projection(
+    frame: &Frame,
+    pose: &na::Isometry3<f64>,
+    mappoints: &[Rc<RefCell<MapPoint>>],
+    intrinsics: &camera::CameraIntrinsics,
+    scale_factor: f32,
+    config: &MatcherConfig,
+) -> Result<Vec<ProjectionMatch>, Box<dyn Error>> {
+    let grid = KeypointGrid::new(frame);
+    let (width, height) = (frame.img.cols() as f64, frame.img.rows() as f64);
+    let max_distance = frame.descriptor_kind.max_distance(config) as f64;
+
+    let mut best_per_keypoint: HashMap<usize, (f64, usize, f32)> = HashMap::new();
+    for (mp_idx, mappoint) in mappoints.iter().enumerate() {
+        let mp = mappoint.borrow();
+        let p_c = pose * na::Point3::from(mp.position);
+        if p_c.z <= 0.0 {
+            continue;
+        }
+        let u = intrinsics.fx * p_c.x / p_c.z + intrinsics.cx;
+        let v = intrinsics.fy * p_c.y / p_c.z + intrinsics.cy;
+        if u < 0.0 || v < 0.0 || u >= width || v >= height {
+            continue;
+        }
+
+        // predict the octave from the latest observation of the point
+        let reference = match mp.references.last() {
+            Some(reference) => reference,
+            None => continue,
+        };
+        let octave = reference.keypoint.octave();
+        let radius = config.projection_radius * scale_factor.powi(octave);
+
+        let mut best = (f64::MAX, usize::MAX);
+        let mut second = f64::MAX;
+        for idx in grid.query(frame, u as f32, v as f32, radius) {
+            let kp = frame.keypoints.get(idx)?;
+            if (kp.octave() - octave).abs() > 1 {
+                continue;
+            }
+            let descriptor = frame.descriptors.row(idx as i32)?;
+            let distance = extractor::descriptor_distance(&mp.desctriptor, &descriptor, frame.descriptor_kind)?;
+            if distance < best.0 {
+                second = best.0;
+                best = (distance, idx);
+            } else if distance < second {
+                second = distance;
+            }
+        }
+
+        if best.0 > max_distance || best.0 > config.nn_ratio * second {
+            continue;
+        }
+        let rotation = frame.keypoints.get(best.1)?.angle() - reference.keypoint.angle();
+        match best_per_keypoint.get(&best.1) {
+            Some((distance, _, _)) if *distance <= best.0 => {},
+            _ => {
+                best_per_keypoint.insert(best.1, (best.0, mp_idx, rotation));
+            },
+        }
+    }
+
+    let mut matches = best_per_keypoint.into_iter()
+        .map(|(keypoint_idx, (distance, mp_idx, rotation))| (keypoint_idx, distance, mp_idx, rotation))
+        .collect::<Vec<_>>();
+
+    if config.check_orientation {
+        let rotations = matches.iter().map(|m| m.3).collect::<Vec<_>>();
+        let keep = rotation_consistency(&rotations, config.orientation_bins);
+        matches = matches.into_iter().zip(keep).filter(|(_, keep)| *keep).map(|(m, _)| m).collect();
+    }
+
+    Ok(matches.into_iter()
+        .map(|(keypoint_idx, distance, mp_idx, _)| ProjectionMatch {
+            keypoint_idx,
+            mappoint: mappoints[mp_idx].clone(),
+            distance,
+        })
+        .collect())
+}
+
+/// Builds a histogram of keypoint rotations (degrees) and keeps the matches falling into the
+/// three most populated bins, ignoring bins with less than a tenth of the largest one.
+pub fn rotation_consistency(rotations: &[f32], bins: usize) -> Vec<bool> {
+    let bin_of = |rotation: f32| {
+        let rotation = rotation.rem_euclid(360.0);
+        ((rotation / 360.0 * bins as f32) as usize).min(bins - 1)
+    };
+
+    let mut histogram = vec![0usize; bins];
+    for rotation in rotations.iter() {
+        histogram[bin_of(*rotation)] += 1;
+    }
+
+    let mut order = (0..bins).collect::<Vec<_>>();
+    order.sort_by(|a, b| histogram[*b].cmp(&histogram[*a]));
+    let max = histogram[order[0]];
+    let dominant = order.into_iter()
+        .take(3)
+        .filter(|bin| histogram[*bin] > 0 && histogram[*bin] as f32 >= 0.1 * max as f32)
+        .collect::<Vec<_>>();
+
+    rotations.iter().map(|rotation| dominant.contains(&bin_of(*rotation))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::camera::Distortion;
+    use super::super::extractor::DescriptorKind;
+    use super::super::map::mappoint::MapPointReference;
+
+    /// An ORB sized descriptor at Hamming distance `bits` from the all zero descriptor.
+    fn descriptor(bits: usize) -> core::Mat {
+        let mut descriptor = core::Mat::new_rows_cols_with_default(1, 32, core::CV_8UC1, core::Scalar::all(0.0)).unwrap();
+        for bit in 0..bits {
+            *descriptor.at_2d_mut::<u8>(0, (bit / 8) as i32).unwrap() |= 1 << (bit % 8);
+        }
+        descriptor
+    }
+
+    fn keypoint(u: f32, v: f32, octave: i32, angle: f32) -> core::KeyPoint {
+        core::KeyPoint::new_point(core::Point2f::new(u, v), 31.0, angle, 0.0, octave, -1).unwrap()
+    }
+
+    /// A map point at depth 5 in front of the identity camera projecting to `(u, v)`,
+    /// last observed at `octave`.
+    fn mappoint(intrinsics: &camera::CameraIntrinsics, u: f64, v: f64, octave: i32) -> Rc<RefCell<MapPoint>> {
+        let position = na::Vector3::new((u - intrinsics.cx) * 5.0 / intrinsics.fx, (v - intrinsics.cy) * 5.0 / intrinsics.fy, 5.0);
+        let mut mp = MapPoint::new(position, descriptor(0));
+        mp.add_reference(MapPointReference::new(0, 0, &keypoint(u as f32, v as f32, octave, 0.0), &descriptor(0)));
+        Rc::new(RefCell::new(mp))
+    }
+
+    fn frame(keypoints: &[(core::KeyPoint, usize)]) -> Frame {
+        let mut descriptors = core::Vector::<core::Mat>::default();
+        for (_, bits) in keypoints.iter() {
+            descriptors.push(descriptor(*bits));
+        }
+        let mut stacked = core::Mat::default();
+        core::vconcat(&descriptors, &mut stacked).unwrap();
+        Frame::new(
+            std::time::Duration::default(),
+            core::Mat::new_rows_cols_with_default(480, 752, core::CV_8UC1, core::Scalar::all(0.0)).unwrap(),
+            keypoints.iter().map(|(kp, _)| kp.clone()).collect(),
+            stacked,
+            DescriptorKind::Binary,
+            na::Isometry3::identity(),
+        )
+    }
+
+    #[test]
+    fn test_search_by_projection() {
+        let intrinsics = camera::CameraIntrinsics::new([458.0, 457.0, 367.0, 248.0], Distortion::default());
+        let config = MatcherConfig::default();
+        let mappoints = vec![
+            // matched by the close keypoint, the identical one is outside the window
+            mappoint(&intrinsics, 367.0, 248.0, 0),
+            // only candidate is two octaves finer
+            mappoint(&intrinsics, 200.0, 100.0, 0),
+            // observed at octave 2, the window grows to 15 * 1.2^2 = 21.6 pixels
+            mappoint(&intrinsics, 500.0, 300.0, 2),
+            // descriptor too far
+            mappoint(&intrinsics, 600.0, 400.0, 0),
+            // two equally good candidates fail the ratio test
+            mappoint(&intrinsics, 100.0, 400.0, 0),
+        ];
+        let frame = frame(&[
+            (keypoint(372.0, 248.0, 0, 0.0), 2),
+            (keypoint(407.0, 248.0, 0, 0.0), 0),
+            (keypoint(202.0, 100.0, 2, 0.0), 0),
+            (keypoint(520.0, 300.0, 2, 0.0), 0),
+            (keypoint(600.0, 400.0, 0, 0.0), 40),
+            (keypoint(101.0, 400.0, 0, 0.0), 4),
+            (keypoint(99.0, 400.0, 0, 0.0), 4),
+        ]);
+
+        let matches = search_by_projection(&frame, &na::Isometry3::identity(), &mappoints, &intrinsics, 1.2, &config).unwrap();
+        let mut matched = matches.iter()
+            .map(|m| (m.keypoint_idx, mappoints.iter().position(|mp| Rc::ptr_eq(mp, &m.mappoint)).unwrap()))
+            .collect::<Vec<_>>();
+        matched.sort();
+        assert_eq!(matched, vec![(0, 0), (3, 2)]);
+        assert_eq!(matches.iter().find(|m| m.keypoint_idx == 0).unwrap().distance, 2.0);
+
+        // seen from behind, nothing projects
+        let behind = na::Isometry3::rotation(na::Vector3::y() * std::f64::consts::PI);
+        assert!(search_by_projection(&frame, &behind, &mappoints, &intrinsics, 1.2, &config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search_by_projection_orientation() {
+        let intrinsics = camera::CameraIntrinsics::new([458.0, 457.0, 367.0, 248.0], Distortion::default());
+        let mut config = MatcherConfig::default();
+        let positions = (0..12).map(|i| (40.0 + 60.0 * i as f64, 200.0)).collect::<Vec<_>>();
+        let mappoints = positions.iter().map(|(u, v)| mappoint(&intrinsics, *u, *v, 0)).collect::<Vec<_>>();
+        // all keypoints rotated by 30 degrees but the last, rotated by 180 and alone in
+        // a bin with less than a tenth of the dominant one
+        let frame = frame(&positions.iter().enumerate()
+            .map(|(i, (u, v))| (keypoint(*u as f32, *v as f32, 0, if i == 11 { 180.0 } else { 30.0 }), 0))
+            .collect::<Vec<_>>());
+
+        let matches = search_by_projection(&frame, &na::Isometry3::identity(), &mappoints, &intrinsics, 1.2, &config).unwrap();
+        assert_eq!(matches.len(), 11);
+        assert!(matches.iter().all(|m| m.keypoint_idx != 11));
+
+        config.check_orientation = false;
+        let matches = search_by_projection(&frame, &na::Isometry3::identity(), &mappoints, &intrinsics, 1.2, &config).unwrap();
+        assert_eq!(matches.len(), 12);
+    }
+
+    #[test]
+    fn test_rotation_consistency() {
+        // 30 bins of 12 degrees: 10 in bin 0 (365 wraps to 5), 6 in bin 8, 4 in bin 15
+        // (-170 wraps to 190) and 2 in bin 20, which is not among the top three
+        let mut rotations = vec![5.0; 9];
+        rotations.push(365.0);
+        rotations.extend([100.0; 6]);
+        rotations.extend([-170.0; 4]);
+        rotations.extend([250.0; 2]);
+        let keep = rotation_consistency(&rotations, 30);
+        assert_eq!(keep.iter().filter(|keep| **keep).count(), 20);
+        assert!(!keep[20] && !keep[21]);
+
+        // the third bin holds less than a tenth of the first
+        let mut rotations = vec![5.0; 30];
+        rotations.extend([100.0; 4]);
+        rotations.extend([200.0; 2]);
+        let keep = rotation_consistency(&rotations, 30);
+        assert_eq!(keep, [vec![true; 34], vec![false; 2]].concat());
+
+        // 359.9 and 0 fall in the last and first bin, both kept
+        assert_eq!(rotation_consistency(&[359.9, 0.0, 11.9], 30), vec![true; 3]);
+        assert!(rotation_consistency(&[], 30).is_empty());
+    }
+}
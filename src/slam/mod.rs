@@ -1,9 +1,22 @@
 pub mod load_data;
 pub mod process_image;
 pub mod camera;
+pub mod config;
 pub mod recover_pose;
 pub mod cv_convert;
+pub mod debug;
+pub mod extractor;
+pub mod klt;
+pub mod lie;
+pub mod mapping;
+pub mod matcher;
+pub mod motion_model;
 pub mod optimize;
+pub mod output;
+pub mod ransac;
+pub mod solvers;
+pub mod triangulation;
+pub mod visualizer_client;
 
 pub mod map;
 pub mod init;
@@ -0,0 +1,89 @@
+use std::time;
+
+use nalgebra as na;
+
+use super::lie;
+
+/// Constant velocity motion prior on world to camera poses.
+///
+/// The velocity is the relative motion between the last two poses,
+/// `T_{c_k, c_{k-1}} = T_{c_k, w} * T_{c_{k-1}, w}^{-1}`, and is applied once more on top
+/// of the last pose. It is rescaled by the ratio of frame intervals, so dropped frames
+/// still get a sensible prediction, and by `decay`, which damps the prediction towards
+/// the last pose (1.0 is a pure constant velocity model).
+pub struct MotionModel {
+    decay: f64,
+    last: Option<(time::Duration, na::Isometry3<f64>)>,
+    velocity: Option<(na::Isometry3<f64>, f64)>,
+}
+
+impl MotionModel {
+    pub fn new(decay: f64) -> Self {
+        Self {
+            decay,
+            last: None,
+            velocity: None,
+        }
+    }
+
+    pub fn update(&mut self, timestamp: time::Duration, pose: na::Isometry3<f64>) {
+        if let Some((last_timestamp, last_pose)) = self.last {
+            let dt = timestamp.saturating_sub(last_timestamp).as_secs_f64();
+            self.velocity = Some((pose * last_pose.inverse(), dt));
+        }
+        self.last = Some((timestamp, pose));
+    }
+
+    /// Predicted pose at `timestamp`, `None` until two poses have been seen.
+    pub fn predict(&self, timestamp: time::Duration) -> Option<na::Isometry3<f64>> {
+        let (last_timestamp, last_pose) = self.last?;
+        let (velocity, dt) = self.velocity?;
+
+        let elapsed = timestamp.saturating_sub(last_timestamp).as_secs_f64();
+        let ratio = if dt > 0.0 && elapsed > 0.0 { elapsed / dt } else { 1.0 };
+        Some(lie::se3_scale(&velocity, ratio * self.decay) * last_pose)
+    }
+
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.velocity = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_velocity() {
+        let step = na::Isometry3::<f64>::new(na::Vector3::new(0.1, 0.0, 0.05), na::Vector3::new(0.0, 0.02, 0.0));
+        let mut model = MotionModel::new(1.0);
+        let mut pose = na::Isometry3::<f64>::identity();
+        assert!(model.predict(time::Duration::from_millis(0)).is_none());
+
+        for i in 0..3 {
+            model.update(time::Duration::from_millis(50 * i), pose);
+            pose = step * pose;
+        }
+        let predicted = model.predict(time::Duration::from_millis(150)).unwrap();
+        assert!((predicted.to_matrix() - pose.to_matrix()).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_dropped_frame() {
+        let step = na::Isometry3::<f64>::translation(0.1, 0.0, 0.05);
+        let mut model = MotionModel::new(1.0);
+        model.update(time::Duration::from_millis(0), na::Isometry3::identity());
+        model.update(time::Duration::from_millis(50), step);
+
+        // twice the interval, twice the motion
+        let predicted = model.predict(time::Duration::from_millis(150)).unwrap();
+        assert!((predicted.to_matrix() - (step * step * step).to_matrix()).norm() < 1e-9);
+
+        let mut damped = MotionModel::new(0.5);
+        damped.update(time::Duration::from_millis(0), na::Isometry3::identity());
+        damped.update(time::Duration::from_millis(50), step);
+        let predicted = damped.predict(time::Duration::from_millis(100)).unwrap();
+        assert!((predicted.translation.vector - na::Vector3::new(0.15, 0.0, 0.075)).norm() < 1e-9);
+    }
+}
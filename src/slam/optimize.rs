@@ -8,98 +8,60 @@ use nalgebra as na;
 use lm::*;
 use opencv::prelude::KeyPointTraitConst;
 
+use super::config::OptimizeConfig;
+use super::lie;
 use super::map::{ Map, mappoint::*, keyframe::* };
 
-/// Converts a 6-Vector Lie Algebra representation of a rigid body
-/// transform to an NAlgebra Isometry (quaternion+translation pair)
-///
-/// This is largely taken from this paper:
-/// https://ingmec.ual.es/~jlblanco/papers/jlblanco2010geometry3D_techrep.pdf
-fn exp_map(param_vector: &na::Vector6<f64>) -> na::Isometry3<f64> {
-    let t = param_vector.fixed_view::<3, 1>(0, 0);
-    let omega = param_vector.fixed_view::<3, 1>(3, 0);
-    let theta = omega.norm();
-    let half_theta = 0.5 * theta;
-    let quat_axis = omega * half_theta.sin() / theta;
-    let quat = if theta > 1e-6 {
-        na::UnitQuaternion::from_quaternion(na::Quaternion::new(
-            half_theta.cos(),
-            quat_axis.x,
-            quat_axis.y,
-            quat_axis.z,
-        ))
-    } else {
-        na::UnitQuaternion::identity()
-    };
-
-    let mut v = na::Matrix3::<f64>::identity();
-    if theta > 1e-6 {
-        let ssym_omega = skew_sym(omega.clone_owned());
-        v += ssym_omega * (1.0 - theta.cos()) / (theta.powi(2))
-            + (ssym_omega * ssym_omega) * ((theta - theta.sin()) / (theta.powi(3)));
-    }
+/// 95% quantile of the chi-square distribution with 2 degrees of freedom. Observations
+/// whose squared reprojection error, in units of their sigma, exceeds it are outliers.
+const CHI2_2DOF: f64 = 5.991;
 
-    let trans = na::Translation::from(v * t);
-
-    na::Isometry3::from_parts(trans, quat)
+/// Standard deviation of a keypoint detected at pyramid `octave`: it is located to
+/// `pixel_sigma` in that level, which is downscaled by `scale_factor^octave`.
+fn level_sigma(config: &OptimizeConfig, scale_factor: f32, octave: i32) -> f64 {
+    config.pixel_sigma * (scale_factor as f64).powi(octave.max(0))
 }
 
-
-/// Produces a skew-symmetric or "cross-product matrix" from
-/// a 3-vector. This is needed for the `exp_map` and `log_map`
-/// functions
-fn skew_sym(v: na::Vector3<f64>) -> na::Matrix3<f64> {
-    let mut ss = na::Matrix3::zeros();
-    ss[(0, 1)] = -v[2];
-    ss[(0, 2)] = v[1];
-    ss[(1, 0)] = v[2];
-    ss[(1, 2)] = -v[0];
-    ss[(2, 0)] = -v[1];
-    ss[(2, 1)] = v[0];
-    ss
+/// Distance between the camera centers of two keyframes.
+fn baseline(map: &Map, id1: KeyFrameId, id2: KeyFrameId) -> Option<f64> {
+    let c1 = map.keyframe(id1)?.pose.inverse().translation.vector;
+    let c2 = map.keyframe(id2)?.pose.inverse().translation.vector;
+    Some((c1 - c2).norm())
 }
 
-/// Converts an NAlgebra Isometry to a 6-Vector Lie Algebra representation
-/// of a rigid body transform.
+/// Bundle adjusts the given keyframes and map points.
 ///
-/// This is largely taken from this paper:
-/// https://ingmec.ual.es/~jlblanco/papers/jlblanco2010geometry3D_techrep.pdf
-fn log_map(input: &na::Isometry3<f64>) -> na::DVector<f64> {
-    let t: na::Vector3<f64> = input.translation.vector;
-
-    let quat = input.rotation;
-    let theta: f64 = 2.0 * (quat.scalar()).acos();
-    let half_theta = 0.5 * theta;
-    let mut omega = na::Vector3::<f64>::zeros();
-
-    let mut v_inv = na::Matrix3::<f64>::identity();
-    if theta > 1e-6 {
-        omega = quat.vector() * theta / (half_theta.sin());
-        let ssym_omega = skew_sym(omega);
-        v_inv -= ssym_omega * 0.5;
-        v_inv += ssym_omega * ssym_omega * (1.0 - half_theta * half_theta.cos() / half_theta.sin())
-            / (theta * theta);
-    }
-
-    let mut ret = na::DVector::<f64>::zeros(6);
-    ret.fixed_view_mut::<3, 1>(0, 0).copy_from(&(v_inv * t));
-    ret.fixed_view_mut::<3, 1>(3, 0).copy_from(&omega);
-
-    ret
-}
+/// A monocular reconstruction is only defined up to a similarity, so the gauge is fixed
+/// explicitly: the oldest keyframe is held constant (6 DoF), and since the reprojection
+/// error does not constrain the scale, the solution is rescaled about the oldest keyframe
+/// afterwards so its baseline to the second oldest keyframe is the same as before (1 DoF).
+///
+/// Observations are weighted by the sigma of their pyramid level, and those that are
+/// still outliers after the optimization are dropped, see `remove_outliers`.
+pub fn optimize(
+    map: &mut Map,
+    keyframes: &[KeyFrameId],
+    mappoints: &[MapPointId],
+    config: &OptimizeConfig,
+    scale_factor: f32,
+) {
+    let mut sorted = keyframes.to_vec();
+    sorted.sort();
+    let gauge = match sorted.as_slice() {
+        [first, second, ..] => baseline(map, *first, *second).map(|b| (*first, *second, b)),
+        _ => None,
+    };
 
-pub fn optimize(map: &mut Map, keyframes: &[KeyFrameId], mappoints: &[MapPointId])
-{
     let mut graph = Graph::default();
     let mut id = 0;
-    let camera_vertices = keyframes.iter().enumerate().map(|(idx, id_kf)| {
+    let camera_vertices = keyframes.iter().map(|id_kf| {
         let pose = map.keyframe(*id_kf).expect("keyframe id not correct!").pose;
         
         let ret = Rc::new(RefCell::new(CameraVertex {
             id,
-            params: log_map(&pose),
+            params: na::DVector::from_column_slice(lie::se3_log(&pose).as_slice()),
             edges: Vec::new(),
-            fixed: idx == 0,
+            fixed: Some(id_kf) == sorted.first(),
             hessian_index: 0,
         })) as VertexBase;
         id += 1;
@@ -123,11 +85,14 @@ pub fn optimize(map: &mut Map, keyframes: &[KeyFrameId], mappoints: &[MapPointId
         for mp_reference in mp.references.iter() {
             if let Some(idx_kf) = keyframes.iter().position(|x| *x == mp_reference.id) {
                 let obs = mp_reference.keypoint.pt();
+                let sigma = level_sigma(config, scale_factor, mp_reference.keypoint.octave());
                 if let Some(keyframe) = map.keyframe(mp_reference.id) {
                     let edge = Rc::new(RefCell::new( Point3dProjectWithIntrinsicEdge {
                         id: id_edge,
                         vertices: Vec::new(),
-                        sigma: na::DMatrix::<f64>::identity(2, 2),
+                        // lm weighs the residual as `r^T * sigma * r`, i.e. `sigma` is the
+                        // information matrix, the inverse of the pixel covariance
+                        sigma: na::DMatrix::<f64>::identity(2, 2) / sigma.powi(2),
                         measurement: na::dvector![obs.x as f64, obs.y as f64],
                         intrinsic: keyframe.intrinsics.vector(),
                     }
@@ -154,7 +119,7 @@ pub fn optimize(map: &mut Map, keyframes: &[KeyFrameId], mappoints: &[MapPointId
         let kf = map.keyframe_mut(*id_kf).expect("keyframe id not correct!");
         let camera_vertex = graph.vertex(idx).expect("camera vertex id not correct!");
         let camera_vertex = camera_vertex.borrow();
-        let pose = exp_map(&camera_vertex.params().fixed_view::<6, 1>(0, 0).clone_owned());
+        let pose = lie::se3_exp(&camera_vertex.params().fixed_view::<6, 1>(0, 0).clone_owned());
         kf.pose = pose;
     }
 
@@ -166,4 +131,64 @@ pub fn optimize(map: &mut Map, keyframes: &[KeyFrameId], mappoints: &[MapPointId
         let point_vertex = point_vertex.borrow();
         mp.position = na::Vector3::<f64>::new(point_vertex.params()[0], point_vertex.params()[1], point_vertex.params()[2]);
     }
-}
\ No newline at end of file
+
+    // restore the scale of the gauge baseline
+    if let Some((first, second, before)) = gauge {
+        match baseline(map, first, second) {
+            Some(after) if after > f64::EPSILON => {
+                map.rescale(first, before / after, keyframes, mappoints);
+            },
+            _ => println!("bundle adjustment collapsed the baseline, scale not restored"),
+        }
+    }
+
+    let removed = remove_outliers(map, keyframes, mappoints, config, scale_factor);
+    if removed > 0 {
+        println!("bundle adjustment dropped {} outlier observations", removed);
+    }
+}
+
+/// Drops the observations of `mappoints` by `keyframes` whose reprojection error fails
+/// the chi-square test at the sigma of their pyramid level, and the map points left with
+/// fewer than two observations. Returns the number of observations dropped.
+pub fn remove_outliers(
+    map: &mut Map,
+    keyframes: &[KeyFrameId],
+    mappoints: &[MapPointId],
+    config: &OptimizeConfig,
+    scale_factor: f32,
+) -> usize {
+    let mut outliers = Vec::new();
+    for id_mp in mappoints {
+        let mp = match map.mappoint(*id_mp) {
+            Some(mp) => mp,
+            None => continue,
+        };
+        let mp = mp.borrow();
+        for reference in mp.references.iter().filter(|r| keyframes.contains(&r.id)) {
+            let kf = match map.keyframe(reference.id) {
+                Some(kf) => kf,
+                None => continue,
+            };
+            let p_c = kf.pose * na::Point3::from(mp.position);
+            let projected = kf.intrinsics.projection(&p_c.coords);
+            let pt = reference.keypoint.pt();
+            let error = projected - na::Point2::new(pt.x as f64, pt.y as f64);
+            let sigma = level_sigma(config, scale_factor, reference.keypoint.octave());
+            if p_c.z <= 0.0 || error.norm_squared() / sigma.powi(2) > CHI2_2DOF {
+                outliers.push((reference.id, *id_mp));
+            }
+        }
+    }
+
+    for (id_kf, id_mp) in outliers.iter() {
+        map.erase_observation(*id_kf, *id_mp);
+    }
+    for id_mp in mappoints {
+        let observed = map.mappoint(*id_mp).map(|mp| mp.borrow().references.len());
+        if observed.is_some_and(|count| count < 2) {
+            map.remove_mappoint(*id_mp);
+        }
+    }
+    outliers.len()
+}
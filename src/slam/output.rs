@@ -0,0 +1,150 @@
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time;
+
+use nalgebra as na;
+use opencv::prelude::KeyPointTraitConst;
+
+use super::config::OutputConfig;
+use super::map::Map;
+
+/// Conversion from map units to output units, see `OutputConfig::world_scale`.
+/// Every pose and point leaving the pipeline goes through it.
+#[derive(Clone, Copy, Debug)]
+pub struct WorldScale(pub f64);
+
+impl WorldScale {
+    pub fn new(config: &OutputConfig) -> Self {
+        Self(config.world_scale)
+    }
+
+    /// Scales the translation of a world to camera pose.
+    pub fn pose(&self, pose: &na::Isometry3<f64>) -> na::Isometry3<f64> {
+        na::Isometry3::from_parts(na::Translation3::from(pose.translation.vector * self.0), pose.rotation)
+    }
+
+    pub fn point(&self, point: &na::Vector3<f64>) -> na::Vector3<f64> {
+        point * self.0
+    }
+}
+
+/// Writes a trajectory in TUM format: `timestamp tx ty tz qx qy qz qw`.
+///
+/// Tracker poses are world-to-camera, TUM expects camera-to-world,
+/// so every pose is inverted before it is written.
+pub fn save_trajectory_tum(
+    path: &Path,
+    trajectory: &[(time::Duration, na::Isometry3<f64>)],
+    scale: WorldScale,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::File::create(path)?;
+    for (timestamp, pose) in trajectory {
+        let t_wc = scale.pose(pose).inverse();
+        writeln!(
+            file,
+            "{:.9} {} {} {} {} {} {} {}",
+            timestamp.as_secs_f64(),
+            t_wc.translation.x, t_wc.translation.y, t_wc.translation.z,
+            t_wc.rotation.i, t_wc.rotation.j, t_wc.rotation.k, t_wc.rotation.w,
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes map points, keyframe poses and the keypoints each keyframe observes as json.
+pub fn save_map_json(map: &Map, path: &Path, scale: WorldScale) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::File::create(path)?;
+    let points = map.points();
+    let keyframes = &map.keyframes;
+
+    let mut json = String::new();
+    json.push_str("{\n");
+    json.push_str("\t\"points\": [\n");
+    let points_json = points.iter()
+        .map(|point| {
+            let position = scale.point(&point.as_ref().borrow().position);
+            format!("\t\t[{},{},{}]", position.x, position.y, position.z)
+        })
+        .collect::<Vec<_>>();
+    json.push_str(&points_json.join(",\n"));
+    json.push_str("\n\t],\n");
+    json.push_str("\t\"keyframes\": [\n");
+    let keyframes_json = keyframes.values()
+        .map(|kf| {
+            let mut kf_json = String::new();
+            kf_json.push_str("\t\t{\n");
+            kf_json.push_str(&format!("\t\t\t\"id\": {},\n", kf.id));
+            let pose = scale.pose(&kf.pose);
+            kf_json.push_str(&format!("\t\t\t\"pose\":{{\"x\": {}, \"y\": {}, \"z\": {}, \"q\": [{}, {}, {}, {}]}},\n", pose.translation.x, pose.translation.y, pose.translation.z, pose.rotation.i, pose.rotation.j, pose.rotation.k, pose.rotation.w));
+
+            let mut observed = Vec::new();
+            for point in points.iter() {
+                let point = point.as_ref().borrow();
+                if let Some(reference) = point.reference(kf.id) {
+                    observed.push(format!("[{},{}]", reference.keypoint.pt().x, reference.keypoint.pt().y));
+                }
+            }
+            kf_json.push_str(&format!("\t\t\t\"points\": [{}]\n", observed.join(",")));
+            kf_json.push_str("\t\t}");
+            kf_json
+        })
+        .collect::<Vec<_>>();
+    json.push_str(&keyframes_json.join(",\n"));
+    json.push_str("\n\t]\n");
+    json.push_str("}\n");
+    file.write_all(json.as_bytes())?;
+
+    Ok(())
+}
+
+/// Per-frame processing time of a run.
+#[derive(Default)]
+pub struct TimingStats {
+    pub frames: Vec<(time::Duration, time::Duration)>,
+}
+
+impl TimingStats {
+    pub fn new() -> Self {
+        Self { frames: Vec::new() }
+    }
+
+    pub fn push(&mut self, timestamp: time::Duration, elapsed: time::Duration) {
+        self.frames.push((timestamp, elapsed));
+    }
+
+    pub fn total(&self) -> time::Duration {
+        self.frames.iter().map(|(_, elapsed)| *elapsed).sum()
+    }
+
+    pub fn summary(&self) -> String {
+        if self.frames.is_empty() {
+            return "frames: 0".to_string();
+        }
+        let mut elapsed = self.frames.iter().map(|(_, e)| e.as_secs_f64()).collect::<Vec<_>>();
+        elapsed.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let total = self.total().as_secs_f64();
+        format!(
+            "frames: {}\ntotal: {:.6} s\nmean: {:.6} s\nmedian: {:.6} s\nmin: {:.6} s\nmax: {:.6} s\nfps: {:.3}",
+            elapsed.len(),
+            total,
+            total / elapsed.len() as f64,
+            elapsed[elapsed.len() / 2],
+            elapsed[0],
+            elapsed[elapsed.len() - 1],
+            elapsed.len() as f64 / total,
+        )
+    }
+
+    /// Writes the summary followed by one `timestamp elapsed` line per frame.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "{}", self.summary())?;
+        writeln!(file, "# timestamp[s] elapsed[s]")?;
+        for (timestamp, elapsed) in self.frames.iter() {
+            writeln!(file, "{:.9} {:.6}", timestamp.as_secs_f64(), elapsed.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
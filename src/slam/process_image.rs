@@ -1,24 +1,37 @@
-use std::error::Error;
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+};
 use opencv::{
     prelude::*,
     core,
     features2d,
     imgcodecs,
     imgproc,
-    calib3d,
 };
 use nalgebra as na;
 
 use super::load_data;
 use super::camera;
-use super::map;
+use super::config::{SlamConfig, TrackingMode};
+use super::debug::{self, DebugOutput, DebugStage};
+use super::extractor::{self, DescriptorKind, FeatureExtractor};
+use super::klt::KltTracker;
+use super::matcher;
+use super::motion_model::MotionModel;
+use super::ransac::RansacParams;
+use super::recover_pose;
+use super::map::{self, keyframe::KeyFrameId, mappoint::MapPoint};
+use super::mapping;
+use super::optimize;
 use super::frame::Frame;
 use super::init;
+use crate::protocol::KeypointStatus;
 
-const GX: usize = 15;
-const GY: usize = 10;
-const FEATURE_NUM: usize = GX * GY * 6;
-
+/// Probability of drawing at least one outlier free PnP sample.
+const PNP_CONFIDENCE: f64 = 0.99;
 
 pub struct Tracker {
     pub initializer: init::Init,
@@ -26,29 +39,43 @@ pub struct Tracker {
     pub last_frame: Frame,
     pub curr_frame: Frame,
     pub camera: camera::CameraIntrinsics,
-    pub orb_detector: core::Ptr<features2d::ORB>,
+    pub config: SlamConfig,
+    pub extractor: Box<dyn FeatureExtractor>,
+    /// frame-to-frame tracker, only present in `TrackingMode::Klt`
+    pub klt: Option<KltTracker>,
     pub bf_matcher: core::Ptr<features2d::BFMatcher>,
+    pub motion_model: MotionModel,
+    pub debug: DebugOutput,
     pub map: map::Map,
+    /// frames tracked since the latest keyframe was inserted
+    pub frames_since_keyframe: usize,
+    /// map point followed by each KLT track id of the last tracked frame
+    pub track_mappoints: HashMap<i32, Rc<RefCell<MapPoint>>>,
+}
+
+/// A frame tracked against the map.
+struct Tracked {
+    pose: na::Isometry3<f64>,
+    status: Vec<KeypointStatus>,
+    /// keypoint index and map point of every PnP inlier
+    inliers: Vec<(usize, Rc<RefCell<MapPoint>>)>,
 }
 
 impl Tracker {
     pub fn new(
         camera: camera::CameraIntrinsics,
+        config: SlamConfig,
     ) -> Result<Self, Box<dyn Error>> {
-        let orb_detector = features2d::ORB::create(
-            FEATURE_NUM as i32,
-            1.2,
-            8,
-            31,
-            0,
-            2,
-            features2d::ORB_ScoreType::HARRIS_SCORE, 
-            31,
-            20,
-        ).unwrap();
+        let extractor = extractor::create(&config.feature)?;
+        let motion_model = MotionModel::new(config.tracking.motion_decay);
+        let debug = DebugOutput::from_config(&config.debug, None);
+        let klt = match config.tracking.mode {
+            TrackingMode::Klt => Some(KltTracker::new(&config)?),
+            TrackingMode::Descriptor => None,
+        };
 
         // 创建 BFMatcher
-        let bf_matcher = match features2d::BFMatcher::create(core::NORM_HAMMING, false) {
+        let bf_matcher = match features2d::BFMatcher::create(extractor.descriptor_kind().norm_type(), false) {
             Ok(bf_matcher) => bf_matcher,
             Err(e) => {
                 println!("Create BFMatcher failed: {}", e);
@@ -57,17 +84,30 @@ impl Tracker {
         };
 
         Ok(Self {
-            initializer: init::Init::new(&camera),
+            initializer: init::Init::new(&camera, &config, debug.clone()),
             pose: na::Isometry3::identity(),
             last_frame: Frame::default(),
             curr_frame: Frame::default(),
             camera,
-            orb_detector,
+            config,
+            extractor,
+            klt,
             bf_matcher,
+            motion_model,
+            debug,
             map: map::Map::new(),
+            frames_since_keyframe: 0,
+            track_mappoints: HashMap::new(),
         })
     }
 
+    /// Replaces the debug output of the tracker and its initializer, e.g. with one
+    /// that has a channel for the `visualizer` sink.
+    pub fn set_debug_output(&mut self, debug: DebugOutput) {
+        self.initializer.debug = debug.clone();
+        self.debug = debug;
+    }
+
     pub fn track(
         &mut self, 
         data: load_data::EurocData
@@ -80,54 +120,310 @@ impl Tracker {
                 return Err(Box::new(e));
             },
         };
-        let mut corners = core::Vector::<core::Point2f>::default();
-        imgproc::good_features_to_track(
-            &img, 
-            &mut corners, 
-            1000, 
-            0.01, 
-            10.0, 
-            &opencv::core::Mat::default(), 
-            3, 
-            false, 
-            0.04).unwrap(); 
-        
-
-        let mut orb_keypoints: core::Vector<core::KeyPoint> = corners.into_iter()
-            .map(|f|  
-                core::KeyPoint::new_point(
-                                    f,
-                                    1.0, 
-                                    -1.0, 
-                                    0.0, 
-                                    0, 
-                                    -1,).unwrap()
-            ).collect();
-        let mut orb_desc = core::Mat::default();
-        if let Err(e) = self.orb_detector.compute(&img, &mut orb_keypoints, &mut orb_desc) {
-            println!("Detect and compute failed: {}", e);
-            return Err(Box::new(e));
-        }
+        // KLT always describes its tracks with ORB
+        let (features, descriptor_kind) = match self.klt.as_mut() {
+            Some(klt) => (klt.track(&img), DescriptorKind::Binary),
+            None => (self.extractor.extract(&img), self.extractor.descriptor_kind()),
+        };
+        let (orb_keypoints, orb_desc) = match features {
+            Ok(features) => features,
+            Err(e) => {
+                println!("Detect and compute failed: {}", e);
+                return Err(e);
+            },
+        };
 
         let inframe = Frame::new(
             data.timestamp,
             img,
             orb_keypoints,
             orb_desc,
+            descriptor_kind,
             na::Isometry3::identity(),
         );
 
         if !self.initializer.done() {
             println!("initlializing...");
+            self.keep_frame(inframe.clone());
             if self.initializer.run(inframe) {
                 self.map = self.initializer.map.clone();
+                self.seed_from_keyframes();
+                self.curr_frame.pose = self.pose;
+                self.frames_since_keyframe = 0;
+                self.track_mappoints = self.map.latest_keyframe()
+                    .map(|kf| keyframe_tracks(&self.map, kf.id))
+                    .unwrap_or_default();
                 println!("map size: {}", self.map.mappoints.len());
+                return Ok(self.pose);
             }
             return Ok(na::Isometry3::identity());
         }
 
+        let mut frame = inframe;
+        let prior = match self.motion_model.predict(frame.timestamp) {
+            Some(prediction) if self.config.tracking.use_motion_model => prediction,
+            _ => self.pose,
+        };
+        let by_track_id = match self.klt {
+            Some(_) => Some(self.track_by_track_id(&frame)),
+            None => None,
+        };
+        let tracked = match by_track_id {
+            Some(Ok(tracked)) => Ok(tracked),
+            Some(Err(e)) => {
+                println!("tracking by KLT track id failed: {}, trying projection", e);
+                self.track_with_prior(&frame, &prior)
+            },
+            None => self.track_with_prior(&frame, &prior),
+        };
+        let tracked = match tracked {
+            Ok(tracked) => Ok(tracked),
+            Err(e) => {
+                println!("tracking with motion prior failed: {}, trying reference keyframe", e);
+                self.track_reference_keyframe(&frame)
+            },
+        };
+        let tracked = match tracked {
+            Ok(tracked) => tracked,
+            Err(e) => {
+                // the velocity is meaningless across a tracking loss
+                self.motion_model.reset();
+                self.keep_frame(frame);
+                return Err(e);
+            },
+        };
+        println!("tracked {} map points", tracked.inliers.len());
+        let pose = tracked.pose;
+        frame.pose = pose;
+        frame.status = tracked.status;
+        self.pose = pose;
+        self.motion_model.update(frame.timestamp, pose);
+        if self.klt.is_some() {
+            self.track_mappoints = tracked.inliers.iter()
+                .map(|(idx, mp)| Ok((frame.keypoints.get(*idx)?.class_id(), mp.clone())))
+                .collect::<Result<HashMap<_, _>, opencv::Error>>()?;
+        }
+
+        self.frames_since_keyframe += 1;
+        if mapping::need_keyframe(&self.map, tracked.inliers.len(), self.frames_since_keyframe, &self.config.mapping) {
+            match mapping::insert_keyframe(&mut self.map, &frame, &tracked.inliers, &self.camera, &self.config) {
+                Ok((id, created)) => {
+                    println!("inserted keyframe {} with {} new map points", id, created);
+                    self.frames_since_keyframe = 0;
+                    if self.klt.is_some() {
+                        // the new points are followed by tracks from now on as well
+                        self.track_mappoints = keyframe_tracks(&self.map, id);
+                    }
+                },
+                Err(e) => println!("keyframe insertion failed: {}", e),
+            }
+        }
+        self.keep_frame(frame);
+
         Ok(self.pose)
     }
+
+    /// Makes `frame` the current one, so it is shown whether or not it was tracked.
+    /// Keypoints of a frame that was not tracked are all `New`.
+    fn keep_frame(&mut self, mut frame: Frame) {
+        if frame.status.is_empty() {
+            frame.status = vec![KeypointStatus::New; frame.keypoints.len()];
+        }
+        self.last_frame = std::mem::replace(&mut self.curr_frame, frame);
+    }
+
+    /// Tracks `data` like `track`, and bundle adjusts the initial map right after the
+    /// initialization succeeded, returning the adjusted pose in that case.
+    pub fn track_and_optimize(
+        &mut self,
+        data: load_data::EurocData,
+    ) -> Result<na::Isometry3<f64>, Box<dyn Error>> {
+        let initialized = self.initializer.done();
+        let pose = self.track(data)?;
+        if !initialized && self.initializer.done() {
+            return Ok(self.optimize_map());
+        }
+        Ok(pose)
+    }
+
+    /// Bundle adjusts all keyframes and map points, then continues tracking from the
+    /// adjusted poses. Returns the adjusted pose of the newest keyframe.
+    pub fn optimize_map(&mut self) -> na::Isometry3<f64> {
+        let keyframes = self.map.keyframes.keys().cloned().collect::<Vec<_>>();
+        let mappoints = self.map.mappoints.keys().cloned().collect::<Vec<_>>();
+        optimize::optimize(&mut self.map, &keyframes, &mappoints, &self.config.optimize, self.config.feature.scale_factor);
+        self.seed_from_keyframes();
+        self.pose
+    }
+
+    /// Restarts the motion model from the keyframe poses and sets the current pose to
+    /// that of the newest keyframe.
+    fn seed_from_keyframes(&mut self) {
+        self.motion_model.reset();
+        let mut keyframes = self.map.keyframes.values().collect::<Vec<_>>();
+        keyframes.sort_by_key(|kf| kf.id);
+        for kf in keyframes {
+            self.motion_model.update(kf.timestamp, kf.pose);
+            self.pose = kf.pose;
+        }
+    }
+
+    /// Fallback when tracking with the motion prior fails: matches the descriptors of the
+    /// map points observed by the latest keyframe and solves PnP.
+    fn track_reference_keyframe(
+        &self,
+        frame: &Frame,
+    ) -> Result<Tracked, Box<dyn Error>> {
+        let keyframe = self.map.latest_keyframe().ok_or("map has no keyframe")?;
+        let mappoints = keyframe.observations.clone();
+        if mappoints.is_empty() {
+            return Err("reference keyframe observes no map point".into());
+        }
+
+        let mut train = core::Vector::<core::Mat>::default();
+        for mp in mappoints.iter() {
+            train.push(mp.borrow().desctriptor.try_clone()?);
+        }
+        let mut train_descriptors = core::Mat::default();
+        core::vconcat(&train, &mut train_descriptors)?;
+
+        let mut bf_matcher = features2d::BFMatcher::create(frame.descriptor_kind.norm_type(), false)?;
+        bf_matcher.add(&train_descriptors)?;
+        let mut knn_matches = core::Vector::<core::Vector<core::DMatch>>::default();
+        bf_matcher.knn_match(&frame.descriptors, &mut knn_matches, 2, &core::Mat::default(), false)?;
+
+        let max_distance = frame.descriptor_kind.max_distance(&self.config.matcher);
+        let mut matched = Vec::new();
+        for candidates in knn_matches.iter() {
+            let best = match candidates.get(0) {
+                Ok(best) => best,
+                Err(_) => continue,
+            };
+            let passes_ratio = match candidates.get(1) {
+                Ok(second) => (best.distance as f64) < self.config.matcher.nn_ratio * second.distance as f64,
+                Err(_) => true,
+            };
+            if best.distance > max_distance || !passes_ratio {
+                continue;
+            }
+            matched.push((best.query_idx as usize, mappoints[best.train_idx as usize].clone()));
+        }
+
+        if matched.len() < self.config.tracking.min_inliers {
+            return Err(format!("too few reference keyframe matches: {}", matched.len()).into());
+        }
+        self.solve_pnp(frame, &matched)
+    }
+
+    /// Associates the KLT tracks of `frame` with the map points they followed in the last
+    /// tracked frame and solves the pose with PnP, without any descriptor matching.
+    fn track_by_track_id(
+        &self,
+        frame: &Frame,
+    ) -> Result<Tracked, Box<dyn Error>> {
+        let mut matched = Vec::new();
+        for (idx, keypoint) in frame.keypoints.iter().enumerate() {
+            if let Some(mappoint) = self.track_mappoints.get(&keypoint.class_id()) {
+                matched.push((idx, mappoint.clone()));
+            }
+        }
+        if matched.len() < self.config.tracking.min_inliers {
+            return Err(format!("too few tracks following map points: {}", matched.len()).into());
+        }
+        self.solve_pnp(frame, &matched)
+    }
+
+    /// Matches the map points into `frame` by projection from `prior` and solves
+    /// the pose with PnP.
+    fn track_with_prior(
+        &self,
+        frame: &Frame,
+        prior: &na::Isometry3<f64>,
+    ) -> Result<Tracked, Box<dyn Error>> {
+        let matches = matcher::search_by_projection(
+            frame,
+            prior,
+            &self.map.points(),
+            &self.camera,
+            self.config.feature.scale_factor,
+            &self.config.matcher,
+        )?;
+        if matches.len() < self.config.tracking.min_inliers {
+            return Err(format!("too few projection matches: {}", matches.len()).into());
+        }
+
+        let matched = matches.into_iter().map(|m| (m.keypoint_idx, m.mappoint)).collect::<Vec<_>>();
+        self.solve_pnp(frame, &matched)
+    }
+
+    /// Solves the pose of `frame` with PnP RANSAC, given its keypoints matched to
+    /// map points, and fails when fewer than `min_inliers` matches are kept.
+    fn solve_pnp(
+        &self,
+        frame: &Frame,
+        matched: &[(usize, Rc<RefCell<MapPoint>>)],
+    ) -> Result<Tracked, Box<dyn Error>> {
+        let points3d = matched.iter().map(|(_, mp)| mp.borrow().position).collect::<Vec<_>>();
+        let points2d = matched.iter()
+            .map(|(idx, _)| frame.keypoints.get(*idx).map(|kp| kp.pt()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let tracking = &self.config.tracking;
+        let params = RansacParams {
+            max_iterations: tracking.pnp_iterations as usize,
+            ..RansacParams::new(tracking.pnp_reprojection_error as f64, PNP_CONFIDENCE, &self.config.ransac)
+        };
+        let (pose, inliers) = recover_pose::from_pnp(&points3d, &points2d, &self.camera, &params)?;
+        if inliers.len() < self.config.tracking.min_inliers {
+            return Err(format!("too few PnP inliers: {}", inliers.len()).into());
+        }
+        self.debug.emit(DebugStage::Reprojections, frame.timestamp, || {
+            let observed = inliers.iter().map(|&i| points2d[i]).collect::<Vec<_>>();
+            let projected = inliers.iter()
+                .map(|&i| {
+                    let p = self.camera.projection(&(pose * na::Point3::from(points3d[i])).coords);
+                    core::Point2f::new(p.x as f32, p.y as f32)
+                })
+                .collect::<Vec<_>>();
+            debug::draw_reprojections(&frame.img, &observed, &projected)
+        });
+
+        Ok(tracking_result(pose, frame.keypoints.len(), matched, &inliers))
+    }
+}
+
+/// The tracking result for a frame with `count` keypoints, given the keypoints matched
+/// to map points and the indices into `matched` that PnP kept.
+fn tracking_result(
+    pose: na::Isometry3<f64>,
+    count: usize,
+    matched: &[(usize, Rc<RefCell<MapPoint>>)],
+    inliers: &[usize],
+) -> Tracked {
+    let mut status = vec![KeypointStatus::New; count];
+    for (i, _) in matched.iter() {
+        status[*i] = KeypointStatus::Outlier;
+    }
+    let inliers = inliers.iter()
+        .map(|&i| {
+            status[matched[i].0] = KeypointStatus::Matched;
+            matched[i].clone()
+        })
+        .collect();
+    Tracked { pose, status, inliers }
+}
+
+/// The map point followed by each KLT track id among the observations of keyframe `id`.
+fn keyframe_tracks(map: &map::Map, id: KeyFrameId) -> HashMap<i32, Rc<RefCell<MapPoint>>> {
+    let mut tracks = HashMap::new();
+    if let Some(kf) = map.keyframe(id) {
+        for mappoint in kf.observations.iter() {
+            let track_id = mappoint.borrow().reference(id).map(|r| r.keypoint.class_id());
+            if let Some(track_id) = track_id.filter(|track_id| *track_id >= 0) {
+                tracks.insert(track_id, mappoint.clone());
+            }
+        }
+    }
+    tracks
 }
 
 
@@ -139,7 +435,7 @@ mod test {
         let path = "/home/zhang/Downloads/MH_01_easy/mav0/cam0";
         let data_set = super::super::load_data::load_euroc_data(path)?;
         let camera = super::super::camera::CameraIntrinsics::new_euroc();
-        let mut tracker = super::Tracker::new(camera).unwrap();
+        let mut tracker = super::Tracker::new(camera, super::super::config::SlamConfig::default()).unwrap();
         let mut pose = nalgebra::Isometry3::<f64>::identity();
         for data in data_set {
             if tracker.initializer.done() {
@@ -273,7 +569,7 @@ mod test {
 
         let data_set = vec![data1, data2];
         let camera = super::super::camera::CameraIntrinsics::new_euroc();
-        let mut tracker = super::Tracker::new(camera).unwrap();
+        let mut tracker = super::Tracker::new(camera, super::super::config::SlamConfig::default()).unwrap();
         for data in data_set {
             let pose = tracker.track(data).unwrap();
             println!("pose: {}", pose.to_matrix());
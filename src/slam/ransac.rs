@@ -0,0 +1,551 @@
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+use super::camera::CameraIntrinsics;
+use super::config::RansacConfig;
+use super::solvers::{eight_point, epnp, five_point, homography, p3p};
+
+/// How hypotheses are sampled and scored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RobustMethod {
+    /// uniform sampling, a hypothesis scores its number of inliers
+    Ransac,
+    /// uniform sampling, a hypothesis scores the truncated quadratic loss of all data
+    Msac,
+    /// samples progressively from the best-ranked data first, scored like MSAC
+    Prosac,
+}
+
+/// A model that can be estimated robustly from the data it holds.
+pub trait Estimator {
+    type Model: Clone;
+
+    /// Number of data points, indexed `0..num_data()`.
+    fn num_data(&self) -> usize;
+
+    /// Size of a minimal sample.
+    fn sample_size(&self) -> usize;
+
+    /// Models through the data in `sample`, which has at least `sample_size()` entries.
+    fn solve(&self, sample: &[usize]) -> Vec<Self::Model>;
+
+    /// Squared error of data point `idx` under `model`.
+    fn residual(&self, model: &Self::Model, idx: usize) -> f64;
+
+    /// Non-minimal fit to `inliers` for local optimization, the least squares `solve` by default.
+    fn refine(&self, _model: &Self::Model, inliers: &[usize]) -> Vec<Self::Model> {
+        self.solve(inliers)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RansacParams {
+    pub method: RobustMethod,
+    /// inlier threshold, in the units of the square root of `Estimator::residual`
+    pub threshold: f64,
+    pub confidence: f64,
+    pub max_iterations: usize,
+    pub local_optimization: bool,
+    pub seed: u64,
+}
+
+impl RansacParams {
+    pub fn new(threshold: f64, confidence: f64, config: &RansacConfig) -> Self {
+        Self {
+            method: config.method,
+            threshold,
+            confidence,
+            max_iterations: config.max_iterations,
+            local_optimization: config.local_optimization,
+            seed: config.seed,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RansacResult<M> {
+    pub model: M,
+    pub inliers: Vec<bool>,
+    pub num_inliers: usize,
+    pub iterations: usize,
+}
+
+/// Local optimization passes run whenever a new best hypothesis is found.
+const LO_ITERATIONS: usize = 4;
+
+/// SplitMix64, small and reproducible across platforms.
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// `k` distinct values from `0..n`.
+    pub fn sample(&mut self, n: usize, k: usize) -> Vec<usize> {
+        let mut sample = Vec::with_capacity(k);
+        while sample.len() < k {
+            let idx = self.below(n);
+            if !sample.contains(&idx) {
+                sample.push(idx);
+            }
+        }
+        sample
+    }
+}
+
+/// Iterations needed to draw one all-inlier sample with probability `confidence`.
+pub fn adaptive_iterations(inlier_ratio: f64, sample_size: usize, confidence: f64, max_iterations: usize) -> usize {
+    let all_inliers = inlier_ratio.powi(sample_size as i32);
+    if all_inliers >= 1.0 {
+        return 1;
+    }
+    if all_inliers <= f64::EPSILON {
+        return max_iterations;
+    }
+    let iterations = (1.0 - confidence).ln() / (1.0 - all_inliers).ln();
+    (iterations.ceil() as usize).clamp(1, max_iterations)
+}
+
+/// PROSAC sampling schedule (Chum and Matas 2005): samples are drawn from the `n`
+/// best-ranked data, and `n` grows at the rate at which uniform sampling from all data
+/// would have drawn its samples from the top `n`.
+struct ProsacSampler {
+    n: usize,
+    t: usize,
+    t_n: f64,
+    t_n_prime: usize,
+    len: usize,
+    m: usize,
+}
+
+impl ProsacSampler {
+    fn new(len: usize, m: usize, max_iterations: usize) -> Self {
+        let mut t_n = max_iterations as f64;
+        for i in 0..m {
+            t_n *= (m - i) as f64 / (len - i) as f64;
+        }
+        Self { n: m, t: 0, t_n, t_n_prime: 1, len, m }
+    }
+
+    /// Ranks within the ordered data.
+    fn sample(&mut self, rng: &mut SplitMix64) -> Vec<usize> {
+        self.t += 1;
+        if self.t == self.t_n_prime && self.n < self.len {
+            let t_next = self.t_n * (self.n + 1) as f64 / (self.n + 1 - self.m) as f64;
+            self.t_n_prime += (t_next - self.t_n).ceil() as usize;
+            self.t_n = t_next;
+            self.n += 1;
+        }
+        if self.t_n_prime < self.t || self.n == self.m {
+            rng.sample(self.n, self.m)
+        } else {
+            // the newest datum plus m - 1 from the ones before it
+            let mut sample = rng.sample(self.n - 1, self.m - 1);
+            sample.push(self.n - 1);
+            sample
+        }
+    }
+}
+
+/// Robustly estimates a model of `estimator`. `order` ranks the data from most to least
+/// promising (e.g. by descriptor distance) and is required by PROSAC, ignored otherwise.
+/// Returns `None` if no hypothesis has a minimal sample worth of inliers.
+pub fn estimate<E: Estimator>(
+    estimator: &E,
+    params: &RansacParams,
+    order: Option<&[usize]>,
+) -> Option<RansacResult<E::Model>> {
+    let len = estimator.num_data();
+    let m = estimator.sample_size();
+    if len < m || m == 0 {
+        return None;
+    }
+    let threshold2 = params.threshold * params.threshold;
+    let score = |model: &E::Model| -> (f64, Vec<bool>, usize) {
+        let mut score = 0.0;
+        let mut inliers = vec![false; len];
+        let mut count = 0;
+        for (idx, inlier) in inliers.iter_mut().enumerate() {
+            let r = estimator.residual(model, idx);
+            if r < threshold2 {
+                *inlier = true;
+                count += 1;
+                score += match params.method {
+                    RobustMethod::Ransac => 1.0,
+                    RobustMethod::Msac | RobustMethod::Prosac => threshold2 - r,
+                };
+            }
+        }
+        (score, inliers, count)
+    };
+
+    let mut rng = SplitMix64::new(params.seed);
+    let mut prosac = match (params.method, order) {
+        (RobustMethod::Prosac, Some(order)) if order.len() == len => Some((ProsacSampler::new(len, m, params.max_iterations), order)),
+        _ => None,
+    };
+
+    let mut best: Option<(f64, E::Model, Vec<bool>, usize)> = None;
+    let mut max_iterations = params.max_iterations;
+    let mut iterations = 0;
+    while iterations < max_iterations {
+        iterations += 1;
+        let sample = match prosac.as_mut() {
+            Some((sampler, order)) => sampler.sample(&mut rng).into_iter().map(|rank| order[rank]).collect(),
+            None => rng.sample(len, m),
+        };
+
+        for model in estimator.solve(&sample) {
+            let (s, inliers, count) = score(&model);
+            if best.as_ref().is_some_and(|b| s <= b.0) {
+                continue;
+            }
+            let mut candidate = (s, model, inliers, count);
+            if params.local_optimization {
+                local_optimization(estimator, &score, &mut candidate);
+            }
+            max_iterations = max_iterations.min(adaptive_iterations(
+                candidate.3 as f64 / len as f64, m, params.confidence, params.max_iterations));
+            best = Some(candidate);
+        }
+    }
+
+    let (_, model, inliers, num_inliers) = best?;
+    if num_inliers < m {
+        return None;
+    }
+    Some(RansacResult { model, inliers, num_inliers, iterations })
+}
+
+/// Re-fits the model to its inliers while that improves the score.
+fn local_optimization<E: Estimator, F>(estimator: &E, score: &F, best: &mut (f64, E::Model, Vec<bool>, usize))
+where
+    F: Fn(&E::Model) -> (f64, Vec<bool>, usize),
+{
+    for _ in 0..LO_ITERATIONS {
+        let inliers = best.2.iter().enumerate().filter(|(_, inlier)| **inlier).map(|(idx, _)| idx).collect::<Vec<_>>();
+        if inliers.len() <= estimator.sample_size() {
+            return;
+        }
+        let mut improved = false;
+        for model in estimator.refine(&best.1, &inliers) {
+            let (s, inliers, count) = score(&model);
+            if s > best.0 {
+                *best = (s, model, inliers, count);
+                improved = true;
+            }
+        }
+        if !improved {
+            return;
+        }
+    }
+}
+
+/// Fundamental matrix `x2^T * F * x1 = 0` between pixel correspondences, with the
+/// squared Sampson distance as residual.
+pub struct FundamentalEstimator<'a> {
+    pub points1: &'a [na::Point2<f64>],
+    pub points2: &'a [na::Point2<f64>],
+}
+
+impl<'a> Estimator for FundamentalEstimator<'a> {
+    type Model = na::Matrix3<f64>;
+
+    fn num_data(&self) -> usize {
+        self.points1.len()
+    }
+
+    fn sample_size(&self) -> usize {
+        8
+    }
+
+    fn solve(&self, sample: &[usize]) -> Vec<Self::Model> {
+        let points1 = sample.iter().map(|&i| self.points1[i]).collect::<Vec<_>>();
+        let points2 = sample.iter().map(|&i| self.points2[i]).collect::<Vec<_>>();
+        eight_point::fundamental(&points1, &points2).into_iter().collect()
+    }
+
+    fn residual(&self, f: &Self::Model, idx: usize) -> f64 {
+        sampson_distance2(f, &self.points1[idx], &self.points2[idx])
+    }
+}
+
+/// Squared Sampson distance of a correspondence to the epipolar geometry `x2^T * F * x1 = 0`.
+fn sampson_distance2(f: &na::Matrix3<f64>, p1: &na::Point2<f64>, p2: &na::Point2<f64>) -> f64 {
+    let x1 = p1.to_homogeneous();
+    let x2 = p2.to_homogeneous();
+    let fx1 = f * x1;
+    let ftx2 = f.transpose() * x2;
+    let denominator = fx1.x * fx1.x + fx1.y * fx1.y + ftx2.x * ftx2.x + ftx2.y * ftx2.y;
+    if denominator <= f64::EPSILON {
+        return f64::MAX;
+    }
+    x2.dot(&fx1).powi(2) / denominator
+}
+
+/// Essential matrix `x2^T * E * x1 = 0` between normalized image coordinates, solved
+/// with the five-point algorithm and refined with the eight-point one. The residual is the
+/// squared Sampson distance scaled by `focal`, so that thresholds stay in pixels.
+pub struct EssentialEstimator<'a> {
+    pub points1: &'a [na::Point2<f64>],
+    pub points2: &'a [na::Point2<f64>],
+    pub focal: f64,
+}
+
+impl<'a> Estimator for EssentialEstimator<'a> {
+    type Model = na::Matrix3<f64>;
+
+    fn num_data(&self) -> usize {
+        self.points1.len()
+    }
+
+    fn sample_size(&self) -> usize {
+        5
+    }
+
+    fn solve(&self, sample: &[usize]) -> Vec<Self::Model> {
+        let bearings1 = sample.iter().map(|&i| self.points1[i].to_homogeneous()).collect::<Vec<_>>();
+        let bearings2 = sample.iter().map(|&i| self.points2[i].to_homogeneous()).collect::<Vec<_>>();
+        five_point::essential(&bearings1, &bearings2)
+    }
+
+    fn residual(&self, e: &Self::Model, idx: usize) -> f64 {
+        sampson_distance2(e, &self.points1[idx], &self.points2[idx]) * self.focal * self.focal
+    }
+
+    fn refine(&self, _model: &Self::Model, inliers: &[usize]) -> Vec<Self::Model> {
+        let points1 = inliers.iter().map(|&i| self.points1[i]).collect::<Vec<_>>();
+        let points2 = inliers.iter().map(|&i| self.points2[i]).collect::<Vec<_>>();
+        eight_point::fundamental(&points1, &points2)
+            .and_then(|e| five_point::closest_essential(&e))
+            .into_iter()
+            .collect()
+    }
+}
+
+/// World to camera pose from 3d-2d correspondences, solved with P3P and refined with
+/// EPnP, with the squared reprojection error in pixels as residual.
+pub struct PnpEstimator<'a> {
+    pub points3d: &'a [na::Vector3<f64>],
+    pub points2d: &'a [na::Point2<f64>],
+    pub intrinsics: &'a CameraIntrinsics,
+}
+
+impl<'a> PnpEstimator<'a> {
+    fn bearing(&self, idx: usize) -> na::Vector3<f64> {
+        self.intrinsics.inv_projection(&self.points2d[idx]).to_homogeneous()
+    }
+}
+
+impl<'a> Estimator for PnpEstimator<'a> {
+    type Model = na::Isometry3<f64>;
+
+    fn num_data(&self) -> usize {
+        self.points3d.len()
+    }
+
+    fn sample_size(&self) -> usize {
+        3
+    }
+
+    fn solve(&self, sample: &[usize]) -> Vec<Self::Model> {
+        let points = sample.iter().map(|&i| self.points3d[i]).collect::<Vec<_>>();
+        let bearings = sample.iter().map(|&i| self.bearing(i)).collect::<Vec<_>>();
+        p3p::solve(&points, &bearings)
+    }
+
+    fn residual(&self, pose: &Self::Model, idx: usize) -> f64 {
+        let p_c = pose * na::Point3::from(self.points3d[idx]);
+        if p_c.z <= 0.0 {
+            return f64::MAX;
+        }
+        (self.intrinsics.projection(&p_c.coords) - self.points2d[idx]).norm_squared()
+    }
+
+    fn refine(&self, _model: &Self::Model, inliers: &[usize]) -> Vec<Self::Model> {
+        let points = inliers.iter().map(|&i| self.points3d[i]).collect::<Vec<_>>();
+        let bearings = inliers.iter().map(|&i| self.bearing(i)).collect::<Vec<_>>();
+        epnp::solve(&points, &bearings).into_iter().collect()
+    }
+}
+
+/// Homography `x2 ~ H * x1` between pixel correspondences, with the squared transfer
+/// error in the second image as residual.
+pub struct HomographyEstimator<'a> {
+    pub points1: &'a [na::Point2<f64>],
+    pub points2: &'a [na::Point2<f64>],
+}
+
+impl<'a> Estimator for HomographyEstimator<'a> {
+    type Model = na::Matrix3<f64>;
+
+    fn num_data(&self) -> usize {
+        self.points1.len()
+    }
+
+    fn sample_size(&self) -> usize {
+        4
+    }
+
+    fn solve(&self, sample: &[usize]) -> Vec<Self::Model> {
+        let points1 = sample.iter().map(|&i| self.points1[i]).collect::<Vec<_>>();
+        let points2 = sample.iter().map(|&i| self.points2[i]).collect::<Vec<_>>();
+        homography::dlt(&points1, &points2).into_iter().collect()
+    }
+
+    fn residual(&self, h: &Self::Model, idx: usize) -> f64 {
+        match na::Point2::from_homogeneous(h * self.points1[idx].to_homogeneous()) {
+            Some(p) => (p - self.points2[idx]).norm_squared(),
+            None => f64::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::solvers::test_utils::scene;
+
+    /// Points on a plane under a homography, with every fourth match replaced by an outlier.
+    fn homography_data() -> (na::Matrix3<f64>, Vec<na::Point2<f64>>, Vec<na::Point2<f64>>) {
+        let truth = na::Matrix3::new(
+            1.1, 0.05, 20.0,
+            -0.03, 0.95, -10.0,
+            1e-4, -2e-4, 1.0,
+        );
+        let mut rng = SplitMix64::new(42);
+        let mut uniform = |scale: f64| (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64 * scale;
+        let mut points1 = Vec::new();
+        let mut points2 = Vec::new();
+        for i in 0..200 {
+            let p1 = na::Point2::new(uniform(640.0), uniform(480.0));
+            let p2 = if i % 4 == 0 {
+                na::Point2::new(uniform(640.0), uniform(480.0))
+            } else {
+                na::Point2::from_homogeneous(truth * p1.to_homogeneous()).unwrap()
+            };
+            points1.push(p1);
+            points2.push(p2);
+        }
+        (truth, points1, points2)
+    }
+
+    #[test]
+    fn test_methods() {
+        let (truth, points1, points2) = homography_data();
+        let estimator = HomographyEstimator { points1: &points1, points2: &points2 };
+        // rank the true matches first, as descriptor distances would
+        let mut order = (0..points1.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| i % 4 == 0);
+
+        for method in [RobustMethod::Ransac, RobustMethod::Msac, RobustMethod::Prosac] {
+            for local_optimization in [false, true] {
+                let params = RansacParams {
+                    method,
+                    threshold: 1.0,
+                    confidence: 0.99,
+                    max_iterations: 1000,
+                    local_optimization,
+                    seed: 7,
+                };
+                let result = estimate(&estimator, &params, Some(&order)).unwrap();
+                assert_eq!(result.num_inliers, 150, "{:?}", method);
+                assert!(result.inliers.iter().enumerate().all(|(i, inlier)| *inlier == (i % 4 != 0)));
+                assert!((result.model / result.model[(2, 2)] - truth).norm() < 1e-6);
+                assert!(result.iterations < params.max_iterations);
+            }
+        }
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let (_, points1, points2) = homography_data();
+        let estimator = HomographyEstimator { points1: &points1, points2: &points2 };
+        let params = RansacParams {
+            method: RobustMethod::Ransac,
+            threshold: 1.0,
+            confidence: 0.99,
+            max_iterations: 1000,
+            local_optimization: false,
+            seed: 3,
+        };
+        let first = estimate(&estimator, &params, None).unwrap();
+        let second = estimate(&estimator, &params, None).unwrap();
+        assert_eq!(first.iterations, second.iterations);
+        assert_eq!(first.model, second.model);
+    }
+
+    #[test]
+    fn test_adaptive_iterations() {
+        assert_eq!(adaptive_iterations(1.0, 8, 0.99, 1000), 1);
+        assert_eq!(adaptive_iterations(0.0, 8, 0.99, 1000), 1000);
+        // 0.99 confidence at 50% inliers and 4-point samples: log(0.01) / log(15 / 16)
+        assert_eq!(adaptive_iterations(0.5, 4, 0.99, 1000), 72);
+    }
+
+    #[test]
+    fn test_essential_estimator() {
+        let (pose, points) = scene(100, 5);
+        let points1 = points.iter().map(|p| na::Point2::new(p.x / p.z, p.y / p.z)).collect::<Vec<_>>();
+        let mut points2 = points.iter()
+            .map(|p| na::Point2::from_homogeneous((pose * na::Point3::from(*p)).coords).unwrap())
+            .collect::<Vec<_>>();
+        for p in points2.iter_mut().step_by(5) {
+            p.x += 0.05;
+        }
+        let estimator = EssentialEstimator { points1: &points1, points2: &points2, focal: 450.0 };
+        let params = RansacParams {
+            method: RobustMethod::Msac,
+            threshold: 1.0,
+            confidence: 0.99,
+            max_iterations: 500,
+            local_optimization: true,
+            seed: 1,
+        };
+        let result = estimate(&estimator, &params, None).unwrap();
+        assert_eq!(result.num_inliers, 80);
+        assert!(result.inliers.iter().enumerate().all(|(i, inlier)| *inlier == (i % 5 != 0)));
+    }
+
+    #[test]
+    fn test_pnp_estimator() {
+        let intrinsics = CameraIntrinsics::new([450.0, 450.0, 320.0, 240.0], Default::default());
+        let (pose, points) = scene(60, 9);
+        let mut points2d = points.iter()
+            .map(|p| intrinsics.projection(&(pose * na::Point3::from(*p)).coords))
+            .collect::<Vec<_>>();
+        for p in points2d.iter_mut().step_by(3) {
+            p.y += 30.0;
+        }
+        let estimator = PnpEstimator { points3d: &points, points2d: &points2d, intrinsics: &intrinsics };
+        for local_optimization in [false, true] {
+            let params = RansacParams {
+                method: RobustMethod::Ransac,
+                threshold: 2.0,
+                confidence: 0.99,
+                max_iterations: 200,
+                local_optimization,
+                seed: 4,
+            };
+            let result = estimate(&estimator, &params, None).unwrap();
+            assert_eq!(result.num_inliers, 40);
+            assert!((result.model.translation.vector - pose.translation.vector).norm() < 1e-6);
+            assert!(result.model.rotation.angle_to(&pose.rotation) < 1e-6);
+        }
+    }
+}
@@ -1,6 +1,4 @@
 use std::error::Error;
-use std::fmt::format;
-use std::time;
 
 use opencv::{
     core, prelude::*,
@@ -8,7 +6,11 @@ use opencv::{
 use nalgebra as na;
 
 use super::camera;
+use super::config::InitConfig;
 use super::cv_convert;
+use super::ransac::{self, PnpEstimator, RansacParams};
+use super::solvers::homography;
+use super::triangulation;
 
 
 // t1= U(:,3) and R1=U * W * VT
@@ -17,13 +19,13 @@ use super::cv_convert;
 // then we have 4 possible solutions
 // (R1,t1),(R1,t2),(R2,t1),(R2,t2)
 pub fn from_essential(
-    essential_mat: &core::Mat,
+    e: &na::Matrix3<f64>,
     points1: &core::Vector<core::Point2f>,
     points2: &core::Vector<core::Point2f>,
     intrinsics: &camera::CameraIntrinsics,
+    config: &InitConfig,
 ) -> Result<(na::Isometry3<f64>, Vec<bool>, Vec<na::Point3<f64>>), Box<dyn Error>> {
     // use nalgebra to decompose essential matrix
-    let e = cv_convert::cv_mat_to_na_mat(essential_mat)?;
     let svd = e.svd(true, true);
     let mut u = svd.u.unwrap();
     // let sigma = svd.singular_values;
@@ -46,68 +48,140 @@ pub fn from_essential(
         u.m13, u.m23, u.m33
     );
     let t2 = -t1;
-    
-    let (inliers1, mask1, points3d1) = check_cheirality(&r1, &t1, points1, points2, intrinsics)?;
-    let (inliers2, mask2, points3d2) = check_cheirality(&r1, &t2, points1, points2, intrinsics)?;
-    let (inliers3, mask3, points3d3) = check_cheirality(&r2, &t1, points1, points2, intrinsics)?;
-    let (inliers4, mask4, points3d4) = check_cheirality(&r2, &t2, points1, points2, intrinsics)?;
 
-    println!("inliers1: {}, inliers2: {}, inliers3: {}, inliers4: {}", inliers1, inliers2, inliers3, inliers4);
+    select_pose(&[(r1, t1), (r1, t2), (r2, t1), (r2, t2)], points1, points2, intrinsics, config)
+}
+
+/// Decomposes a homography `x2 ~ H * x1` between pixels into its candidate poses and
+/// keeps the one passing the cheirality check. Translations are normalized to unit length,
+/// as for the essential matrix, since the plane distance is unknown anyway.
+pub fn from_homography(
+    h: &na::Matrix3<f64>,
+    points1: &core::Vector<core::Point2f>,
+    points2: &core::Vector<core::Point2f>,
+    intrinsics: &camera::CameraIntrinsics,
+    config: &InitConfig,
+) -> Result<(na::Isometry3<f64>, Vec<bool>, Vec<na::Point3<f64>>), Box<dyn Error>> {
+    let k = intrinsics.k_mat;
+    let k_inv = k.try_inverse().ok_or("singular camera matrix")?;
+    let candidates = homography::decompose(&(k_inv * h * k));
+    if candidates.is_empty() {
+        // pure rotation, nothing can be triangulated
+        return Err("homography has no translation".into());
+    }
 
-    // get max inliers
-    let max_inliers = inliers1.max(inliers2).max(inliers3).max(inliers4);
-    if (max_inliers as f64 / points1.len() as f64) < 0.5 {
+    select_pose(&candidates, points1, points2, intrinsics, config)
+}
+
+/// Triangulates the correspondences with every candidate `(R_21, t_21)` and picks the one
+/// with the most points in front of both cameras. Fails when too few points survive,
+/// when a second candidate is almost as good (ambiguous), or when the median triangulation
+/// angle is below `min_parallax_deg`.
+fn select_pose(
+    candidates: &[(na::Matrix3<f64>, na::Vector3<f64>)],
+    points1: &core::Vector<core::Point2f>,
+    points2: &core::Vector<core::Point2f>,
+    intrinsics: &camera::CameraIntrinsics,
+    config: &InitConfig,
+) -> Result<(na::Isometry3<f64>, Vec<bool>, Vec<na::Point3<f64>>), Box<dyn Error>> {
+    let mut results = Vec::new();
+    for (r, t) in candidates.iter() {
+        let (inliers, mask, points3d) = check_cheirality(r, t, points1, points2, intrinsics, config)?;
+        results.push((inliers, r, t, mask, points3d));
+    }
+    println!("inliers: {:?}", results.iter().map(|r| r.0).collect::<Vec<_>>());
+
+    results.sort_by(|a, b| b.0.cmp(&a.0));
+    let max_inliers = results[0].0;
+    if (max_inliers as f64 / points1.len() as f64) < config.min_inlier_ratio || max_inliers < config.min_triangulated {
         println!("len: {}, max_inliers: {}", points1.len(), max_inliers);
         return Err("Not enough inliers".into());
     }
-    // get corresponding r and t
-    let (r, t, mask, points3d) = if max_inliers == inliers1 {
-        (r1, t1, mask1, points3d1)
-    } else if max_inliers == inliers2 {
-        (r1, t2, mask2, points3d2)
-    } else if max_inliers == inliers3 {
-        (r2, t1, mask3, points3d3)
-    } else {
-        (r2, t2, mask4, points3d4)
-    };
+    if results.len() > 1 && results[1].0 as f64 > 0.75 * max_inliers as f64 {
+        return Err("Ambiguous pose".into());
+    }
+
+    let (_, r, t, mask, points3d) = results.swap_remove(0);
+    let center2 = -r.transpose() * t;
+    let mut parallax = points3d.iter()
+        .map(|p| {
+            let ray1 = p.coords;
+            let ray2 = p.coords - center2;
+            (ray1.dot(&ray2) / (ray1.norm() * ray2.norm())).clamp(-1.0, 1.0).acos().to_degrees()
+        })
+        .collect::<Vec<_>>();
+    parallax.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median_parallax = parallax[parallax.len() / 2];
+    if median_parallax < config.min_parallax_deg {
+        println!("median parallax: {} deg", median_parallax);
+        return Err("Not enough parallax".into());
+    }
+
     // construct pose from r and t
     let pose = na::Isometry3::<f64>::from_parts(
-        na::Translation3::<f64>::from(t),
-        na::UnitQuaternion::<f64>::from_rotation_matrix(&na::Rotation3::<f64>::from_matrix_unchecked(r)),
+        na::Translation3::<f64>::from(*t),
+        na::UnitQuaternion::<f64>::from_rotation_matrix(&na::Rotation3::<f64>::from_matrix_unchecked(*r)),
     );
 
     Ok((pose, mask, points3d))
 }
 
+/// Estimates the world to camera pose from 3d-2d correspondences with RANSAC over P3P
+/// samples, refined with EPnP. Returns the pose and the indices of the inliers.
+pub fn from_pnp(
+    points3d: &[na::Vector3<f64>],
+    points2d: &[core::Point2f],
+    intrinsics: &camera::CameraIntrinsics,
+    params: &RansacParams,
+) -> Result<(na::Isometry3<f64>, Vec<usize>), Box<dyn Error>> {
+    let points2d = points2d.iter().map(cv_convert::cv_point2f_to_na_point2f).collect::<Vec<_>>();
+    let estimator = PnpEstimator { points3d, points2d: &points2d, intrinsics };
+    let result = ransac::estimate(&estimator, params, None).ok_or("PnP failed")?;
+    let inliers = result.inliers.iter()
+        .enumerate()
+        .filter(|(_, inlier)| **inlier)
+        .map(|(idx, _)| idx)
+        .collect();
+
+    Ok((result.model, inliers))
+}
+
+/// Triangulates every correspondence with the first camera at the origin and the second at
+/// `(r, t)`, and keeps points in front of both cameras, closer than `max_depth`, reprojecting
+/// within `max_reprojection_error` and with a relative depth uncertainty below `max_depth_sigma`.
 pub fn check_cheirality(
     r: &na::Matrix3<f64>,
     t: &na::Vector3<f64>,
     points1: &core::Vector<core::Point2f>,
     points2: &core::Vector<core::Point2f>,
     intrinsics: &camera::CameraIntrinsics,
+    config: &InitConfig,
 ) -> Result<(usize, Vec<bool>, Vec<na::Point3<f64>>), Box<dyn Error>> {
+    let pose1 = na::Isometry3::<f64>::identity();
+    let pose2 = na::Isometry3::<f64>::from_parts(
+        na::Translation3::<f64>::from(*t),
+        na::UnitQuaternion::<f64>::from_rotation_matrix(&na::Rotation3::<f64>::from_matrix_unchecked(*r)),
+    );
     let mut mask = Vec::new();
     let mut points3d = Vec::new();
     for (pt1, pt2) in points1.iter().zip(points2.iter()) {
         let x1 = cv_convert::cv_point2f_to_na_point2f(&pt1);
         let x2 = cv_convert::cv_point2f_to_na_point2f(&pt2);
-        let p1 = intrinsics.k_mat * na::Matrix3x4::<f64>::from_rows(&[
-            na::RowVector4::<f64>::new(1.0, 0.0, 0.0, 0.0),
-            na::RowVector4::<f64>::new(0.0, 1.0, 0.0, 0.0),
-            na::RowVector4::<f64>::new(0.0, 0.0, 1.0, 0.0),
-        ]);
-        let p2 = intrinsics.k_mat * na::Matrix3x4::<f64>::from_columns(&[
-            r.column(0), r.column(1), r.column(2), t.into()
-        ]);
-
-        let p1 = trangulate_point_linear(&x1, &x2, &p1, &p2)?;
-        let p2 = r * p1 + t;
-
-        if p1[2] > 0.0 && p2[2] > 0.0 && p1[2] < 50.0 && p2[2] < 50.0 {
-            points3d.push(p1);
-            mask.push(true);
-        } else {
-            mask.push(false);
+
+        let good = match triangulation::triangulate(config.triangulation, &pose1, &pose2, &x1, &x2, intrinsics, config.sigma) {
+            Some(p) if p.depth1 > 0.0 && p.depth2 > 0.0
+                && p.depth1 < config.max_depth && p.depth2 < config.max_depth
+                && p.reprojection_error1 < config.max_reprojection_error
+                && p.reprojection_error2 < config.max_reprojection_error
+                && p.relative_depth_sigma < config.max_depth_sigma => Some(p.point),
+            _ => None,
+        };
+        match good {
+            Some(point) => {
+                points3d.push(point);
+                mask.push(true);
+            },
+            None => mask.push(false),
         }
     }
 
@@ -115,22 +189,3 @@ pub fn check_cheirality(
 
     Ok((inlier, mask, points3d))
 }
-
-pub fn trangulate_point_linear(
-    xl: &na::Point2<f64>,
-    xr: &na::Point2<f64>,
-    pl: &na::Matrix3x4<f64>,
-    pr: &na::Matrix3x4<f64>,
-) -> Result<na::Point3<f64>, Box<dyn Error>> { 
-    let design = na::Matrix4::<f64>::from_rows(&[
-        xl.x * pl.row(2) - pl.row(0),
-        xl.y * pl.row(2) - pl.row(1),
-        xr.x * pr.row(2) - pr.row(0),
-        xr.y * pr.row(2) - pr.row(1),
-    ]);
-    let svd = design.svd(true, true);
-    let vt = svd.v_t.expect("SVD failed");
-    let p = vt.row(vt.nrows() - 1).transpose();
-    let p = p / p[3];
-    Ok(na::Point3::<f64>::new(p[0], p[1], p[2]))
-}
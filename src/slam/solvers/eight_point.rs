@@ -0,0 +1,87 @@
+use nalgebra as na;
+
+use super::nullspace;
+
+/// Similarity moving the centroid of `points` to the origin and their mean distance to
+/// it to `sqrt(2)` (Hartley normalization).
+pub(super) fn normalization(points: &[na::Point2<f64>]) -> na::Matrix3<f64> {
+    let n = points.len() as f64;
+    let centroid = points.iter().map(|p| p.coords).sum::<na::Vector2<f64>>() / n;
+    let mean_distance = points.iter().map(|p| (p.coords - centroid).norm()).sum::<f64>() / n;
+    let s = if mean_distance > 0.0 { std::f64::consts::SQRT_2 / mean_distance } else { 1.0 };
+    na::Matrix3::new(
+        s, 0.0, -s * centroid.x,
+        0.0, s, -s * centroid.y,
+        0.0, 0.0, 1.0,
+    )
+}
+
+/// One row of the linear system `x2^T * F * x1 = 0` in the unknowns of row-major `F`.
+pub(super) fn epipolar_row(x1: &na::Vector3<f64>, x2: &na::Vector3<f64>) -> [f64; 9] {
+    [
+        x2.x * x1.x, x2.x * x1.y, x2.x * x1.z,
+        x2.y * x1.x, x2.y * x1.y, x2.y * x1.z,
+        x2.z * x1.x, x2.z * x1.y, x2.z * x1.z,
+    ]
+}
+
+/// Normalized eight-point algorithm: the fundamental matrix `x2^T * F * x1 = 0` from at
+/// least eight pixel correspondences, with rank 2 enforced and unit Frobenius norm.
+/// More than eight points give the linear least squares solution.
+pub fn fundamental(points1: &[na::Point2<f64>], points2: &[na::Point2<f64>]) -> Option<na::Matrix3<f64>> {
+    if points1.len() < 8 || points1.len() != points2.len() {
+        return None;
+    }
+    let t1 = normalization(points1);
+    let t2 = normalization(points2);
+
+    let mut a = na::DMatrix::<f64>::zeros(points1.len(), 9);
+    for (i, (p1, p2)) in points1.iter().zip(points2.iter()).enumerate() {
+        let x1 = t1 * p1.to_homogeneous();
+        let x2 = t2 * p2.to_homogeneous();
+        a.row_mut(i).copy_from_slice(&epipolar_row(&x1, &x2));
+    }
+    let f = nullspace(&a, 1).pop()?;
+    let f = na::Matrix3::from_row_slice(f.as_slice());
+
+    // closest rank 2 matrix
+    let mut svd = f.svd(true, true);
+    let smallest = svd.singular_values.imin();
+    svd.singular_values[smallest] = 0.0;
+    let f = svd.recompose().ok()?;
+
+    let f = t2.transpose() * f * t1;
+    let norm = f.norm();
+    if norm < f64::EPSILON {
+        return None;
+    }
+    Some(f / norm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_utils::scene;
+
+    #[test]
+    fn test_fundamental() {
+        let (pose, points) = scene(20, 1);
+        let k = na::Matrix3::new(
+            458.0, 0.0, 367.0,
+            0.0, 457.0, 248.0,
+            0.0, 0.0, 1.0,
+        );
+        let project = |p: na::Vector3<f64>| na::Point2::from((k * p).xy() / p.z);
+        let points1 = points.iter().map(|p| project(*p)).collect::<Vec<_>>();
+        let points2 = points.iter().map(|p| project((pose * na::Point3::from(*p)).coords)).collect::<Vec<_>>();
+
+        for n in [8, 20] {
+            let f = fundamental(&points1[..n], &points2[..n]).unwrap();
+            for (p1, p2) in points1.iter().zip(points2.iter()) {
+                let residual = p2.to_homogeneous().dot(&(f * p1.to_homogeneous()));
+                assert!(residual.abs() < 1e-6, "residual {}", residual);
+            }
+            assert!(f.determinant().abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use nalgebra as na;
+
+use super::rigid_alignment;
+
+/// Control point pairs whose distances constrain the betas.
+const PAIRS: [(usize, usize); 6] = [(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)];
+
+/// Gauss-Newton iterations refining the betas.
+const REFINE_ITERATIONS: usize = 5;
+
+/// EPnP (Lepetit et al. 2009): world to camera pose from at least four world points and
+/// their bearings, which must point in front of the camera (positive z).
+///
+/// The points are expressed as barycentric combinations of four control points, whose
+/// camera coordinates lie in the nullspace of a `2n x 12` system. The combination of the
+/// nullspace vectors is found for one, two and three dimensional nullspaces, refined with
+/// Gauss-Newton, and the solution with the smallest reprojection error is returned.
+/// With exactly four points the nullspace is four-dimensional and the result is only an
+/// approximation; use `p3p` for minimal samples.
+pub fn solve(points: &[na::Vector3<f64>], bearings: &[na::Vector3<f64>]) -> Option<na::Isometry3<f64>> {
+    let n = points.len();
+    if n < 4 || bearings.len() != n {
+        return None;
+    }
+    let image = bearings.iter()
+        .map(|b| if b.z > 0.0 { Some(na::Vector2::new(b.x / b.z, b.y / b.z)) } else { None })
+        .collect::<Option<Vec<_>>>()?;
+
+    // control points: centroid and principal directions of the world points
+    let centroid = points.iter().sum::<na::Vector3<f64>>() / n as f64;
+    let mut cov = na::Matrix3::<f64>::zeros();
+    for p in points.iter() {
+        cov += (p - centroid) * (p - centroid).transpose();
+    }
+    let eigen = cov.symmetric_eigen();
+    let mut control_world = [centroid; 4];
+    for i in 0..3 {
+        let scale = (eigen.eigenvalues[i].max(0.0) / n as f64).sqrt();
+        control_world[i + 1] = centroid + eigen.eigenvectors.column(i) * scale;
+    }
+
+    // barycentric coordinates of every point
+    let basis = na::Matrix3::from_columns(&[
+        control_world[1] - centroid,
+        control_world[2] - centroid,
+        control_world[3] - centroid,
+    ]);
+    let basis_inv = basis.try_inverse()?;
+    let alphas = points.iter()
+        .map(|p| {
+            let a = basis_inv * (p - centroid);
+            [1.0 - a.x - a.y - a.z, a.x, a.y, a.z]
+        })
+        .collect::<Vec<_>>();
+
+    let mut m = na::DMatrix::<f64>::zeros(2 * n, 12);
+    for (i, (alpha, uv)) in alphas.iter().zip(image.iter()).enumerate() {
+        for j in 0..4 {
+            m[(2 * i, 3 * j)] = alpha[j];
+            m[(2 * i, 3 * j + 2)] = -alpha[j] * uv.x;
+            m[(2 * i + 1, 3 * j + 1)] = alpha[j];
+            m[(2 * i + 1, 3 * j + 2)] = -alpha[j] * uv.y;
+        }
+    }
+    let mtm = m.transpose() * &m;
+    let eigen = mtm.symmetric_eigen();
+    let mut order = (0..12).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| eigen.eigenvalues[i].partial_cmp(&eigen.eigenvalues[j]).unwrap());
+    let v = order.iter().take(4).map(|&i| eigen.eigenvectors.column(i).clone_owned()).collect::<Vec<_>>();
+    let control = |k: usize, j: usize| na::Vector3::new(v[k][3 * j], v[k][3 * j + 1], v[k][3 * j + 2]);
+
+    // |sum_k beta_k * (v_k_i - v_k_j)|^2 = |c_i - c_j|^2 is linear in the beta products
+    // [b00, b01, b11, b02, b12, b22, b03, b13, b23, b33]
+    let mut l = na::SMatrix::<f64, 6, 10>::zeros();
+    let mut rho = na::SVector::<f64, 6>::zeros();
+    for (row, (i, j)) in PAIRS.iter().enumerate() {
+        let dv = (0..4).map(|k| control(k, *i) - control(k, *j)).collect::<Vec<_>>();
+        let products = [
+            dv[0].dot(&dv[0]), 2.0 * dv[0].dot(&dv[1]), dv[1].dot(&dv[1]),
+            2.0 * dv[0].dot(&dv[2]), 2.0 * dv[1].dot(&dv[2]), dv[2].dot(&dv[2]),
+            2.0 * dv[0].dot(&dv[3]), 2.0 * dv[1].dot(&dv[3]), 2.0 * dv[2].dot(&dv[3]), dv[3].dot(&dv[3]),
+        ];
+        l.row_mut(row).copy_from_slice(&products);
+        rho[row] = (control_world[*i] - control_world[*j]).norm_squared();
+    }
+
+    let pose_from_betas = |betas: &[f64; 4]| -> Option<(na::Isometry3<f64>, f64)> {
+        let mut control_camera = [na::Vector3::<f64>::zeros(); 4];
+        for (j, c) in control_camera.iter_mut().enumerate() {
+            *c = (0..4).map(|k| control(k, j) * betas[k]).sum();
+        }
+        let mut camera_points = alphas.iter()
+            .map(|a| (0..4).map(|j| control_camera[j] * a[j]).sum::<na::Vector3<f64>>())
+            .collect::<Vec<_>>();
+        // the nullspace has no sign, the points must be in front of the camera
+        if camera_points.iter().map(|p| p.z).sum::<f64>() < 0.0 {
+            camera_points.iter_mut().for_each(|p| *p = -*p);
+        }
+        let pose = rigid_alignment(points, &camera_points)?;
+        let error = points.iter().zip(image.iter())
+            .map(|(p, uv)| {
+                let p = pose * na::Point3::from(*p);
+                (p.coords.xy() / p.z - uv).norm()
+            })
+            .sum::<f64>() / n as f64;
+        Some((pose, error))
+    };
+
+    [approximate_n1(&l, &rho), approximate_n2(&l, &rho), approximate_n3(&l, &rho)]
+        .into_iter()
+        .flatten()
+        .filter_map(|betas| pose_from_betas(&refine(&l, &rho, betas)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(pose, _)| pose)
+}
+
+/// Least squares solution of `a[:, columns] * x = b`.
+fn least_squares<const C: usize>(a: &na::SMatrix<f64, 6, C>, columns: &[usize], b: &na::SVector<f64, 6>) -> Option<na::DVector<f64>> {
+    let a = na::DMatrix::from_fn(6, columns.len(), |r, c| a[(r, columns[c])]);
+    let b = na::DVector::from_column_slice(b.as_slice());
+    a.svd(true, true).solve(&b, 1e-12).ok()
+}
+
+/// One-dimensional nullspace, using [b00, b01, b02, b03].
+fn approximate_n1(l: &na::SMatrix<f64, 6, 10>, rho: &na::SVector<f64, 6>) -> Option<[f64; 4]> {
+    let b = least_squares(l, &[0, 1, 3, 6], rho)?;
+    let b00 = b[0].abs().sqrt();
+    if b00 < f64::EPSILON {
+        return None;
+    }
+    let sign = if b[0] < 0.0 { -1.0 } else { 1.0 };
+    Some([b00, sign * b[1] / b00, sign * b[2] / b00, sign * b[3] / b00])
+}
+
+/// Two-dimensional nullspace, using [b00, b01, b11].
+fn approximate_n2(l: &na::SMatrix<f64, 6, 10>, rho: &na::SVector<f64, 6>) -> Option<[f64; 4]> {
+    let b = least_squares(l, &[0, 1, 2], rho)?;
+    let (mut beta0, beta1) = if b[0] < 0.0 {
+        ((-b[0]).sqrt(), if b[2] < 0.0 { (-b[2]).sqrt() } else { 0.0 })
+    } else {
+        (b[0].sqrt(), if b[2] > 0.0 { b[2].sqrt() } else { 0.0 })
+    };
+    if b[1] < 0.0 {
+        beta0 = -beta0;
+    }
+    Some([beta0, beta1, 0.0, 0.0])
+}
+
+/// Three-dimensional nullspace, using [b00, b01, b11, b02, b12].
+fn approximate_n3(l: &na::SMatrix<f64, 6, 10>, rho: &na::SVector<f64, 6>) -> Option<[f64; 4]> {
+    let b = least_squares(l, &[0, 1, 2, 3, 4], rho)?;
+    let (mut beta0, beta1) = if b[0] < 0.0 {
+        ((-b[0]).sqrt(), if b[2] < 0.0 { (-b[2]).sqrt() } else { 0.0 })
+    } else {
+        (b[0].sqrt(), if b[2] > 0.0 { b[2].sqrt() } else { 0.0 })
+    };
+    if b[1] < 0.0 {
+        beta0 = -beta0;
+    }
+    if beta0.abs() < f64::EPSILON {
+        return None;
+    }
+    Some([beta0, beta1, b[3] / beta0, 0.0])
+}
+
+/// Gauss-Newton on `L * beta_products(betas) = rho`.
+fn refine(l: &na::SMatrix<f64, 6, 10>, rho: &na::SVector<f64, 6>, mut betas: [f64; 4]) -> [f64; 4] {
+    for _ in 0..REFINE_ITERATIONS {
+        let [b0, b1, b2, b3] = betas;
+        let products = na::SVector::<f64, 10>::from([
+            b0 * b0, b0 * b1, b1 * b1, b0 * b2, b1 * b2, b2 * b2, b0 * b3, b1 * b3, b2 * b3, b3 * b3,
+        ]);
+        let residual = l * products - rho;
+        let mut jacobian = na::SMatrix::<f64, 6, 4>::zeros();
+        for r in 0..6 {
+            let l = |c: usize| l[(r, c)];
+            jacobian[(r, 0)] = 2.0 * b0 * l(0) + b1 * l(1) + b2 * l(3) + b3 * l(6);
+            jacobian[(r, 1)] = b0 * l(1) + 2.0 * b1 * l(2) + b2 * l(4) + b3 * l(7);
+            jacobian[(r, 2)] = b0 * l(3) + b1 * l(4) + 2.0 * b2 * l(5) + b3 * l(8);
+            jacobian[(r, 3)] = b0 * l(6) + b1 * l(7) + b2 * l(8) + 2.0 * b3 * l(9);
+        }
+        let delta = match least_squares(&jacobian, &[0, 1, 2, 3], &(-residual)) {
+            Some(delta) => delta,
+            None => break,
+        };
+        for (beta, d) in betas.iter_mut().zip(delta.iter()) {
+            *beta += d;
+        }
+    }
+    betas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_utils::{bearing, scene, Lcg};
+
+    #[test]
+    fn test_epnp() {
+        for (seed, n) in [(0, 5), (1, 6), (2, 50), (3, 100)] {
+            let (pose, points) = scene(n, seed);
+            let bearings = points.iter().map(|p| bearing(&pose, p)).collect::<Vec<_>>();
+            let estimate = solve(&points, &bearings).unwrap();
+            let error = (estimate.to_homogeneous() - pose.to_homogeneous()).norm();
+            assert!(error < 1e-6, "n {}: error {}", n, error);
+        }
+    }
+
+    #[test]
+    fn test_epnp_noise() {
+        let (pose, points) = scene(100, 7);
+        let mut rng = Lcg::new(8);
+        // about half a pixel at a focal length of 500 pixels
+        let bearings = points.iter()
+            .map(|p| bearing(&pose, p) + na::Vector3::new(rng.range(-1e-3, 1e-3), rng.range(-1e-3, 1e-3), 0.0))
+            .collect::<Vec<_>>();
+        let estimate = solve(&points, &bearings).unwrap();
+        assert!((estimate.translation.vector - pose.translation.vector).norm() < 0.05);
+        assert!(estimate.rotation.angle_to(&pose.rotation) < 0.01);
+    }
+}
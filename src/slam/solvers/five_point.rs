@@ -0,0 +1,240 @@
+use nalgebra as na;
+
+use super::{eight_point::epipolar_row, nullspace, real_roots};
+
+/// Monomials in `x, y, z` up to degree 3, in the order of Nistér's paper, so that
+/// Gauss-Jordan elimination of the first ten columns leaves the rows needed for the
+/// final 3x3 polynomial matrix.
+const MONOMIALS: [(u8, u8, u8); 20] = [
+    (3, 0, 0), (0, 3, 0), (2, 1, 0), (1, 2, 0), (2, 0, 1), (2, 0, 0), (0, 2, 1), (0, 2, 0), (1, 1, 1), (1, 1, 0),
+    (1, 0, 2), (1, 0, 1), (1, 0, 0), (0, 1, 2), (0, 1, 1), (0, 1, 0), (0, 0, 3), (0, 0, 2), (0, 0, 1), (0, 0, 0),
+];
+
+/// Polynomial in `x, y, z` of degree at most 3, one coefficient per entry of `MONOMIALS`.
+#[derive(Clone, Copy)]
+struct Poly([f64; 20]);
+
+impl Poly {
+    fn zero() -> Self {
+        Self([0.0; 20])
+    }
+
+    /// `x * cx + y * cy + z * cz + c`
+    fn linear(cx: f64, cy: f64, cz: f64, c: f64) -> Self {
+        let mut p = Self::zero();
+        p.0[12] = cx;
+        p.0[15] = cy;
+        p.0[18] = cz;
+        p.0[19] = c;
+        p
+    }
+
+    fn index(exponents: (u8, u8, u8)) -> usize {
+        MONOMIALS.iter().position(|m| *m == exponents).expect("polynomial degree exceeds 3")
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        let mut p = *self;
+        p.0.iter_mut().zip(other.0.iter()).for_each(|(a, b)| *a += b);
+        p
+    }
+
+    fn scale(&self, s: f64) -> Self {
+        let mut p = *self;
+        p.0.iter_mut().for_each(|a| *a *= s);
+        p
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        let mut p = Self::zero();
+        for (i, a) in self.0.iter().enumerate().filter(|(_, a)| **a != 0.0) {
+            for (j, b) in other.0.iter().enumerate().filter(|(_, b)| **b != 0.0) {
+                let (mi, mj) = (MONOMIALS[i], MONOMIALS[j]);
+                p.0[Self::index((mi.0 + mj.0, mi.1 + mj.1, mi.2 + mj.2))] += a * b;
+            }
+        }
+        p
+    }
+}
+
+/// Univariate polynomial product, coefficients in ascending powers.
+fn poly_mul(a: &[f64], b: &[f64]) -> Vec<f64> {
+    let mut p = vec![0.0; a.len() + b.len() - 1];
+    for (i, a) in a.iter().enumerate() {
+        for (j, b) in b.iter().enumerate() {
+            p[i + j] += a * b;
+        }
+    }
+    p
+}
+
+fn poly_add(a: &[f64], b: &[f64]) -> Vec<f64> {
+    let mut p = vec![0.0; a.len().max(b.len())];
+    a.iter().enumerate().for_each(|(i, a)| p[i] += a);
+    b.iter().enumerate().for_each(|(i, b)| p[i] += b);
+    p
+}
+
+fn poly_eval(p: &[f64], z: f64) -> f64 {
+    p.iter().rev().fold(0.0, |acc, c| acc * z + c)
+}
+
+/// Nistér's five-point solver: up to ten essential matrices `x2^T * E * x1 = 0`, with
+/// unit Frobenius norm, consistent with five bearing correspondences. Extra
+/// correspondences beyond the first five are ignored; pick among the solutions with them.
+pub fn essential(bearings1: &[na::Vector3<f64>], bearings2: &[na::Vector3<f64>]) -> Vec<na::Matrix3<f64>> {
+    if bearings1.len() < 5 || bearings2.len() < 5 {
+        return Vec::new();
+    }
+    let mut q = na::DMatrix::<f64>::zeros(5, 9);
+    for i in 0..5 {
+        q.row_mut(i).copy_from_slice(&epipolar_row(&bearings1[i], &bearings2[i]));
+    }
+    // E = x * X + y * Y + z * Z + W
+    let basis = nullspace(&q, 4);
+    let (x, y, z, w) = (&basis[0], &basis[1], &basis[2], &basis[3]);
+    let e: [[Poly; 3]; 3] = std::array::from_fn(|r| std::array::from_fn(|c| {
+        let i = 3 * r + c;
+        Poly::linear(x[i], y[i], z[i], w[i])
+    }));
+
+    // det(E) = 0 and 2 * E * E^T * E - trace(E * E^T) * E = 0
+    let mut constraints = Vec::with_capacity(10);
+    let det = e[0][0].mul(&e[1][1].mul(&e[2][2]).add(&e[1][2].mul(&e[2][1]).scale(-1.0)))
+        .add(&e[0][1].mul(&e[1][2].mul(&e[2][0]).add(&e[1][0].mul(&e[2][2]).scale(-1.0))))
+        .add(&e[0][2].mul(&e[1][0].mul(&e[2][1]).add(&e[1][1].mul(&e[2][0]).scale(-1.0))));
+    constraints.push(det);
+    let eet: [[Poly; 3]; 3] = std::array::from_fn(|r| std::array::from_fn(|c| {
+        (0..3).fold(Poly::zero(), |acc, k| acc.add(&e[r][k].mul(&e[c][k])))
+    }));
+    let trace = eet[0][0].add(&eet[1][1]).add(&eet[2][2]);
+    for (eet_row, e_row) in eet.iter().zip(e.iter()) {
+        for (c, e_rc) in e_row.iter().enumerate() {
+            let eete = eet_row.iter().zip(e.iter())
+                .fold(Poly::zero(), |acc, (eet_rk, e_k)| acc.add(&eet_rk.mul(&e_k[c])));
+            constraints.push(eete.scale(2.0).add(&trace.mul(e_rc).scale(-1.0)));
+        }
+    }
+
+    // Gauss-Jordan elimination of the first ten monomials
+    let mut a = na::DMatrix::<f64>::from_fn(10, 20, |r, c| constraints[r].0[c]);
+    for col in 0..10 {
+        let pivot = (col..10).max_by(|&i, &j| a[(i, col)].abs().partial_cmp(&a[(j, col)].abs()).unwrap()).unwrap();
+        if a[(pivot, col)].abs() < 1e-12 {
+            return Vec::new();
+        }
+        a.swap_rows(col, pivot);
+        let inv = 1.0 / a[(col, col)];
+        a.row_mut(col).scale_mut(inv);
+        for row in 0..10 {
+            if row != col {
+                let factor = a[(row, col)];
+                for c in 0..20 {
+                    a[(row, c)] -= factor * a[(col, c)];
+                }
+            }
+        }
+    }
+
+    // <k> = <e> - z<f>, <l> = <g> - z<h>, <m> = <i> - z<j> only contain x, y and 1
+    // with coefficients polynomial in z: B(z) * [x, y, 1]^T = 0
+    let b = [(4, 5), (6, 7), (8, 9)].map(|(r1, r2)| {
+        let e = |c: usize| a[(r1, c)];
+        let f = |c: usize| a[(r2, c)];
+        [
+            vec![e(12), e(11) - f(12), e(10) - f(11), -f(10)],
+            vec![e(15), e(14) - f(15), e(13) - f(14), -f(13)],
+            vec![e(19), e(18) - f(19), e(17) - f(18), e(16) - f(17), -f(16)],
+        ]
+    });
+    let minor = |i: usize, j: usize, k: usize, l: usize| {
+        poly_add(&poly_mul(&b[i][k], &b[j][l]), &poly_mul(&b[i][l], &b[j][k]).iter().map(|c| -c).collect::<Vec<_>>())
+    };
+    let det = poly_add(
+        &poly_add(&poly_mul(&b[0][0], &minor(1, 2, 1, 2)), &poly_mul(&b[0][1], &minor(1, 2, 2, 0))),
+        &poly_mul(&b[0][2], &minor(1, 2, 0, 1)),
+    );
+
+    let mut solutions = Vec::new();
+    for z_root in real_roots(&det) {
+        let row = |i: usize| na::Vector3::new(
+            poly_eval(&b[i][0], z_root),
+            poly_eval(&b[i][1], z_root),
+            poly_eval(&b[i][2], z_root),
+        );
+        // [x, y, 1] is orthogonal to every row of B(z), take the best conditioned cross product
+        let candidates = [row(0).cross(&row(1)), row(0).cross(&row(2)), row(1).cross(&row(2))];
+        let null = candidates.iter().max_by(|a, b| a.z.abs().partial_cmp(&b.z.abs()).unwrap()).unwrap();
+        if null.z.abs() < 1e-12 {
+            continue;
+        }
+        let (x_root, y_root) = (null.x / null.z, null.y / null.z);
+        let e = x * x_root + y * y_root + z * z_root + w;
+        let e = na::Matrix3::from_row_slice(e.as_slice());
+        let norm = e.norm();
+        if norm > f64::EPSILON {
+            solutions.push(e / norm);
+        }
+    }
+    solutions
+}
+
+/// Closest essential matrix to `m` in Frobenius norm, with singular values `(1, 1, 0)`.
+pub fn closest_essential(m: &na::Matrix3<f64>) -> Option<na::Matrix3<f64>> {
+    let svd = m.svd(true, true);
+    let mut sigma = svd.singular_values;
+    let smallest = sigma.imin();
+    sigma.fill(1.0);
+    sigma[smallest] = 0.0;
+    Some(svd.u? * na::Matrix3::from_diagonal(&sigma) * svd.v_t?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_utils::{bearing, scene};
+
+    fn skew(v: &na::Vector3<f64>) -> na::Matrix3<f64> {
+        na::Matrix3::new(
+            0.0, -v.z, v.y,
+            v.z, 0.0, -v.x,
+            -v.y, v.x, 0.0,
+        )
+    }
+
+    #[test]
+    fn test_essential() {
+        for seed in 0..10 {
+            let (pose, points) = scene(5, seed);
+            let bearings1 = points.iter().map(|p| p.normalize()).collect::<Vec<_>>();
+            let bearings2 = points.iter().map(|p| bearing(&pose, p)).collect::<Vec<_>>();
+
+            let truth = skew(&pose.translation.vector) * pose.rotation.to_rotation_matrix().matrix();
+            let truth = truth / truth.norm();
+            let solutions = essential(&bearings1, &bearings2);
+            assert!(!solutions.is_empty());
+            for e in solutions.iter() {
+                for (b1, b2) in bearings1.iter().zip(bearings2.iter()) {
+                    assert!(b2.dot(&(e * b1)).abs() < 1e-8);
+                }
+            }
+            let error = solutions.iter()
+                .map(|e| (e - truth).norm().min((e + truth).norm()))
+                .fold(f64::MAX, f64::min);
+            assert!(error < 1e-6, "seed {}: error {}", seed, error);
+        }
+    }
+
+    #[test]
+    fn test_closest_essential() {
+        let (pose, _) = scene(0, 3);
+        let truth = skew(&pose.translation.vector.normalize()) * pose.rotation.to_rotation_matrix().matrix();
+        let noisy = truth * 2.0 + na::Matrix3::from_fn(|r, c| 1e-3 * (r as f64 - c as f64));
+        let e = closest_essential(&noisy).unwrap();
+        let sigma = e.singular_values();
+        let mut sigma = sigma.as_slice().to_vec();
+        sigma.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!(sigma[0].abs() < 1e-12 && (sigma[1] - 1.0).abs() < 1e-12 && (sigma[2] - 1.0).abs() < 1e-12);
+        assert!((e - truth).norm() < 1e-2);
+    }
+}
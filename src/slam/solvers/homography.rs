@@ -0,0 +1,139 @@
+use nalgebra as na;
+
+use super::{eight_point::normalization, nullspace};
+
+/// Normalized DLT: the homography `x2 ~ H * x1` from at least four pixel correspondences,
+/// scaled to `H[(2, 2)] = 1` when possible. More than four points give the linear least
+/// squares solution.
+pub fn dlt(points1: &[na::Point2<f64>], points2: &[na::Point2<f64>]) -> Option<na::Matrix3<f64>> {
+    if points1.len() < 4 || points1.len() != points2.len() {
+        return None;
+    }
+    let t1 = normalization(points1);
+    let t2 = normalization(points2);
+
+    let mut a = na::DMatrix::<f64>::zeros(2 * points1.len(), 9);
+    for (i, (p1, p2)) in points1.iter().zip(points2.iter()).enumerate() {
+        let x1 = t1 * p1.to_homogeneous();
+        let x2 = t2 * p2.to_homogeneous();
+        a.row_mut(2 * i).copy_from_slice(&[
+            0.0, 0.0, 0.0,
+            -x2.z * x1.x, -x2.z * x1.y, -x2.z * x1.z,
+            x2.y * x1.x, x2.y * x1.y, x2.y * x1.z,
+        ]);
+        a.row_mut(2 * i + 1).copy_from_slice(&[
+            x2.z * x1.x, x2.z * x1.y, x2.z * x1.z,
+            0.0, 0.0, 0.0,
+            -x2.x * x1.x, -x2.x * x1.y, -x2.x * x1.z,
+        ]);
+    }
+    let h = nullspace(&a, 1).pop()?;
+    let h = na::Matrix3::from_row_slice(h.as_slice());
+    let h = t2.try_inverse()? * h * t1;
+
+    if h[(2, 2)].abs() > f64::EPSILON {
+        Some(h / h[(2, 2)])
+    } else {
+        Some(h / h.norm())
+    }
+}
+
+/// Faugeras' SVD decomposition of a calibrated homography `H = K^{-1} * H_pixel * K`
+/// into the eight candidate motions `(R_21, t_21)` it admits, with unit translations
+/// since the distance of the plane is unknown. Pick among them by cheirality.
+/// Returns nothing when the singular values are (nearly) repeated, i.e. for pure
+/// rotation or a degenerate homography.
+pub fn decompose(h: &na::Matrix3<f64>) -> Vec<(na::Matrix3<f64>, na::Vector3<f64>)> {
+    let svd = h.svd(true, true);
+    let (u, v_t) = match (svd.u, svd.v_t) {
+        (Some(u), Some(v_t)) => (u, v_t),
+        _ => return Vec::new(),
+    };
+    // nalgebra does not sort the singular values
+    let mut order = [0, 1, 2];
+    order.sort_by(|&i, &j| svd.singular_values[j].partial_cmp(&svd.singular_values[i]).unwrap());
+    let u = na::Matrix3::from_columns(&order.map(|i| u.column(i).into_owned()));
+    let v_t = na::Matrix3::from_rows(&order.map(|i| v_t.row(i).into_owned()));
+    let [d1, d2, d3] = order.map(|i| svd.singular_values[i]);
+    if d3 <= 0.0 || d1 / d2 < 1.00001 || d2 / d3 < 1.00001 {
+        return Vec::new();
+    }
+    let s = u.determinant() * v_t.determinant();
+
+    let (d1s, d2s, d3s) = (d1 * d1, d2 * d2, d3 * d3);
+    let aux1 = ((d1s - d2s) / (d1s - d3s)).sqrt();
+    let aux3 = ((d2s - d3s) / (d1s - d3s)).sqrt();
+    let x1 = [aux1, aux1, -aux1, -aux1];
+    let x3 = [aux3, -aux3, aux3, -aux3];
+    let root = ((d1s - d2s) * (d2s - d3s)).sqrt();
+
+    let mut candidates = Vec::with_capacity(8);
+    // d' = d2
+    let sin_theta = root / ((d1 + d3) * d2);
+    let cos_theta = (d2s + d1 * d3) / ((d1 + d3) * d2);
+    for (i, sin) in [sin_theta, -sin_theta, -sin_theta, sin_theta].into_iter().enumerate() {
+        let rp = na::Matrix3::new(
+            cos_theta, 0.0, -sin,
+            0.0, 1.0, 0.0,
+            sin, 0.0, cos_theta,
+        );
+        let tp = na::Vector3::new(x1[i], 0.0, -x3[i]) * (d1 - d3);
+        candidates.push((s * u * rp * v_t, (u * tp).normalize()));
+    }
+    // d' = -d2
+    let sin_phi = root / ((d1 - d3) * d2);
+    let cos_phi = (d1 * d3 - d2s) / ((d1 - d3) * d2);
+    for (i, sin) in [sin_phi, -sin_phi, -sin_phi, sin_phi].into_iter().enumerate() {
+        let rp = na::Matrix3::new(
+            cos_phi, 0.0, sin,
+            0.0, -1.0, 0.0,
+            sin, 0.0, -cos_phi,
+        );
+        let tp = na::Vector3::new(x1[i], 0.0, x3[i]) * (d1 + d3);
+        candidates.push((s * u * rp * v_t, (u * tp).normalize()));
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dlt() {
+        let truth = na::Matrix3::new(
+            1.1, 0.05, 20.0,
+            -0.03, 0.95, -10.0,
+            1e-4, -2e-4, 1.0,
+        );
+        let points1 = [(10.0, 20.0), (600.0, 40.0), (580.0, 450.0), (30.0, 400.0), (300.0, 250.0)]
+            .map(|(x, y)| na::Point2::new(x, y));
+        let points2 = points1.map(|p| na::Point2::from_homogeneous(truth * p.to_homogeneous()).unwrap());
+
+        for n in [4, 5] {
+            let h = dlt(&points1[..n], &points2[..n]).unwrap();
+            assert!((h - truth).norm() < 1e-8, "n {}: {}", n, h);
+        }
+    }
+
+    #[test]
+    fn test_decompose() {
+        let rotation = na::Rotation3::from_euler_angles(0.05, -0.1, 0.02);
+        let t = na::Vector3::new(0.3, -0.1, 0.05);
+        // plane n^T * x = d in the first camera
+        let (n, d) = (na::Vector3::new(0.1, -0.2, 1.0).normalize(), 5.0);
+        let h = rotation.matrix() + t * n.transpose() / d;
+
+        for scale in [1.0, -2.5] {
+            let candidates = decompose(&(h * scale));
+            assert_eq!(candidates.len(), 8);
+            for (r, _) in candidates.iter() {
+                assert!((r.determinant() - 1.0).abs() < 1e-9);
+            }
+            assert!(candidates.iter().any(|(r, t_unit)| {
+                (r - rotation.matrix()).norm() < 1e-9 && (t_unit - t.normalize()).norm() < 1e-9
+            }), "scale {}", scale);
+        }
+        assert!(decompose(rotation.matrix()).is_empty());
+    }
+}
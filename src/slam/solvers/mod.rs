@@ -0,0 +1,125 @@
+//! Minimal and linear solvers for two-view and absolute pose geometry, implemented on
+//! nalgebra only.
+//!
+//! Conventions follow the rest of the pipeline: an essential or fundamental matrix
+//! satisfies `x2^T * E * x1 = 0`, and poses map world (or first camera) points into the
+//! camera, `p_c = R * p_w + t`. Calibrated solvers take bearing vectors, i.e. homogeneous
+//! normalized image coordinates `K^{-1} * [u, v, 1]^T` of any positive scale.
+pub mod eight_point;
+pub mod homography;
+pub mod five_point;
+pub mod p3p;
+pub mod epnp;
+
+use nalgebra as na;
+
+/// Real roots of the polynomial `coeffs[0] + coeffs[1] * x + ... + coeffs[n] * x^n`,
+/// computed as the eigenvalues of its companion matrix.
+fn real_roots(coeffs: &[f64]) -> Vec<f64> {
+    let mut coeffs = coeffs.to_vec();
+    while coeffs.len() > 1 && coeffs.last().unwrap().abs() < 1e-14 {
+        coeffs.pop();
+    }
+    let degree = coeffs.len() - 1;
+    if degree == 0 {
+        return Vec::new();
+    }
+    let leading = coeffs[degree];
+
+    let mut companion = na::DMatrix::<f64>::zeros(degree, degree);
+    for i in 1..degree {
+        companion[(i, i - 1)] = 1.0;
+    }
+    for i in 0..degree {
+        companion[(i, degree - 1)] = -coeffs[i] / leading;
+    }
+
+    companion.complex_eigenvalues()
+        .iter()
+        .filter(|root| root.im.abs() < 1e-8 * (1.0 + root.re.abs()))
+        .map(|root| root.re)
+        .collect()
+}
+
+/// Nullspace basis of `a`, as the right singular vectors of its `dim` smallest singular values.
+fn nullspace(a: &na::DMatrix<f64>, dim: usize) -> Vec<na::DVector<f64>> {
+    let cols = a.ncols();
+    // pad wide systems with zero rows, the thin SVD would drop the nullspace otherwise
+    let mut square = na::DMatrix::<f64>::zeros(a.nrows().max(cols), cols);
+    square.rows_mut(0, a.nrows()).copy_from(a);
+    let svd = square.svd(false, true);
+    let v_t = svd.v_t.unwrap();
+    let mut order = (0..cols).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| svd.singular_values[i].partial_cmp(&svd.singular_values[j]).unwrap());
+    order.into_iter()
+        .take(dim)
+        .map(|i| v_t.row(i).transpose())
+        .collect()
+}
+
+/// Rigid transform `T` minimizing `sum |dst_i - T * src_i|^2` (Kabsch), `None` for fewer
+/// than three points.
+pub fn rigid_alignment(src: &[na::Vector3<f64>], dst: &[na::Vector3<f64>]) -> Option<na::Isometry3<f64>> {
+    if src.len() < 3 || src.len() != dst.len() {
+        return None;
+    }
+    let n = src.len() as f64;
+    let src_mean = src.iter().sum::<na::Vector3<f64>>() / n;
+    let dst_mean = dst.iter().sum::<na::Vector3<f64>>() / n;
+    let mut cov = na::Matrix3::<f64>::zeros();
+    for (s, d) in src.iter().zip(dst.iter()) {
+        cov += (d - dst_mean) * (s - src_mean).transpose();
+    }
+
+    let svd = cov.svd(true, true);
+    let u = svd.u?;
+    let v_t = svd.v_t?;
+    let mut d = na::Matrix3::<f64>::identity();
+    if (u * v_t).determinant() < 0.0 {
+        d[(2, 2)] = -1.0;
+    }
+    let r = u * d * v_t;
+    let t = dst_mean - r * src_mean;
+    let rotation = na::UnitQuaternion::from_matrix(&r);
+    Some(na::Isometry3::from_parts(na::Translation3::from(t), rotation))
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use nalgebra as na;
+
+    /// Deterministic pseudo random numbers in [0, 1).
+    pub struct Lcg(u64);
+
+    impl Lcg {
+        pub fn new(seed: u64) -> Self {
+            Self(seed)
+        }
+
+        pub fn next(&mut self) -> f64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
+
+        pub fn range(&mut self, lo: f64, hi: f64) -> f64 {
+            lo + (hi - lo) * self.next()
+        }
+    }
+
+    /// A pose and `n` world points in front of both the identity camera and the posed camera.
+    pub fn scene(n: usize, seed: u64) -> (na::Isometry3<f64>, Vec<na::Vector3<f64>>) {
+        let mut rng = Lcg::new(seed);
+        let pose = na::Isometry3::new(
+            na::Vector3::new(rng.range(-0.5, 0.5), rng.range(-0.2, 0.2), rng.range(-0.2, 0.2)),
+            na::Vector3::new(rng.range(-0.1, 0.1), rng.range(-0.1, 0.1), rng.range(-0.1, 0.1)),
+        );
+        let points = (0..n)
+            .map(|_| na::Vector3::new(rng.range(-2.0, 2.0), rng.range(-2.0, 2.0), rng.range(4.0, 8.0)))
+            .collect();
+        (pose, points)
+    }
+
+    pub fn bearing(pose: &na::Isometry3<f64>, point: &na::Vector3<f64>) -> na::Vector3<f64> {
+        (pose * na::Point3::from(*point)).coords.normalize()
+    }
+}
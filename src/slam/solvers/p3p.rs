@@ -0,0 +1,91 @@
+use nalgebra as na;
+
+use super::{real_roots, rigid_alignment};
+
+/// Grunert's P3P solution as presented by Haralick et al. (1994): up to four world to
+/// camera poses explaining three world points seen along three bearings.
+///
+/// The distances `s_i` of the points along their bearings satisfy the law of cosines
+/// for every pair; substituting `s_2 = u * s_1`, `s_3 = v * s_1` leads to a quartic in `v`.
+/// Each positive solution is turned into a pose by aligning the world points with the
+/// camera points `s_i * f_i`.
+pub fn solve(points: &[na::Vector3<f64>], bearings: &[na::Vector3<f64>]) -> Vec<na::Isometry3<f64>> {
+    if points.len() < 3 || bearings.len() < 3 {
+        return Vec::new();
+    }
+    let (p1, p2, p3) = (points[0], points[1], points[2]);
+    let (f1, f2, f3) = (bearings[0].normalize(), bearings[1].normalize(), bearings[2].normalize());
+
+    let a2 = (p2 - p3).norm_squared();
+    let b2 = (p1 - p3).norm_squared();
+    let c2 = (p1 - p2).norm_squared();
+    if a2 < f64::EPSILON || b2 < f64::EPSILON || c2 < f64::EPSILON {
+        return Vec::new();
+    }
+    let cos_alpha = f2.dot(&f3);
+    let cos_beta = f1.dot(&f3);
+    let cos_gamma = f1.dot(&f2);
+
+    let a_c = (a2 - c2) / b2;
+    let a_p_c = (a2 + c2) / b2;
+    let b_c = (b2 - c2) / b2;
+    let b_a = (b2 - a2) / b2;
+
+    let a4 = (a_c - 1.0).powi(2) - 4.0 * c2 / b2 * cos_alpha.powi(2);
+    let a3 = 4.0 * (a_c * (1.0 - a_c) * cos_beta
+        - (1.0 - a_p_c) * cos_alpha * cos_gamma
+        + 2.0 * c2 / b2 * cos_alpha.powi(2) * cos_beta);
+    let a2_coeff = 2.0 * (a_c.powi(2) - 1.0
+        + 2.0 * a_c.powi(2) * cos_beta.powi(2)
+        + 2.0 * b_c * cos_alpha.powi(2)
+        - 4.0 * a_p_c * cos_alpha * cos_beta * cos_gamma
+        + 2.0 * b_a * cos_gamma.powi(2));
+    let a1 = 4.0 * (-a_c * (1.0 + a_c) * cos_beta
+        + 2.0 * a2 / b2 * cos_gamma.powi(2) * cos_beta
+        - (1.0 - a_p_c) * cos_alpha * cos_gamma);
+    let a0 = (1.0 + a_c).powi(2) - 4.0 * a2 / b2 * cos_gamma.powi(2);
+
+    let mut poses = Vec::new();
+    for v in real_roots(&[a0, a1, a2_coeff, a3, a4]) {
+        if v <= 0.0 {
+            continue;
+        }
+        let denominator = 2.0 * (cos_gamma - v * cos_alpha);
+        if denominator.abs() < 1e-12 {
+            continue;
+        }
+        let u = ((a_c - 1.0) * v * v - 2.0 * a_c * cos_beta * v + 1.0 + a_c) / denominator;
+        if u <= 0.0 {
+            continue;
+        }
+        let s1_squared = c2 / (1.0 + u * u - 2.0 * u * cos_gamma);
+        if s1_squared <= 0.0 {
+            continue;
+        }
+        let s1 = s1_squared.sqrt();
+        let camera_points = [f1 * s1, f2 * (u * s1), f3 * (v * s1)];
+        if let Some(pose) = rigid_alignment(&points[..3], &camera_points) {
+            poses.push(pose);
+        }
+    }
+    poses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_utils::{bearing, scene};
+
+    #[test]
+    fn test_p3p() {
+        for seed in 0..10 {
+            let (pose, points) = scene(3, seed);
+            let bearings = points.iter().map(|p| bearing(&pose, p)).collect::<Vec<_>>();
+            let solutions = solve(&points, &bearings);
+            let error = solutions.iter()
+                .map(|s| (s.to_homogeneous() - pose.to_homogeneous()).norm())
+                .fold(f64::MAX, f64::min);
+            assert!(error < 1e-6, "seed {}: error {}", seed, error);
+        }
+    }
+}
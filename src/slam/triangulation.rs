@@ -0,0 +1,199 @@
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+use super::camera::CameraIntrinsics;
+use super::lie;
+
+/// How a point is triangulated from two views.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriangulationMethod {
+    /// linear least squares on the projection equations
+    Dlt,
+    /// midpoint of the shortest segment between the two rays
+    Midpoint,
+    /// minimizes the image distance to an exact correspondence (Lindstrom's niter2,
+    /// equivalent to Hartley-Sturm), then intersects the corrected rays
+    Optimal,
+}
+
+/// A triangulated point and how well it is conditioned.
+#[derive(Clone, Debug)]
+pub struct Triangulation {
+    pub point: na::Point3<f64>,
+    /// depths in the first and second camera
+    pub depth1: f64,
+    pub depth2: f64,
+    /// reprojection errors in pixels in the first and second image
+    pub reprojection_error1: f64,
+    pub reprojection_error2: f64,
+    /// angle in degrees between the two viewing rays
+    pub parallax_deg: f64,
+    /// first order standard deviation of `depth1` relative to `depth1`, for `pixel_sigma`
+    /// pixels of noise on the observations
+    pub relative_depth_sigma: f64,
+}
+
+/// Triangulates the pixel observations `x1`, `x2` of cameras with world to camera poses
+/// `pose1`, `pose2`. Returns `None` for parallel rays.
+pub fn triangulate(
+    method: TriangulationMethod,
+    pose1: &na::Isometry3<f64>,
+    pose2: &na::Isometry3<f64>,
+    x1: &na::Point2<f64>,
+    x2: &na::Point2<f64>,
+    intrinsics: &CameraIntrinsics,
+    pixel_sigma: f64,
+) -> Option<Triangulation> {
+    let n1 = intrinsics.inv_projection(x1).to_homogeneous();
+    let n2 = intrinsics.inv_projection(x2).to_homogeneous();
+    // work in the first camera frame, T_21 maps it into the second
+    let relative = pose2 * pose1.inverse();
+
+    let point_c1 = match method {
+        TriangulationMethod::Dlt => dlt(&relative, &n1, &n2)?,
+        TriangulationMethod::Midpoint => midpoint(&relative, &n1, &n2)?,
+        TriangulationMethod::Optimal => {
+            let (n1, n2) = optimal_correction(&relative, &n1, &n2);
+            midpoint(&relative, &n1, &n2)?
+        },
+    };
+    let point_c2 = relative * point_c1;
+
+    let reprojection_error = |p: &na::Point3<f64>, x: &na::Point2<f64>| {
+        (intrinsics.projection(&p.coords) - x).norm()
+    };
+    let ray1 = point_c1.coords;
+    let ray2 = point_c1.coords - relative.inverse().translation.vector;
+    let cos_parallax = (ray1.dot(&ray2) / (ray1.norm() * ray2.norm())).clamp(-1.0, 1.0);
+    let parallax = cos_parallax.acos();
+    // the angular error of a ray is pixel_sigma / f, by the law of sines it moves the
+    // depth along the other ray by about depth * angular_error / sin(parallax)
+    let angular_sigma = pixel_sigma / intrinsics.fx.min(intrinsics.fy);
+
+    Some(Triangulation {
+        point: pose1.inverse() * point_c1,
+        depth1: point_c1.z,
+        depth2: point_c2.z,
+        reprojection_error1: reprojection_error(&point_c1, x1),
+        reprojection_error2: reprojection_error(&point_c2, x2),
+        parallax_deg: parallax.to_degrees(),
+        relative_depth_sigma: angular_sigma / parallax.sin().max(f64::EPSILON),
+    })
+}
+
+/// DLT in normalized coordinates, the first camera is `[I | 0]`.
+fn dlt(relative: &na::Isometry3<f64>, n1: &na::Vector3<f64>, n2: &na::Vector3<f64>) -> Option<na::Point3<f64>> {
+    let p1 = na::Matrix3x4::<f64>::identity();
+    let p2 = relative.to_homogeneous().fixed_view::<3, 4>(0, 0).into_owned();
+    let design = na::Matrix4::<f64>::from_rows(&[
+        n1.x * p1.row(2) - p1.row(0),
+        n1.y * p1.row(2) - p1.row(1),
+        n2.x * p2.row(2) - p2.row(0),
+        n2.y * p2.row(2) - p2.row(1),
+    ]);
+    let svd = design.svd(false, true);
+    let v_t = svd.v_t?;
+    let p = v_t.row(svd.singular_values.imin()).transpose();
+    if p[3].abs() < f64::EPSILON {
+        return None;
+    }
+    Some(na::Point3::new(p[0] / p[3], p[1] / p[3], p[2] / p[3]))
+}
+
+/// Midpoint of the common perpendicular of the rays through `n1` from the first camera
+/// and through `n2` from the second, in the first camera frame.
+fn midpoint(relative: &na::Isometry3<f64>, n1: &na::Vector3<f64>, n2: &na::Vector3<f64>) -> Option<na::Point3<f64>> {
+    let inverse = relative.inverse();
+    let c2 = inverse.translation.vector;
+    let d1 = *n1;
+    let d2 = inverse.rotation * n2;
+
+    // minimize |s * d1 - (c2 + u * d2)|^2
+    let a = d1.dot(&d1);
+    let b = d1.dot(&d2);
+    let c = d2.dot(&d2);
+    let denominator = a * c - b * b;
+    if denominator.abs() < 1e-12 * a * c {
+        return None;
+    }
+    let s = (c * d1.dot(&c2) - b * d2.dot(&c2)) / denominator;
+    let u = (b * d1.dot(&c2) - a * d2.dot(&c2)) / denominator;
+    Some(na::Point3::from((d1 * s + c2 + d2 * u) / 2.0))
+}
+
+/// Lindstrom's niter2: moves the normalized observations the least so that they satisfy
+/// the epipolar constraint `n2^T * E * n1 = 0` exactly.
+fn optimal_correction(
+    relative: &na::Isometry3<f64>,
+    n1: &na::Vector3<f64>,
+    n2: &na::Vector3<f64>,
+) -> (na::Vector3<f64>, na::Vector3<f64>) {
+    let e = lie::skew(&relative.translation.vector) * relative.rotation.to_rotation_matrix().matrix();
+    let e_tilde = e.fixed_view::<2, 2>(0, 0).into_owned();
+
+    // Lindstrom writes the constraint as x^T * E * x' = 0, so x = n2 and x' = n1
+    let mut n = (e * n1).xy();
+    let mut n_prime = (e.transpose() * n2).xy();
+    let a = n.dot(&(e_tilde * n_prime));
+    let b = 0.5 * (n.norm_squared() + n_prime.norm_squared());
+    let c = n2.dot(&(e * n1));
+    let d = (b * b - a * c).max(0.0).sqrt();
+    if b + d <= f64::EPSILON {
+        return (*n1, *n2);
+    }
+    let mut lambda = c / (b + d);
+    let delta = n * lambda;
+    let delta_prime = n_prime * lambda;
+    n -= e_tilde * delta_prime;
+    n_prime -= e_tilde.transpose() * delta;
+    let norm = n.norm_squared() + n_prime.norm_squared();
+    if norm > f64::EPSILON {
+        lambda *= 2.0 * d / norm;
+    }
+    let delta = n * lambda;
+    let delta_prime = n_prime * lambda;
+
+    (
+        na::Vector3::new(n1.x - delta_prime.x, n1.y - delta_prime.y, 1.0),
+        na::Vector3::new(n2.x - delta.x, n2.y - delta.y, 1.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::camera::Distortion;
+
+    #[test]
+    fn test_methods() {
+        let intrinsics = CameraIntrinsics::new([458.0, 457.0, 367.0, 248.0], Distortion::default());
+        let pose1 = na::Isometry3::new(na::Vector3::new(0.1, -0.2, 0.3), na::Vector3::new(0.02, 0.1, -0.05));
+        let pose2 = na::Isometry3::new(na::Vector3::new(-0.4, -0.1, 0.25), na::Vector3::new(0.0, 0.05, 0.02));
+        let point = na::Point3::new(0.5, 0.3, 5.0);
+        let project = |pose: &na::Isometry3<f64>| intrinsics.projection(&(pose * point).coords);
+        let x1 = project(&pose1);
+        let x2 = project(&pose2);
+        let noisy2 = x2 + na::Vector2::new(0.8, -0.6);
+
+        for method in [TriangulationMethod::Dlt, TriangulationMethod::Midpoint, TriangulationMethod::Optimal] {
+            let exact = triangulate(method, &pose1, &pose2, &x1, &x2, &intrinsics, 1.0).unwrap();
+            assert!((exact.point - point).norm() < 1e-8, "{:?}", method);
+            assert!(exact.reprojection_error1 < 1e-6 && exact.reprojection_error2 < 1e-6);
+            assert!((exact.depth1 - (pose1 * point).z).abs() < 1e-8);
+            assert!(exact.parallax_deg > 1.0 && exact.relative_depth_sigma < 0.2);
+
+            let noisy = triangulate(method, &pose1, &pose2, &x1, &noisy2, &intrinsics, 1.0).unwrap();
+            assert!(noisy.reprojection_error1 + noisy.reprojection_error2 > 0.1);
+        }
+
+        // the optimal method minimizes the total squared image error
+        let total_error = |method| {
+            let t = triangulate(method, &pose1, &pose2, &x1, &noisy2, &intrinsics, 1.0).unwrap();
+            t.reprojection_error1.powi(2) + t.reprojection_error2.powi(2)
+        };
+        let optimal = total_error(TriangulationMethod::Optimal);
+        assert!(optimal <= total_error(TriangulationMethod::Dlt) + 1e-6);
+        assert!(optimal <= total_error(TriangulationMethod::Midpoint) + 1e-6);
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time;
+
+use nalgebra as na;
+use opencv::{
+    prelude::*,
+    core,
+    imgcodecs,
+};
+
+use crate::protocol::{codec, CameraImage, CameraInfo, EdgeKind, GraphEdge, ImageKeypoint, KeyFramePose, Message, PointUpdate};
+use super::frame::Frame;
+use super::map::{Map, keyframe::KeyFrameId, mappoint::{MapPoint, MapPointId}};
+use super::output::WorldScale;
+
+/// Default address of the `frontend` server.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9123";
+
+/// Points per `PointBatch` message, well below the protocol's payload limit.
+const POINTS_PER_BATCH: usize = 10_000;
+
+/// Covisibility edges are sent for keyframes sharing at least this many map points,
+/// unless `VisualizerClient::min_covisibility` is changed.
+const MIN_COVISIBILITY: usize = 15;
+
+/// A point is sent again once it moved by more than this, in map units.
+const POINT_EPSILON: f64 = 1e-6;
+
+/// Label of the tracked frame among the images.
+const CAMERA_LABEL: &str = "camera";
+const JPEG_QUALITY: i32 = 80;
+
+/// Streams the tracked pose, keyframes and map points to the `frontend` visualizer.
+///
+/// Only what changed since the last call is sent. The client never fails the pipeline:
+/// if the visualizer is not running or the connection drops, it retries every
+/// `retry_interval` and resends the whole map once it is back.
+pub struct VisualizerClient {
+    address: SocketAddr,
+    stream: Option<TcpStream>,
+    last_attempt: Option<time::Instant>,
+    pub retry_interval: time::Duration,
+    pub min_covisibility: usize,
+    scale: WorldScale,
+    /// whether the visualizer still shows what an earlier connection or run sent
+    needs_reset: bool,
+    sent_camera: bool,
+    /// timestamp of the last tracked frame sent
+    sent_frame: Option<time::Duration>,
+    sent_keyframes: HashMap<KeyFrameId, na::Isometry3<f64>>,
+    /// sorted ids of the observed map points and parent of every keyframe, the graph is
+    /// sent again when any of them changes
+    sent_structure: HashMap<KeyFrameId, (Vec<u32>, Option<KeyFrameId>)>,
+    sent_points: HashMap<MapPointId, (na::Vector3<f64>, usize)>,
+}
+
+impl VisualizerClient {
+    pub fn new(address: &str, scale: WorldScale) -> Result<Self, Box<dyn Error>> {
+        let address = address.to_socket_addrs()?
+            .next()
+            .ok_or(format!("cannot resolve {}", address))?;
+        Ok(Self {
+            address,
+            stream: None,
+            last_attempt: None,
+            retry_interval: time::Duration::from_secs(1),
+            min_covisibility: MIN_COVISIBILITY,
+            scale,
+            needs_reset: true,
+            sent_camera: false,
+            sent_frame: None,
+            sent_keyframes: HashMap::new(),
+            sent_structure: HashMap::new(),
+            sent_points: HashMap::new(),
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Sends the current world to camera `pose` and the keyframes and map points of `map`
+    /// that changed since the last call.
+    pub fn publish(&mut self, pose: Option<&na::Isometry3<f64>>, map: &Map) {
+        if !self.connect() {
+            return;
+        }
+
+        let mut messages = Vec::new();
+        if let Some(message) = self.camera_message(map) {
+            messages.push(message);
+        }
+        if let Some(pose) = pose {
+            messages.push(self.pose_message(pose));
+        }
+        if let Some(message) = self.keyframe_message(map) {
+            messages.push(message);
+        }
+        messages.extend(self.point_messages(map));
+        messages.extend(self.graph_messages(map));
+        self.send(messages);
+    }
+
+    /// Sends the image of the tracked `frame` with the status of its keypoints, unless it
+    /// was already sent.
+    pub fn publish_frame(&mut self, frame: &Frame) {
+        if frame.img.empty() || self.sent_frame == Some(frame.timestamp) {
+            return;
+        }
+        let keypoints = frame.keypoints.iter()
+            .zip(frame.status.iter())
+            .map(|(keypoint, status)| ImageKeypoint {
+                position: [keypoint.pt().x, keypoint.pt().y],
+                status: *status,
+            })
+            .collect();
+        if self.publish_image(CAMERA_LABEL, &frame.img, keypoints) {
+            self.sent_frame = Some(frame.timestamp);
+        }
+    }
+
+    /// Sends an image, e.g. a debug image of the pipeline, shown under `label`. Returns
+    /// whether it was sent.
+    pub fn publish_image(&mut self, label: &str, img: &core::Mat, keypoints: Vec<ImageKeypoint>) -> bool {
+        if !self.connect() {
+            return false;
+        }
+        let jpeg = match encode_jpeg(img) {
+            Ok(jpeg) => jpeg,
+            Err(e) => {
+                println!("encoding the {} image failed: {}", label, e);
+                return false;
+            },
+        };
+        let label = label.to_string();
+        self.send(vec![Message::Image(CameraImage { label, keypoints, jpeg })]);
+        self.is_connected()
+    }
+
+    /// Writes `messages`, after a reset if this is the first write of a connection.
+    fn send(&mut self, mut messages: Vec<Message>) {
+        if self.needs_reset {
+            messages.insert(0, Message::Reset);
+            self.needs_reset = false;
+        }
+        let mut bytes = Vec::new();
+        for message in messages.iter() {
+            if let Err(e) = codec::encode(message, &mut bytes) {
+                println!("dropping {:?} message: {}", message.kind(), e);
+            }
+        }
+        let result = self.stream.as_mut().map(|stream| stream.write_all(&bytes));
+        if let Some(Err(e)) = result {
+            println!("visualizer connection lost: {}", e);
+            self.disconnect();
+        }
+    }
+
+    /// Returns whether there is a connection, trying to open one at most every
+    /// `retry_interval`.
+    fn connect(&mut self) -> bool {
+        if self.stream.is_some() {
+            return true;
+        }
+        let now = time::Instant::now();
+        if self.last_attempt.is_some_and(|last| now - last < self.retry_interval) {
+            return false;
+        }
+        self.last_attempt = Some(now);
+
+        match TcpStream::connect_timeout(&self.address, time::Duration::from_millis(100)) {
+            Ok(stream) => {
+                let _ = stream.set_nodelay(true);
+                // a stalled visualizer drops the connection instead of stalling tracking
+                let _ = stream.set_write_timeout(Some(time::Duration::from_secs(1)));
+                println!("connected to visualizer at {}", self.address);
+                self.stream = Some(stream);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Clears the visualizer with the next `publish`, e.g. when tracking restarts with a
+    /// new map, and sends everything again.
+    pub fn reset(&mut self) {
+        self.needs_reset = true;
+        self.sent_camera = false;
+        self.sent_frame = None;
+        self.sent_keyframes.clear();
+        self.sent_structure.clear();
+        self.sent_points.clear();
+    }
+
+    /// A new connection may be a restarted visualizer, so it starts from scratch.
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.reset();
+    }
+
+    /// The camera model, once per connection, taken from the first keyframe since the
+    /// image size is only known from its image.
+    fn camera_message(&mut self, map: &Map) -> Option<Message> {
+        if self.sent_camera {
+            return None;
+        }
+        let kf = map.keyframes.values().next()?;
+        let intrinsics = &kf.intrinsics;
+        self.sent_camera = true;
+        Some(Message::CameraInfo(CameraInfo {
+            width: kf.img.cols() as u32,
+            height: kf.img.rows() as u32,
+            fx: intrinsics.fx as f32,
+            fy: intrinsics.fy as f32,
+            cx: intrinsics.cx as f32,
+            cy: intrinsics.cy as f32,
+        }))
+    }
+
+    /// Camera to world pose of the body.
+    fn pose_message(&self, pose: &na::Isometry3<f64>) -> Message {
+        let t_wc = self.scale.pose(pose).inverse();
+        Message::BodyTransform {
+            id: 0,
+            relative: false,
+            rotation: quaternion(&t_wc.rotation),
+            translation: vector(&t_wc.translation.vector),
+        }
+    }
+
+    fn keyframe_message(&mut self, map: &Map) -> Option<Message> {
+        let mut poses = Vec::new();
+        for kf in map.keyframes.values() {
+            if self.sent_keyframes.get(&kf.id) == Some(&kf.pose) {
+                continue;
+            }
+            self.sent_keyframes.insert(kf.id, kf.pose);
+            let t_wc = self.scale.pose(&kf.pose).inverse();
+            poses.push(KeyFramePose {
+                id: kf.id as u32,
+                rotation: quaternion(&t_wc.rotation),
+                translation: vector(&t_wc.translation.vector),
+            });
+        }
+        (!poses.is_empty()).then_some(Message::KeyFramePoseBatch(poses))
+    }
+
+    fn point_messages(&mut self, map: &Map) -> Vec<Message> {
+        let mut points = Vec::new();
+        for mp in map.mappoints.values() {
+            let mp = mp.borrow();
+            let observations = mp.references.len();
+            let unchanged = self.sent_points.get(&mp.id).is_some_and(|(position, count)| {
+                (position - mp.position).norm() <= POINT_EPSILON && *count == observations
+            });
+            if unchanged {
+                continue;
+            }
+            self.sent_points.insert(mp.id, (mp.position, observations));
+            points.push(PointUpdate {
+                id: mp.id as u32,
+                position: vector(&self.scale.point(&mp.position)),
+                color: point_color(map, &mp),
+                observations: observations as u32,
+                reprojection_error: reprojection_error(map, &mp) as f32,
+                first_keyframe: mp.references.iter().map(|reference| reference.id).min().unwrap_or(0) as u32,
+            });
+        }
+        let mut messages = points.chunks(POINTS_PER_BATCH)
+            .map(|chunk| Message::PointBatch(chunk.to_vec()))
+            .collect::<Vec<_>>();
+
+        // points culled or merged away since the last call
+        let mut removed = self.sent_points.keys()
+            .filter(|id| !map.mappoints.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>();
+        if !removed.is_empty() {
+            removed.sort_unstable();
+            for id in removed.iter() {
+                self.sent_points.remove(id);
+            }
+            messages.push(Message::PointRemove(removed.into_iter().map(|id| id as u32).collect()));
+        }
+        messages
+    }
+
+    /// The observations of every keyframe whose structure changed and, if any did, the
+    /// whole keyframe graph.
+    fn graph_messages(&mut self, map: &Map) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut changed = self.sent_structure.len() != map.keyframes.len();
+        for kf in map.keyframes.values() {
+            let mut points = kf.observations.iter().map(|mp| mp.borrow().id as u32).collect::<Vec<_>>();
+            points.sort_unstable();
+            let structure = (points, kf.parent);
+            if self.sent_structure.get(&kf.id) == Some(&structure) {
+                continue;
+            }
+            messages.push(Message::Observations {
+                keyframe: kf.id as u32,
+                points: structure.0.clone(),
+            });
+            self.sent_structure.insert(kf.id, structure);
+            changed = true;
+        }
+        if !changed {
+            return messages;
+        }
+        self.sent_structure.retain(|id, _| map.keyframes.contains_key(id));
+
+        let mut edges = Vec::new();
+        for kf in map.keyframes.values() {
+            let edge = |to: KeyFrameId, kind: EdgeKind, weight: usize| GraphEdge {
+                from: kf.id as u32,
+                to: to as u32,
+                kind,
+                weight: weight as u32,
+            };
+            if let Some(parent) = kf.parent {
+                edges.push(edge(parent, EdgeKind::SpanningTree, 0));
+            }
+            edges.extend(map.covisibility(kf.id).into_iter()
+                .filter(|(other, shared)| *other > kf.id && *shared >= self.min_covisibility)
+                .map(|(other, shared)| edge(other, EdgeKind::Covisibility, shared)));
+        }
+        messages.push(Message::KeyFrameGraph(edges));
+        messages
+    }
+}
+
+/// Gray value of the point in the first keyframe observing it.
+fn point_color(map: &Map, mp: &MapPoint) -> [u8; 3] {
+    let gray = mp.references.first()
+        .and_then(|reference| {
+            let kf = map.keyframe(reference.id)?;
+            let pt = reference.keypoint.pt();
+            kf.img.at_2d::<u8>(pt.y.round() as i32, pt.x.round() as i32).ok().cloned()
+        })
+        .unwrap_or(128);
+    [gray; 3]
+}
+
+/// Mean distance in pixels between the projection of the point and its keypoints.
+fn reprojection_error(map: &Map, mp: &MapPoint) -> f64 {
+    let errors = mp.references.iter()
+        .filter_map(|reference| {
+            let kf = map.keyframe(reference.id)?;
+            let p_c = kf.pose * na::Point3::from(mp.position);
+            let projected = kf.intrinsics.projection(&p_c.coords);
+            let pt = reference.keypoint.pt();
+            Some((projected - na::Point2::new(pt.x as f64, pt.y as f64)).norm())
+        })
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        return 0.0;
+    }
+    errors.iter().sum::<f64>() / errors.len() as f64
+}
+
+fn encode_jpeg(img: &core::Mat) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut jpeg = core::Vector::<u8>::new();
+    let params = core::Vector::<i32>::from_slice(&[imgcodecs::IMWRITE_JPEG_QUALITY, JPEG_QUALITY]);
+    imgcodecs::imencode(".jpg", img, &mut jpeg, &params)?;
+    Ok(jpeg.to_vec())
+}
+
+fn quaternion(q: &na::UnitQuaternion<f64>) -> [f32; 4] {
+    [q.i as f32, q.j as f32, q.k as f32, q.w as f32]
+}
+
+fn vector(v: &na::Vector3<f64>) -> [f32; 3] {
+    [v.x as f32, v.y as f32, v.z as f32]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use super::super::camera::{CameraIntrinsics, Distortion};
+    use super::super::extractor::DescriptorKind;
+    use super::super::map::{keyframe::KeyFrame, mappoint::MapPointReference};
+
+    fn keypoint(u: f32, v: f32) -> core::KeyPoint {
+        core::KeyPoint::new_point(core::Point2f::new(u, v), 31.0, -1.0, 0.0, 0, -1).unwrap()
+    }
+
+    /// A keyframe at the origin with a black image, except for the gray value 200 at (10, 20).
+    fn keyframe() -> KeyFrame {
+        let mut img = core::Mat::new_rows_cols_with_default(480, 752, core::CV_8UC1, core::Scalar::all(0.0)).unwrap();
+        *img.at_2d_mut::<u8>(20, 10).unwrap() = 200;
+        KeyFrame::new(
+            time::Duration::default(),
+            img,
+            core::Vector::new(),
+            core::Mat::default(),
+            DescriptorKind::Binary,
+            CameraIntrinsics::new([458.0, 457.0, 367.0, 248.0], Distortion::default()),
+            na::Isometry3::identity(),
+        )
+    }
+
+    fn mappoint(map: &mut Map, keyframe: KeyFrameId, position: na::Vector3<f64>, keypoint: core::KeyPoint) -> MapPointId {
+        let mut mp = MapPoint::new(position, core::Mat::default());
+        mp.add_reference(MapPointReference::new(keyframe, 0, &keypoint, &core::Mat::default()));
+        let id = mp.id;
+        map.insert_mappoint(Rc::new(RefCell::new(mp)));
+        id
+    }
+
+    /// Sorted ids of the updated and of the removed points.
+    fn point_ids(messages: &[Message]) -> (Vec<usize>, Vec<usize>) {
+        let mut updated = Vec::new();
+        let mut removed = Vec::new();
+        for message in messages {
+            match message {
+                Message::PointBatch(points) => updated.extend(points.iter().map(|point| point.id as usize)),
+                Message::PointRemove(ids) => removed.extend(ids.iter().map(|id| *id as usize)),
+                _ => {},
+            }
+        }
+        updated.sort_unstable();
+        removed.sort_unstable();
+        (updated, removed)
+    }
+
+    #[test]
+    fn test_point_color() {
+        let mut map = Map::new();
+        let kf = keyframe();
+        let kf_id = kf.id;
+        map.insert_keyframe(kf);
+        let seen = mappoint(&mut map, kf_id, na::Vector3::z(), keypoint(10.2, 19.8));
+        let outside = mappoint(&mut map, kf_id, na::Vector3::z(), keypoint(-5.0, 20.0));
+        let unknown_keyframe = mappoint(&mut map, kf_id + 1000, na::Vector3::z(), keypoint(10.0, 20.0));
+        let unobserved = MapPoint::new(na::Vector3::z(), core::Mat::default());
+
+        assert_eq!(point_color(&map, &map.mappoints[&seen].borrow()), [200; 3]);
+        // anything without a pixel to read falls back to mid gray
+        assert_eq!(point_color(&map, &map.mappoints[&outside].borrow()), [128; 3]);
+        assert_eq!(point_color(&map, &map.mappoints[&unknown_keyframe].borrow()), [128; 3]);
+        assert_eq!(point_color(&map, &unobserved), [128; 3]);
+    }
+
+    #[test]
+    fn test_change_detection() {
+        let mut map = Map::new();
+        let kf = keyframe();
+        let kf_id = kf.id;
+        map.insert_keyframe(kf);
+        let a = mappoint(&mut map, kf_id, na::Vector3::new(0.0, 0.0, 5.0), keypoint(10.0, 20.0));
+        let b = mappoint(&mut map, kf_id, na::Vector3::new(1.0, 0.0, 5.0), keypoint(100.0, 20.0));
+        let mut client = VisualizerClient::new(DEFAULT_ADDRESS, WorldScale(1.0)).unwrap();
+
+        let mut both = vec![a, b];
+        both.sort_unstable();
+        assert_eq!(point_ids(&client.point_messages(&map)), (both.clone(), vec![]));
+        assert!(client.point_messages(&map).is_empty());
+        assert!(client.keyframe_message(&map).is_some());
+        assert!(client.keyframe_message(&map).is_none());
+
+        // moves below POINT_EPSILON are not worth a message
+        map.mappoints[&a].borrow_mut().position.x += 1e-9;
+        assert!(client.point_messages(&map).is_empty());
+        map.mappoints[&a].borrow_mut().position.x += 1e-3;
+        assert_eq!(point_ids(&client.point_messages(&map)), (vec![a], vec![]));
+
+        // a new observation changes the point's statistics
+        map.mappoints[&b].borrow_mut().add_reference(MapPointReference::new(kf_id, 1, &keypoint(5.0, 5.0), &core::Mat::default()));
+        assert_eq!(point_ids(&client.point_messages(&map)), (vec![b], vec![]));
+
+        map.mappoints.remove(&a);
+        assert_eq!(point_ids(&client.point_messages(&map)), (vec![], vec![a]));
+        assert!(client.point_messages(&map).is_empty());
+
+        map.keyframes.get_mut(&kf_id).unwrap().pose.translation.vector.x = 0.5;
+        assert!(client.keyframe_message(&map).is_some());
+
+        client.reset();
+        assert_eq!(point_ids(&client.point_messages(&map)), (vec![b], vec![]));
+        assert!(client.keyframe_message(&map).is_some());
+    }
+}
@@ -1,11 +1,9 @@
-use std::io::Write;
-
 use nalgebra as na;
 
-use opencv::prelude::KeyPointTraitConst;
 use rosrust_msg::*;
 use rosrust_msg::sensor_msgs::PointField;
 
+mod protocol;
 mod slam;
 
 fn main() {
@@ -24,7 +22,9 @@ fn main() {
     let path = "/media/zhang/data/ubuntu_files/downloads/MH_01_easy/mav0/cam0";
     let data_set = slam::load_data::load_euroc_data(path).unwrap();
     let camera = slam::camera::CameraIntrinsics::new_euroc();
-    let mut tracker = slam::process_image::Tracker::new(camera).unwrap();
+    let config = slam::config::SlamConfig::default();
+    let mut tracker = slam::process_image::Tracker::new(camera, config.clone()).unwrap();
+    let scale = slam::output::WorldScale::new(&config.output);
     let mut data_iter = data_set.into_iter();
     let mut pose = na::Isometry3::<f64>::identity();
     let mut path_msg = nav_msgs::Path::default();
@@ -33,15 +33,9 @@ fn main() {
     // Breaks when a shutdown signal is sent
     while rosrust::is_ok() {
         if tracker.initializer.done() {
-            scene_save(&tracker.map, "scene.json");
-            let keyframes = 
-                tracker.map.keyframes.values().into_iter().map(|x| x.id).collect::<Vec<_>>();
-            let mappoints = 
-                tracker.map.mappoints.values().into_iter().map(|x| x.borrow().id).collect::<Vec<_>>();
-            
-            slam::optimize::optimize(
-                &mut tracker.map, &keyframes, &mappoints);
-            scene_save(&tracker.map, "scene_opt.json");
+            slam::output::save_map_json(&tracker.map, std::path::Path::new("scene.json"), scale).unwrap();
+            tracker.optimize_map();
+            slam::output::save_map_json(&tracker.map, std::path::Path::new("scene_opt.json"), scale).unwrap();
             break;
         }
         // Create string message
@@ -54,11 +48,12 @@ fn main() {
             },
         };
 
+        let scaled = scale.pose(&pose);
         let pose = geometry_msgs::Pose{
             position: geometry_msgs::Point {
-                x: pose.translation.x / 10.0,
-                y: pose.translation.y / 10.0,
-                z: pose.translation.z / 10.0,
+                x: scaled.translation.x,
+                y: scaled.translation.y,
+                z: scaled.translation.z,
             },
             orientation: geometry_msgs::Quaternion {
                 x: pose.rotation.i,
@@ -91,10 +86,11 @@ fn main() {
         if tracker.initializer.done() {
             let points = tracker.map.points();
             for point in points {
+                let position = scale.point(&point.as_ref().borrow().position);
                 let point = geometry_msgs::Point32 {
-                    x: point.as_ref().borrow().position.x as f32,
-                    y: point.as_ref().borrow().position.y as f32,
-                    z: point.as_ref().borrow().position.z as f32,
+                    x: position.x as f32,
+                    y: position.y as f32,
+                    z: position.z as f32,
                 };
                 point_cloud_msg.points.push(point);
             }
@@ -107,54 +103,3 @@ fn main() {
         count += 1;
     }
 }
-
-fn scene_save(map: &slam::map::Map, filename: &str) {
-    let mut file = std::fs::File::create(filename).unwrap();
-    let points = map.points();
-    let keyframes = &map.keyframes;
-    // for point in points.iter() {
-    //     let point = point.as_ref().borrow();
-    //     for reference in point.references.iter() {
-    //             if reference.id != 0 {
-    //                 continue;
-    //             }
-    //             let mut ps = na::Vector3::<f64>::new(point.position.x, point.position.y, point.position.z);
-    //             ps /= ps[2];
-    //             let pp = slam::camera::CameraIntrinsics::new_euroc().k_mat * ps;
-    //             println!("res: ({},{})", pp[0] as f32 - reference.keypoint.pt().x, pp[1] as f32 - reference.keypoint.pt().y);
-    //     }
-     
-    // }
-
-    // output to json
-    let mut json = String::new();
-    json.push_str("{\n");
-    json.push_str("\t\"points\": [\n");
-    for point in points.iter() {
-        let point = point.as_ref().borrow();
-        json.push_str(&format!("\t\t[{},{},{}],\n", point.position.x, point.position.y, point.position.z));
-    }
-    json.push_str("\t],\n");
-    json.push_str("\t\"keyframes\": [\n");
-    for kf in keyframes {
-        let kf = kf.1;
-        json.push_str("\t\t{\n");
-        json.push_str(&format!("\t\t\t\"pose\":{{\"x\": {}, \"y\": {}, \"z\": {}, \"q\": [{}, {}, {}, {}]}},\n", kf.pose.translation.x, kf.pose.translation.y, kf.pose.translation.z, kf.pose.rotation.i, kf.pose.rotation.j, kf.pose.rotation.k, kf.pose.rotation.w));
-
-        json.push_str("\t\t\t\"points\": [");
-        for point in points.iter() {
-            let point = point.as_ref().borrow();
-            for reference in point.references.iter() {
-                if reference.id != kf.id {
-                    continue;
-                }
-                json.push_str(&format!("[{},{}],", reference.keypoint.pt().x, reference.keypoint.pt().y));
-            }
-        }
-        json.push_str("]\n");
-        json.push_str("\t\t},\n");
-    }
-    json.push_str("\t]\n");
-    json.push_str("}\n");
-    file.write_all(json.as_bytes()).unwrap();
-}
\ No newline at end of file
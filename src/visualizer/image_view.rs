@@ -0,0 +1,114 @@
+//! The latest image of every label, the tracked camera image and the debug images, each
+//! in its own window with the keypoints drawn over it.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use super::message::{self, ImageKeypointMessage, KeypointStatus};
+
+/// Width the image windows open with, half a EuRoC image.
+const DEFAULT_WIDTH: f32 = 376.0;
+const KEYPOINT_RADIUS: f32 = 3.0;
+const MATCHED_COLOR: egui::Color32 = egui::Color32::from_rgb(0, 200, 0);
+const NEW_COLOR: egui::Color32 = egui::Color32::from_rgb(0, 120, 255);
+const OUTLIER_COLOR: egui::Color32 = egui::Color32::from_rgb(230, 0, 0);
+
+struct ImageView {
+    texture: egui::TextureHandle,
+    size: [usize; 2],
+    keypoints: Vec<ImageKeypointMessage>,
+}
+
+/// Images by label.
+#[derive(Resource, Default)]
+struct ImageViews(HashMap<String, ImageView>);
+
+pub struct ImageViewPlugin;
+impl Plugin for ImageViewPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<ImageViews>()
+            .add_system(receive_image_event.in_set(message::MessageSet::Receive))
+            .add_system(show_images.after(receive_image_event));
+    }
+}
+
+/// Decodes the latest image of every label received this frame, older ones are skipped.
+fn receive_image_event(
+    mut contexts: EguiContexts,
+    mut reset_event: EventReader<message::ResetEvent>,
+    mut image_event: EventReader<message::ImageEvent>,
+    mut views: ResMut<ImageViews>,
+) {
+    if reset_event.iter().count() > 0 {
+        views.0.clear();
+    }
+    let mut latest = HashMap::new();
+    for event in image_event.iter() {
+        latest.insert(&event.0.label, &event.0);
+    }
+
+    for (label, image) in latest {
+        let decoded = match image::load_from_memory_with_format(&image.jpeg, image::ImageFormat::Jpeg) {
+            Ok(decoded) => decoded.to_rgba8(),
+            Err(e) => {
+                eprintln!("Dropping {} image: {}", label, e);
+                continue;
+            },
+        };
+        let size = [decoded.width() as usize, decoded.height() as usize];
+        let pixels = egui::ColorImage::from_rgba_unmultiplied(size, decoded.as_raw());
+        match views.0.get_mut(label) {
+            Some(view) => {
+                view.texture.set(pixels, egui::TextureOptions::LINEAR);
+                view.size = size;
+                view.keypoints = image.keypoints.clone();
+            },
+            None => {
+                let texture = contexts.ctx_mut().load_texture(label, pixels, egui::TextureOptions::LINEAR);
+                views.0.insert(label.clone(), ImageView { texture, size, keypoints: image.keypoints.clone() });
+            },
+        }
+    }
+}
+
+fn show_images(
+    mut contexts: EguiContexts,
+    views: Res<ImageViews>,
+) {
+    let ctx = contexts.ctx_mut();
+    let mut labels = views.0.keys().collect::<Vec<_>>();
+    labels.sort();
+    for label in labels {
+        let view = &views.0[label];
+        egui::Window::new(label.as_str())
+            .default_width(DEFAULT_WIDTH)
+            .resizable(true)
+            .show(ctx, |ui| {
+                let scale = ui.available_width() / view.size[0] as f32;
+                let size = egui::vec2(view.size[0] as f32, view.size[1] as f32) * scale;
+                let rect = ui.image(view.texture.id(), size).rect;
+                let painter = ui.painter_at(rect);
+                for keypoint in view.keypoints.iter() {
+                    let color = match keypoint.status {
+                        KeypointStatus::Matched => MATCHED_COLOR,
+                        KeypointStatus::New => NEW_COLOR,
+                        KeypointStatus::Outlier => OUTLIER_COLOR,
+                    };
+                    let center = rect.min + egui::vec2(keypoint.position.x, keypoint.position.y) * scale;
+                    painter.circle_stroke(center, KEYPOINT_RADIUS, egui::Stroke::new(1.0, color));
+                }
+                if !view.keypoints.is_empty() {
+                    let count = |status| view.keypoints.iter().filter(|k| k.status == status).count();
+                    ui.label(format!(
+                        "{} matched, {} new, {} outliers",
+                        count(KeypointStatus::Matched),
+                        count(KeypointStatus::New),
+                        count(KeypointStatus::Outlier),
+                    ));
+                }
+            });
+    }
+}
@@ -1,87 +1,241 @@
-use std::{error::Error};
-
 use bevy::{prelude::*};
 
+use crate::protocol::Message;
+pub use crate::protocol::{EdgeKind, KeypointStatus};
 use super::server;
 
+/// Camera to world transform of a body, or the motion on top of its current one if
+/// `relative`.
 #[derive(Debug)]
 pub struct BodyTransformMessage {
     pub id: usize,
+    pub relative: bool,
     pub transform: Transform,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct PointMessage {
     pub id: usize,
     pub position: Vec3,
+    pub color: Color,
+    /// number of keyframes observing the point
+    pub observations: u32,
+    /// mean reprojection error, in pixels
+    pub reprojection_error: f32,
+    /// oldest keyframe observing the point
+    pub first_keyframe: usize,
+}
+
+/// Camera to world transform of a keyframe.
+#[derive(Debug)]
+pub struct KeyFramePoseMessage {
+    pub id: usize,
+    pub transform: Transform,
+}
+
+/// Pinhole model of the tracked camera.
+#[derive(Clone, Debug)]
+pub struct CameraInfoMessage {
+    pub width: u32,
+    pub height: u32,
+    pub fx: f32,
+    pub fy: f32,
+    pub cx: f32,
+    pub cy: f32,
+}
+
+impl Default for CameraInfoMessage {
+    /// A VGA camera with a 65 degree horizontal field of view, until the real one arrives.
+    fn default() -> Self {
+        Self { width: 640, height: 480, fx: 500.0, fy: 500.0, cx: 320.0, cy: 240.0 }
+    }
 }
 
+#[derive(Clone, Debug)]
+pub struct GraphEdgeMessage {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+    /// number of shared map points
+    pub weight: u32,
+}
+
+/// Map points observed by a keyframe.
+#[derive(Debug)]
+pub struct ObservationsMessage {
+    pub keyframe: usize,
+    pub points: Vec<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageKeypointMessage {
+    /// in pixels
+    pub position: Vec2,
+    pub status: KeypointStatus,
+}
+
+/// A JPEG image and the keypoints found in it.
+#[derive(Debug)]
+pub struct ImageMessage {
+    pub label: String,
+    pub keypoints: Vec<ImageKeypointMessage>,
+    pub jpeg: Vec<u8>,
+}
+
+/// Color of points sent without one.
+pub const DEFAULT_POINT_COLOR: Color = Color::rgb(0.8, 0.7, 0.6);
+
 pub struct BodyTransformEvent(pub BodyTransformMessage);
 pub struct PointEvent(pub PointMessage);
+pub struct PointBatchEvent(pub Vec<PointMessage>);
+pub struct KeyFramePoseBatchEvent(pub Vec<KeyFramePoseMessage>);
+pub struct CameraInfoEvent(pub CameraInfoMessage);
+pub struct ResetEvent;
+pub struct KeyFrameGraphEvent(pub Vec<GraphEdgeMessage>);
+pub struct ObservationsEvent(pub ObservationsMessage);
+pub struct ImageEvent(pub ImageMessage);
+/// ids of map points to drop
+pub struct PointRemoveEvent(pub Vec<usize>);
+
+/// Order of the message handling within a frame: the server reads the stream, the
+/// messages become events, a reset clears the state and then the other events are
+/// applied, so every message takes effect in the frame it is read.
+#[derive(SystemSet, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum MessageSet {
+    Read,
+    Dispatch,
+    Reset,
+    Receive,
+}
 
 pub struct MessagePlugin;
 impl Plugin for MessagePlugin {
     fn build(&self, app: &mut App) {
         app
+            .configure_sets((MessageSet::Read, MessageSet::Dispatch, MessageSet::Reset, MessageSet::Receive).chain())
             .add_event::<BodyTransformEvent>()
             .add_event::<PointEvent>()
-            .add_system(response_stream_event);
+            .add_event::<PointBatchEvent>()
+            .add_event::<KeyFramePoseBatchEvent>()
+            .add_event::<CameraInfoEvent>()
+            .add_event::<ResetEvent>()
+            .add_event::<KeyFrameGraphEvent>()
+            .add_event::<ObservationsEvent>()
+            .add_event::<ImageEvent>()
+            .add_event::<PointRemoveEvent>()
+            .add_system(response_stream_event.in_set(MessageSet::Dispatch));
     }
 }
 
+/// Turns the messages read this frame into events. A reset drops everything received
+/// before it, so only the last reset of the frame and the messages following it are sent.
 fn response_stream_event(
     mut events: EventReader<server::StreamEvent>,
     mut body_transform_event: EventWriter<BodyTransformEvent>,
-    mut point_event: EventWriter<PointEvent>
+    mut point_event: EventWriter<PointEvent>,
+    mut point_batch_event: EventWriter<PointBatchEvent>,
+    mut keyframe_pose_batch_event: EventWriter<KeyFramePoseBatchEvent>,
+    mut camera_info_event: EventWriter<CameraInfoEvent>,
+    mut reset_event: EventWriter<ResetEvent>,
+    mut keyframe_graph_event: EventWriter<KeyFrameGraphEvent>,
+    mut observations_event: EventWriter<ObservationsEvent>,
+    mut image_event: EventWriter<ImageEvent>,
+    mut point_remove_event: EventWriter<PointRemoveEvent>,
 ) {
-    for event in events.iter() {
-        let msg = &event.0;
-        for str in msg.split('{') {
-            if !str.ends_with('}') {
-                continue;
-            }
-            let str = str.trim_end_matches('}');
-
-            if str.starts_with("body") {
-                let body = parse_transform_message(str).unwrap();
-                body_transform_event.send(BodyTransformEvent(body));
-            } else if str.starts_with("point") {
-                let point = parse_point_message(str).unwrap();
-                point_event.send(PointEvent(point));
-            }
+    let messages = events.iter().map(|event| &event.0).collect::<Vec<_>>();
+    let start = messages.iter().rposition(|message| matches!(message, Message::Reset)).unwrap_or(0);
+    for message in messages[start..].iter() {
+        match message {
+            Message::BodyTransform { id, relative, rotation, translation } => {
+                body_transform_event.send(BodyTransformEvent(BodyTransformMessage {
+                    id: *id as usize,
+                    relative: *relative,
+                    transform: to_transform(rotation, translation),
+                }));
+            },
+            Message::Point { id, position } => {
+                point_event.send(PointEvent(PointMessage {
+                    id: *id as usize,
+                    position: Vec3::from_array(*position),
+                    color: DEFAULT_POINT_COLOR,
+                    observations: 0,
+                    reprojection_error: 0.0,
+                    first_keyframe: 0,
+                }));
+            },
+            Message::PointBatch(points) => {
+                let points = points.iter()
+                    .map(|point| PointMessage {
+                        id: point.id as usize,
+                        position: Vec3::from_array(point.position),
+                        color: Color::rgb_u8(point.color[0], point.color[1], point.color[2]),
+                        observations: point.observations,
+                        reprojection_error: point.reprojection_error,
+                        first_keyframe: point.first_keyframe as usize,
+                    })
+                    .collect();
+                point_batch_event.send(PointBatchEvent(points));
+            },
+            Message::KeyFramePoseBatch(poses) => {
+                let poses = poses.iter()
+                    .map(|pose| KeyFramePoseMessage {
+                        id: pose.id as usize,
+                        transform: to_transform(&pose.rotation, &pose.translation),
+                    })
+                    .collect();
+                keyframe_pose_batch_event.send(KeyFramePoseBatchEvent(poses));
+            },
+            Message::CameraInfo(camera) => {
+                camera_info_event.send(CameraInfoEvent(CameraInfoMessage {
+                    width: camera.width,
+                    height: camera.height,
+                    fx: camera.fx,
+                    fy: camera.fy,
+                    cx: camera.cx,
+                    cy: camera.cy,
+                }));
+            },
+            Message::Reset => reset_event.send(ResetEvent),
+            Message::KeyFrameGraph(edges) => {
+                let edges = edges.iter()
+                    .map(|edge| GraphEdgeMessage {
+                        from: edge.from as usize,
+                        to: edge.to as usize,
+                        kind: edge.kind,
+                        weight: edge.weight,
+                    })
+                    .collect();
+                keyframe_graph_event.send(KeyFrameGraphEvent(edges));
+            },
+            Message::Observations { keyframe, points } => {
+                observations_event.send(ObservationsEvent(ObservationsMessage {
+                    keyframe: *keyframe as usize,
+                    points: points.iter().map(|id| *id as usize).collect(),
+                }));
+            },
+            Message::Image(image) => {
+                image_event.send(ImageEvent(ImageMessage {
+                    label: image.label.clone(),
+                    keypoints: image.keypoints.iter()
+                        .map(|keypoint| ImageKeypointMessage {
+                            position: Vec2::from_array(keypoint.position),
+                            status: keypoint.status,
+                        })
+                        .collect(),
+                    jpeg: image.jpeg.clone(),
+                }));
+            },
+            Message::PointRemove(ids) => {
+                point_remove_event.send(PointRemoveEvent(ids.iter().map(|id| *id as usize).collect()));
+            },
         }
     }
 }
 
-
-
-pub fn parse_transform_message(msg: &str) -> Result<BodyTransformMessage, Box<dyn Error>> {
-    let mut parts = msg.split_whitespace();
-    parts.next();
-    let id = parts.next().unwrap().parse::<usize>()?;
-    let qx = parts.next().unwrap().parse::<f32>()?;
-    let qy = parts.next().unwrap().parse::<f32>()?;
-    let qz = parts.next().unwrap().parse::<f32>()?;
-    let qw = parts.next().unwrap().parse::<f32>()?;
-
-    let x = parts.next().unwrap().parse::<f32>()?;
-    let y = parts.next().unwrap().parse::<f32>()?;
-    let z = parts.next().unwrap().parse::<f32>()?;
-    let transform = Transform {
-        translation: Vec3::new(x, y, z),
-        rotation: Quat::from_xyzw(qx, qy, qz, qw),
+fn to_transform(rotation: &[f32; 4], translation: &[f32; 3]) -> Transform {
+    Transform {
+        translation: Vec3::from_array(*translation),
+        rotation: Quat::from_array(*rotation),
         scale: Vec3::ONE,
-    };
-    Ok(BodyTransformMessage { id, transform })
-}
-
-pub fn parse_point_message(msg: &str) -> Result<PointMessage, Box<dyn Error>> {
-    let mut parts = msg.split_whitespace();
-    parts.next();
-    let id = parts.next().unwrap().parse::<usize>()?;
-    let x = parts.next().unwrap().parse::<f32>()?;
-    let y = parts.next().unwrap().parse::<f32>()?;
-    let z = parts.next().unwrap().parse::<f32>()?;
-    let position = Vec3::new(x, y, z);
-    Ok(PointMessage { id, position })
+    }
 }
@@ -1,4 +1,8 @@
 pub mod pan_orbit_camera;
 pub mod message;
 pub mod server;
-pub mod process_msg;
\ No newline at end of file
+pub mod process_msg;
+pub mod point_cloud;
+pub mod point_color;
+pub mod ui;
+pub mod image_view;
\ No newline at end of file
@@ -1,6 +1,7 @@
 use bevy::prelude::*;
 use bevy::input::mouse::{MouseWheel,MouseMotion};
 use bevy::render::camera::Projection;
+use bevy_egui::EguiContexts;
 
 /// Tags an entity as capable of panning and orbiting.
 #[derive(Component)]
@@ -21,21 +22,36 @@ impl Default for PanOrbitCamera {
     }
 }
 
+/// While set, the camera orbits around this point instead of the one it was panned to.
+#[derive(Resource, Default)]
+pub struct FollowTarget(pub Option<Vec3>);
+
 pub struct PanOrbitCameraPlugin;
 impl Plugin for PanOrbitCameraPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(spawn_camera)
-            .add_system(pan_orbit_camera);
+        app.init_resource::<FollowTarget>()
+            .add_startup_system(spawn_camera)
+            .add_system(pan_orbit_camera)
+            .add_system(follow_target.after(pan_orbit_camera));
     }
 }
 
 /// Pan the camera with middle mouse click, zoom with scroll wheel, orbit with right mouse click.
+/// The mouse is left to egui while it is over a panel or window, or dragging in one.
 fn pan_orbit_camera(
     mut ev_motion: EventReader<MouseMotion>,
     mut ev_scroll: EventReader<MouseWheel>,
     input_mouse: Res<Input<MouseButton>>,
+    mut contexts: EguiContexts,
     mut query: Query<(&mut PanOrbitCamera, &mut Transform, &Projection)>,
 ) {
+    let ctx = contexts.ctx_mut();
+    if ctx.wants_pointer_input() || ctx.is_pointer_over_area() {
+        ev_motion.clear();
+        ev_scroll.clear();
+        return;
+    }
+
     // change input mapping for orbit and panning here
     let orbit_button = MouseButton::Right;
     let pan_button = MouseButton::Middle;
@@ -117,6 +133,22 @@ fn pan_orbit_camera(
     ev_motion.clear();
 }
 
+/// Moves the focus to the `FollowTarget`, keeping the orbit rotation and radius.
+fn follow_target(
+    target: Res<FollowTarget>,
+    mut query: Query<(&mut PanOrbitCamera, &mut Transform)>,
+) {
+    let target = match target.0 {
+        Some(target) => target,
+        None => return,
+    };
+    for (mut pan_orbit, mut transform) in query.iter_mut() {
+        pan_orbit.focus = target;
+        let rot_matrix = Mat3::from_quat(transform.rotation);
+        transform.translation = pan_orbit.focus + rot_matrix.mul_vec3(Vec3::new(0.0, 0.0, pan_orbit.radius));
+    }
+}
+
 fn get_primary_window_size() -> Vec2 {
     let window = Vec2::new(1000.0, 1000.0);
     window
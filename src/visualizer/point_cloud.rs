@@ -0,0 +1,325 @@
+//! Map points drawn as a few large meshes instead of one entity per point.
+//!
+//! Points are stored in fixed size chunks, each rendered as a single mesh with vertex
+//! colors. An update only marks the chunk holding the point, and the marked chunks are
+//! rebuilt once per frame, so streaming a few hundred points into a cloud of 500k
+//! rewrites a handful of chunks rather than the whole cloud.
+
+use std::collections::HashMap;
+
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology, view::NoFrustumCulling},
+};
+
+/// Points per mesh, small enough to rebuild a chunk every frame.
+const POINTS_PER_CHUNK: usize = 16384;
+
+/// Edge length of a point at scale 1, in world units.
+pub const POINT_SIZE: f32 = 0.01;
+
+/// Every point is an octahedron, visible from any side without normals or lighting.
+const OCTAHEDRON_VERTICES: [[f32; 3]; 6] = [
+    [1.0, 0.0, 0.0], [-1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0], [0.0, -1.0, 0.0],
+    [0.0, 0.0, 1.0], [0.0, 0.0, -1.0],
+];
+const OCTAHEDRON_INDICES: [u32; 24] = [
+    0, 2, 4,  2, 1, 4,  1, 3, 4,  3, 0, 4,
+    2, 0, 5,  1, 2, 5,  3, 1, 5,  0, 3, 5,
+];
+
+#[derive(Clone, Copy)]
+struct CloudPoint {
+    id: usize,
+    position: Vec3,
+    color: Color,
+}
+
+#[derive(Default)]
+struct Chunk {
+    points: Vec<CloudPoint>,
+    /// entity and mesh, spawned the first time the chunk is drawn
+    render: Option<(Entity, Handle<Mesh>)>,
+    dirty: bool,
+}
+
+/// All map points, keyed by their map point id.
+#[derive(Resource)]
+pub struct PointCloud {
+    /// chunk and index in the chunk of every point
+    slots: HashMap<usize, (usize, usize)>,
+    chunks: Vec<Chunk>,
+    /// multiplies the size of every point
+    scale: f32,
+    visible: bool,
+    /// entities of cleared chunks, despawned by the next sync
+    stale: Vec<Entity>,
+}
+
+impl Default for PointCloud {
+    fn default() -> Self {
+        Self { slots: HashMap::new(), chunks: Vec::new(), scale: 1.0, visible: true, stale: Vec::new() }
+    }
+}
+
+impl PointCloud {
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Adds the point `id`, or moves and recolors it if it exists.
+    pub fn insert(&mut self, id: usize, position: Vec3, color: Color) {
+        let point = CloudPoint { id, position, color };
+        if let Some((chunk, index)) = self.slots.get(&id) {
+            let chunk = &mut self.chunks[*chunk];
+            chunk.points[*index] = point;
+            chunk.dirty = true;
+            return;
+        }
+        // fill the holes left by removed points before growing the cloud
+        let chunk_index = match self.chunks.iter().position(|chunk| chunk.points.len() < POINTS_PER_CHUNK) {
+            Some(chunk_index) => chunk_index,
+            None => {
+                self.chunks.push(Chunk::default());
+                self.chunks.len() - 1
+            },
+        };
+        let chunk = &mut self.chunks[chunk_index];
+        self.slots.insert(id, (chunk_index, chunk.points.len()));
+        chunk.points.push(point);
+        chunk.dirty = true;
+    }
+
+    /// Removes the point `id`, the last point of its chunk takes its place.
+    pub fn remove(&mut self, id: usize) {
+        let (chunk_index, index) = match self.slots.remove(&id) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let chunk = &mut self.chunks[chunk_index];
+        chunk.points.swap_remove(index);
+        chunk.dirty = true;
+        if let Some(moved) = chunk.points.get(index) {
+            self.slots.insert(moved.id, (chunk_index, index));
+        }
+    }
+
+    /// Recolors the point `id`, its chunk is only rebuilt if the color differs.
+    pub fn set_color(&mut self, id: usize, color: Color) {
+        if let Some((chunk, index)) = self.slots.get(&id) {
+            let chunk = &mut self.chunks[*chunk];
+            if chunk.points[*index].color != color {
+                chunk.points[*index].color = color;
+                chunk.dirty = true;
+            }
+        }
+    }
+
+    pub fn position(&self, id: usize) -> Option<Vec3> {
+        self.slots.get(&id).map(|(chunk, index)| self.chunks[*chunk].points[*index].position)
+    }
+
+    /// Removes all points, their meshes are despawned with the next sync.
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        for chunk in self.chunks.drain(..) {
+            self.stale.extend(chunk.render.map(|(entity, _)| entity));
+        }
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Sets the multiplier of the point size, which rebuilds all chunks.
+    pub fn set_scale(&mut self, scale: f32) {
+        if scale == self.scale {
+            return;
+        }
+        self.scale = scale;
+        for chunk in self.chunks.iter_mut() {
+            chunk.dirty = true;
+        }
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+}
+
+/// Material shared by all chunks, white and unlit so the vertex colors show as they are.
+#[derive(Resource)]
+struct PointCloudMaterial(Handle<StandardMaterial>);
+
+pub struct PointCloudPlugin;
+impl Plugin for PointCloudPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<PointCloud>()
+            .add_startup_system(startup)
+            .add_system(sync_point_cloud.in_base_set(CoreSet::PostUpdate));
+    }
+}
+
+fn startup(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.insert_resource(PointCloudMaterial(materials.add(StandardMaterial {
+        base_color: Color::WHITE,
+        unlit: true,
+        ..Default::default()
+    })));
+}
+
+/// Rebuilds the meshes of the chunks that changed since the last frame and shows or hides
+/// all of them.
+fn sync_point_cloud(
+    mut commands: Commands,
+    mut cloud: ResMut<PointCloud>,
+    material: Res<PointCloudMaterial>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut visibilities: Query<&mut Visibility>,
+) {
+    if !cloud.is_changed() {
+        return;
+    }
+    let cloud = cloud.as_mut();
+    let visibility = if cloud.visible { Visibility::Inherited } else { Visibility::Hidden };
+    for entity in cloud.stale.drain(..) {
+        commands.entity(entity).despawn();
+    }
+    for chunk in cloud.chunks.iter_mut().filter(|chunk| chunk.dirty) {
+        chunk.dirty = false;
+        let mesh = chunk_mesh(&chunk.points, cloud.scale);
+        match &chunk.render {
+            Some((_, handle)) => {
+                if let Some(existing) = meshes.get_mut(handle) {
+                    *existing = mesh;
+                }
+            },
+            None => {
+                let handle = meshes.add(mesh);
+                // the bounds computed at spawn would not grow with the chunk
+                let entity = commands.spawn(PbrBundle {
+                    mesh: handle.clone(),
+                    material: material.0.clone(),
+                    visibility,
+                    ..Default::default()
+                }).insert(NoFrustumCulling).id();
+                chunk.render = Some((entity, handle));
+            },
+        }
+    }
+    for (entity, _) in cloud.chunks.iter().filter_map(|chunk| chunk.render.as_ref()) {
+        if let Ok(mut current) = visibilities.get_mut(*entity) {
+            if *current != visibility {
+                *current = visibility;
+            }
+        }
+    }
+}
+
+fn chunk_mesh(points: &[CloudPoint], scale: f32) -> Mesh {
+    let mut positions = Vec::with_capacity(points.len() * OCTAHEDRON_VERTICES.len());
+    let mut colors = Vec::with_capacity(points.len() * OCTAHEDRON_VERTICES.len());
+    let mut indices = Vec::with_capacity(points.len() * OCTAHEDRON_INDICES.len());
+    for point in points.iter() {
+        let offset = positions.len() as u32;
+        let half_size = 0.5 * POINT_SIZE * scale;
+        let color = point.color.as_linear_rgba_f32();
+        for vertex in OCTAHEDRON_VERTICES.iter() {
+            positions.push((point.position + Vec3::from(*vertex) * half_size).to_array());
+            colors.push(color);
+        }
+        indices.extend(OCTAHEDRON_INDICES.iter().map(|index| offset + index));
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_lens(cloud: &PointCloud) -> Vec<usize> {
+        cloud.chunks.iter().map(|chunk| chunk.points.len()).collect()
+    }
+
+    /// Every slot points at the point it was assigned to.
+    fn check_slots(cloud: &PointCloud) {
+        for (id, (chunk, index)) in cloud.slots.iter() {
+            assert_eq!(cloud.chunks[*chunk].points[*index].id, *id);
+        }
+    }
+
+    #[test]
+    fn test_insert_across_chunks() {
+        let mut cloud = PointCloud::default();
+        for id in 0..POINTS_PER_CHUNK + 10 {
+            cloud.insert(id, Vec3::new(id as f32, 0.0, 0.0), Color::WHITE);
+        }
+        assert_eq!(cloud.len(), POINTS_PER_CHUNK + 10);
+        assert_eq!(chunk_lens(&cloud), vec![POINTS_PER_CHUNK, 10]);
+        assert!(cloud.chunks.iter().all(|chunk| chunk.dirty));
+        check_slots(&cloud);
+
+        // updating a point only marks its own chunk
+        cloud.chunks.iter_mut().for_each(|chunk| chunk.dirty = false);
+        cloud.insert(POINTS_PER_CHUNK + 3, Vec3::ONE, Color::RED);
+        assert_eq!(cloud.len(), POINTS_PER_CHUNK + 10);
+        assert_eq!(cloud.position(POINTS_PER_CHUNK + 3), Some(Vec3::ONE));
+        assert_eq!(cloud.chunks.iter().map(|chunk| chunk.dirty).collect::<Vec<_>>(), vec![false, true]);
+
+        cloud.chunks.iter_mut().for_each(|chunk| chunk.dirty = false);
+        cloud.set_color(5, Color::WHITE);
+        assert!(cloud.chunks.iter().all(|chunk| !chunk.dirty));
+        cloud.set_color(5, Color::BLUE);
+        assert_eq!(cloud.chunks.iter().map(|chunk| chunk.dirty).collect::<Vec<_>>(), vec![true, false]);
+    }
+
+    #[test]
+    fn test_remove_and_refill() {
+        let mut cloud = PointCloud::default();
+        for id in 0..POINTS_PER_CHUNK + 2 {
+            cloud.insert(id, Vec3::new(id as f32, 0.0, 0.0), Color::WHITE);
+        }
+        cloud.remove(3);
+        cloud.remove(POINTS_PER_CHUNK);
+        cloud.remove(3);
+        assert_eq!(cloud.len(), POINTS_PER_CHUNK);
+        assert_eq!(chunk_lens(&cloud), vec![POINTS_PER_CHUNK - 1, 1]);
+        assert_eq!(cloud.position(3), None);
+        // the last point of the chunk took the place of the removed one
+        assert_eq!(cloud.position(POINTS_PER_CHUNK - 1), Some(Vec3::new((POINTS_PER_CHUNK - 1) as f32, 0.0, 0.0)));
+        check_slots(&cloud);
+
+        // the hole in the first chunk is filled before the last one grows
+        cloud.insert(100_000, Vec3::ZERO, Color::WHITE);
+        assert_eq!(chunk_lens(&cloud), vec![POINTS_PER_CHUNK, 1]);
+        check_slots(&cloud);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut cloud = PointCloud::default();
+        for id in 0..POINTS_PER_CHUNK + 1 {
+            cloud.insert(id, Vec3::ZERO, Color::WHITE);
+        }
+        cloud.chunks[0].render = Some((Entity::from_raw(7), Handle::default()));
+        cloud.clear();
+        assert_eq!(cloud.len(), 0);
+        assert!(cloud.chunks.is_empty());
+        assert_eq!(cloud.stale, vec![Entity::from_raw(7)]);
+        assert_eq!(cloud.position(0), None);
+
+        cloud.insert(1, Vec3::ONE, Color::WHITE);
+        assert_eq!(chunk_lens(&cloud), vec![1]);
+        assert_eq!(cloud.position(1), Some(Vec3::ONE));
+    }
+}
@@ -0,0 +1,143 @@
+//! How map points are colored. `C` cycles through the modes.
+
+use bevy::prelude::*;
+
+use super::message::PointMessage;
+
+/// Points seen by this many keyframes or more get the last color of the ramp.
+const MAX_OBSERVATIONS: u32 = 20;
+/// Reprojection error in pixels mapped to the last color of the ramp.
+const MAX_REPROJECTION_ERROR: f32 = 3.0;
+/// Color of points the current mode has no value for, e.g. behind the camera.
+const NO_VALUE_COLOR: Color = Color::rgb(0.6, 0.6, 0.6);
+
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// image intensity at the reference keypoint, as sent
+    #[default]
+    Intensity,
+    /// -y, since y points down in the camera frame of the first keyframe
+    Height,
+    /// distance along the optical axis of the current camera
+    Depth,
+    /// number of observing keyframes
+    Observations,
+    ReprojectionError,
+    /// oldest observing keyframe, relative to the newest keyframe
+    Age,
+}
+
+impl ColorMode {
+    pub const ALL: [ColorMode; 6] = [
+        ColorMode::Intensity,
+        ColorMode::Height,
+        ColorMode::Depth,
+        ColorMode::Observations,
+        ColorMode::ReprojectionError,
+        ColorMode::Age,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorMode::Intensity => "intensity",
+            ColorMode::Height => "height",
+            ColorMode::Depth => "depth",
+            ColorMode::Observations => "observations",
+            ColorMode::ReprojectionError => "reprojection error",
+            ColorMode::Age => "age",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        let index = Self::ALL.iter().position(|mode| mode == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    pub fn color(&self, point: &PointMessage, ranges: &ColorRanges) -> Color {
+        let t = match self {
+            ColorMode::Intensity => return point.color,
+            ColorMode::Height => normalize(-point.position.y, ranges.height),
+            ColorMode::Depth => match ranges.depth(point.position) {
+                Some(depth) => normalize(depth, ranges.depth),
+                None => return NO_VALUE_COLOR,
+            },
+            ColorMode::Observations => {
+                normalize(point.observations as f32, (2.0, MAX_OBSERVATIONS as f32))
+            },
+            ColorMode::ReprojectionError => {
+                normalize(point.reprojection_error, (0.0, MAX_REPROJECTION_ERROR))
+            },
+            ColorMode::Age => {
+                normalize(point.first_keyframe as f32, (0.0, ranges.latest_keyframe as f32))
+            },
+        };
+        ramp(t)
+    }
+}
+
+/// Ranges the relative modes are normalized with, taken from the map when the points
+/// are recolored.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct ColorRanges {
+    /// min and max height of all points
+    pub height: (f32, f32),
+    /// min and max depth of the points in front of the camera
+    pub depth: (f32, f32),
+    /// world to camera transform of the current camera
+    pub t_cw: Option<Mat4>,
+    pub latest_keyframe: usize,
+}
+
+impl Default for ColorRanges {
+    fn default() -> Self {
+        Self { height: (0.0, 1.0), depth: (0.0, 1.0), t_cw: None, latest_keyframe: 0 }
+    }
+}
+
+impl ColorRanges {
+    pub fn new<'a>(
+        points: impl Iterator<Item = &'a PointMessage>,
+        t_wc: Option<&Transform>,
+        latest_keyframe: usize,
+    ) -> Self {
+        let mut ranges = Self {
+            height: (f32::MAX, f32::MIN),
+            depth: (f32::MAX, f32::MIN),
+            t_cw: t_wc.map(|t_wc| t_wc.compute_matrix().inverse()),
+            latest_keyframe,
+        };
+        for point in points {
+            let height = -point.position.y;
+            ranges.height = (ranges.height.0.min(height), ranges.height.1.max(height));
+            if let Some(depth) = ranges.depth(point.position) {
+                ranges.depth = (ranges.depth.0.min(depth), ranges.depth.1.max(depth));
+            }
+        }
+        let defaults = Self::default();
+        if ranges.height.0 > ranges.height.1 {
+            ranges.height = defaults.height;
+        }
+        if ranges.depth.0 > ranges.depth.1 {
+            ranges.depth = defaults.depth;
+        }
+        ranges
+    }
+
+    /// Depth of a world point in the current camera, if it is in front of it.
+    fn depth(&self, position: Vec3) -> Option<f32> {
+        let depth = self.t_cw?.transform_point3(position).z;
+        (depth > 0.0).then_some(depth)
+    }
+}
+
+fn normalize(value: f32, (min, max): (f32, f32)) -> f32 {
+    if max <= min {
+        return 0.0;
+    }
+    ((value - min) / (max - min)).clamp(0.0, 1.0)
+}
+
+/// Blue for 0 through green to red for 1.
+fn ramp(t: f32) -> Color {
+    Color::hsl(240.0 * (1.0 - t), 1.0, 0.5)
+}
@@ -1,30 +1,168 @@
-use std::{collections::HashMap};
+use std::{collections::{HashMap, HashSet}};
 
 use bevy::{prelude::*};
 use bevy_obj::*;
 use bevy_prototype_debug_lines::*;
 
-use super::message;
+use super::message::{self, MessageSet};
+use super::point_cloud::{PointCloud, PointCloudPlugin};
+use super::point_color::{ColorMode, ColorRanges};
 
+/// A tracked camera, keyed by the body id of its messages.
 #[derive(Component)]
-struct Body;
+struct Body(usize);
+/// The cylinders of the world axes.
 #[derive(Component)]
-struct Point;
+struct Axes;
 
+/// What is drawn, all of it by default.
+#[derive(Resource, Clone, PartialEq)]
+pub struct Layers {
+    pub points: bool,
+    /// keyframe and current camera frustums
+    pub frustums: bool,
+    pub trajectory: bool,
+    pub graph: bool,
+    pub axes: bool,
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self { points: true, frustums: true, trajectory: true, graph: true, axes: true }
+    }
+}
+/// Mesh and material of the bodies, spawned when a body id is first seen.
 #[derive(Resource)]
-struct IdMap(HashMap<usize, Entity>);
+struct BodyAssets {
+    mesh: Handle<Mesh>,
+    material: Handle<StandardMaterial>,
+}
+
+/// Latest camera to world transform of every body, with unit scale.
+#[derive(Resource, Default)]
+pub struct BodyPoses(pub HashMap<usize, Transform>);
+
+impl BodyPoses {
+    /// The tracked camera, the body with the lowest id.
+    pub fn current(&self) -> Option<&Transform> {
+        self.0.iter().min_by_key(|(id, _)| **id).map(|(_, t_wc)| t_wc)
+    }
+}
+
+/// Latest camera to world transform of every keyframe.
+#[derive(Resource, Default)]
+pub struct KeyFramePoses(pub HashMap<usize, Transform>);
+
+/// Camera model the frustums are drawn with.
+#[derive(Resource, Default)]
+pub struct CameraInfo(pub message::CameraInfoMessage);
+
+/// Positions every body has been at, oldest first.
+#[derive(Resource, Default)]
+pub struct Trajectory(pub HashMap<usize, Vec<Vec3>>);
+
+impl Trajectory {
+    /// Appends `position` to the trajectory of body `id` unless it is within
+    /// `TRAJECTORY_MIN_STEP` of the last one. Past `MAX_TRAJECTORY_POINTS` every other
+    /// position is dropped, so a long run is still drawn whole, only coarser.
+    fn push(&mut self, id: usize, position: Vec3) {
+        let positions = self.0.entry(id).or_default();
+        if positions.last().map_or(false, |last| last.distance(position) < TRAJECTORY_MIN_STEP) {
+            return;
+        }
+        positions.push(position);
+        if positions.len() > MAX_TRAJECTORY_POINTS {
+            // keeps the even indices, the newest position among them as the length is odd
+            let mut index = 0;
+            positions.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+        }
+    }
+}
+
+/// Latest state of every map point, kept to recolor the points.
+#[derive(Resource, Default)]
+pub struct MapPoints(pub HashMap<usize, message::PointMessage>);
+
+/// Edges between keyframes, as last received.
+#[derive(Resource, Default)]
+pub struct KeyFrameGraph(pub Vec<message::GraphEdgeMessage>);
+
+/// Map points observed by every keyframe.
+#[derive(Resource, Default)]
+pub struct Observations(pub HashMap<usize, Vec<usize>>);
+
+/// Keyframe drawn highlighted with lines to the points it observes. `]` and `[` cycle
+/// through the keyframes, Escape clears the selection.
+#[derive(Resource, Default)]
+pub struct SelectedKeyFrame(pub Option<usize>);
+
+/// The body mesh is modelled in millimeters.
+const BODY_MESH_SCALE: f32 = 0.001;
+
+/// Distance from the camera center to the image plane of the drawn frustums.
+const KEYFRAME_FRUSTUM_DEPTH: f32 = 0.05;
+const CURRENT_FRUSTUM_DEPTH: f32 = 0.08;
+const KEYFRAME_COLOR: Color = Color::rgb(0.1, 0.3, 0.9);
+const CURRENT_COLOR: Color = Color::rgb(0.0, 0.8, 0.0);
+const TRAJECTORY_COLOR: Color = Color::rgb(0.9, 0.5, 0.0);
+const SELECTED_COLOR: Color = Color::rgb(0.9, 0.0, 0.9);
+const OBSERVATION_COLOR: Color = Color::rgb(0.9, 0.6, 0.9);
+const SPANNING_TREE_COLOR: Color = Color::rgb(0.3, 0.3, 0.3);
+const LOOP_COLOR: Color = Color::rgb(1.0, 0.0, 0.0);
+
+/// Distance a body has to move before its trajectory gets a new position.
+const TRAJECTORY_MIN_STEP: f32 = 0.01;
+/// Positions kept per body, bounds the lines drawn every frame. Even, see `Trajectory::push`.
+const MAX_TRAJECTORY_POINTS: usize = 4096;
+
+/// Seconds between recoloring all points while the map or camera changes, for the color
+/// modes relative to them.
+const RECOLOR_INTERVAL: f32 = 0.5;
 
 pub struct ProcessMsgPlugin;
 impl Plugin for ProcessMsgPlugin {
     fn build(&self, app: &mut App) {
          app
-            .insert_resource(IdMap(HashMap::new()))
+            .init_resource::<BodyPoses>()
+            .init_resource::<KeyFramePoses>()
+            .init_resource::<CameraInfo>()
+            .init_resource::<Trajectory>()
+            .init_resource::<Layers>()
+            .init_resource::<MapPoints>()
+            .init_resource::<ColorMode>()
+            .init_resource::<ColorRanges>()
+            .init_resource::<KeyFrameGraph>()
+            .init_resource::<Observations>()
+            .init_resource::<SelectedKeyFrame>()
             .add_plugin(ObjPlugin)
             .add_plugin(DebugLinesPlugin::default())
+            .add_plugin(PointCloudPlugin)
             .add_startup_system(startup)
             .add_system(show_frame)
-            .add_system(receive_body_event)
-            .add_system(receive_point_event);
+            .add_system(sync_layers)
+            .add_system(receive_reset_event.in_set(MessageSet::Reset))
+            .add_system(receive_body_event.in_set(MessageSet::Receive))
+            .add_system(sync_bodies.after(receive_body_event))
+            .add_system(receive_point_event.in_set(MessageSet::Receive))
+            .add_system(toggle_color_mode)
+            .add_system(recolor_points
+                .after(receive_point_event)
+                .after(receive_body_event)
+                .after(receive_keyframe_pose_event)
+                .after(toggle_color_mode))
+            .add_system(receive_keyframe_pose_event.in_set(MessageSet::Receive))
+            .add_system(receive_camera_info_event.in_set(MessageSet::Receive))
+            .add_system(receive_graph_event.in_set(MessageSet::Receive))
+            .add_system(receive_observations_event.in_set(MessageSet::Receive))
+            .add_system(select_keyframe)
+            .add_system(show_keyframes)
+            .add_system(show_current_frame)
+            .add_system(show_trajectory)
+            .add_system(show_graph)
+            .add_system(show_observations);
     }
 }
 
@@ -35,12 +173,10 @@ fn startup(
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
     let mesh_handle: Handle<Mesh> = asset_server.load("obj/iphonex.obj");
-    commands.spawn(PbrBundle {
+    commands.insert_resource(BodyAssets {
         mesh: mesh_handle,
         material: materials.add(Color::rgb(0.8, 0.7, 0.6).into()),
-        transform: Transform {scale: Vec3::splat(0.001), ..Default::default()},
-        ..Default::default()
-    }).insert(Body);
+    });
 
     commands.spawn(PbrBundle {
         mesh: meshes.add(Mesh::from(shape::Cylinder {radius: 0.01, height: 1.0, resolution: 10, segments: 1})),
@@ -51,13 +187,13 @@ fn startup(
             ..Default::default()
         },
         ..Default::default()
-    });
+    }).insert(Axes);
     commands.spawn(PbrBundle {
         mesh: meshes.add(Mesh::from(shape::Cylinder {radius: 0.01, height: 1.0, resolution: 10, segments: 1})),
         material: materials.add(Color::rgb(0.0, 1.0, 0.0).into()),
         transform: Transform::from_xyz(0.0, 0.5, 0.0),
         ..Default::default()
-    });
+    }).insert(Axes);
     commands.spawn(PbrBundle {
         mesh: meshes.add(Mesh::from(shape::Cylinder {radius: 0.01, height: 1.0, resolution: 10, segments: 1})),
         material: materials.add(Color::rgb(0.0, 0.0, 1.0).into()),
@@ -67,7 +203,7 @@ fn startup(
             ..Default::default()
         },
         ..Default::default()
-    });
+    }).insert(Axes);
     commands.spawn(PointLightBundle {
         transform: Transform::from_xyz(4.0, 8.0, 4.0),
         point_light: PointLight {
@@ -80,8 +216,12 @@ fn startup(
 }
 
 fn show_frame(
+    layers: Res<Layers>,
     mut lines: ResMut<DebugLines>,
 ) {
+    if !layers.axes {
+        return;
+    }
     lines.line_colored(
         Vec3::ZERO,
         Vec3::X,
@@ -106,48 +246,470 @@ fn show_frame(
     );
 }
 
+/// Shows or hides the meshes of the layers.
+fn sync_layers(
+    layers: Res<Layers>,
+    mut point_cloud: ResMut<PointCloud>,
+    mut axes: Query<&mut Visibility, With<Axes>>,
+) {
+    if !layers.is_changed() {
+        return;
+    }
+    point_cloud.set_visible(layers.points);
+    for mut visibility in axes.iter_mut() {
+        *visibility = if layers.axes { Visibility::Inherited } else { Visibility::Hidden };
+    }
+}
 
+/// Drops everything received so far. Runs in `MessageSet::Reset`, before the other
+/// receivers, which only get the messages that followed the reset.
+fn receive_reset_event(
+    mut reset_event: EventReader<message::ResetEvent>,
+    mut point_cloud: ResMut<PointCloud>,
+    mut map_points: ResMut<MapPoints>,
+    mut body_poses: ResMut<BodyPoses>,
+    mut keyframe_poses: ResMut<KeyFramePoses>,
+    mut trajectory: ResMut<Trajectory>,
+    mut camera_info: ResMut<CameraInfo>,
+    mut graph: ResMut<KeyFrameGraph>,
+    mut observations: ResMut<Observations>,
+    mut selected: ResMut<SelectedKeyFrame>,
+) {
+    if reset_event.iter().count() == 0 {
+        return;
+    }
+    point_cloud.clear();
+    map_points.0.clear();
+    body_poses.0.clear();
+    keyframe_poses.0.clear();
+    trajectory.0.clear();
+    *camera_info = CameraInfo::default();
+    graph.0.clear();
+    observations.0.clear();
+    selected.0 = None;
+}
 
 fn receive_body_event(
     mut body_transform_event: EventReader<message::BodyTransformEvent>,
-    mut query: Query<&mut Transform, With<Body>>,
+    mut body_poses: ResMut<BodyPoses>,
+    mut trajectory: ResMut<Trajectory>,
 ) {
     for event in body_transform_event.iter() {
-        println!("body event: {:?}", event.0);
-        let mut t_wc = query.single_mut();
-        let t_oc_nc = event.0.transform;
-        let temp = t_wc.rotation * t_oc_nc.translation;
-        t_wc.translation += temp;
-        t_wc.rotate(t_oc_nc.rotation);
+        let msg = &event.0;
+        let t_wc = if msg.relative {
+            let t_wc = body_poses.0.get(&msg.id).copied().unwrap_or_default();
+            t_wc * msg.transform
+        } else {
+            msg.transform
+        };
+        body_poses.0.insert(msg.id, t_wc);
+        trajectory.push(msg.id, t_wc.translation);
     }
 }
 
-fn receive_point_event(
+/// Spawns, moves and despawns the body meshes to match `BodyPoses`.
+fn sync_bodies(
     mut commands: Commands,
+    body_poses: Res<BodyPoses>,
+    body_assets: Res<BodyAssets>,
+    mut query: Query<(Entity, &Body, &mut Transform)>,
+) {
+    if !body_poses.is_changed() {
+        return;
+    }
+    let mut present = HashSet::new();
+    for (entity, body, mut transform) in query.iter_mut() {
+        match body_poses.0.get(&body.0) {
+            Some(t_wc) => {
+                *transform = t_wc.with_scale(Vec3::splat(BODY_MESH_SCALE));
+                present.insert(body.0);
+            },
+            None => commands.entity(entity).despawn(),
+        }
+    }
+    for (id, t_wc) in body_poses.0.iter().filter(|(id, _)| !present.contains(*id)) {
+        commands.spawn(PbrBundle {
+            mesh: body_assets.mesh.clone(),
+            material: body_assets.material.clone(),
+            transform: t_wc.with_scale(Vec3::splat(BODY_MESH_SCALE)),
+            ..Default::default()
+        }).insert(Body(*id));
+    }
+}
+
+/// Applies all point updates received this frame at once, the latest update of a point
+/// wins, then drops the removed points.
+fn receive_point_event(
     mut point_event: EventReader<message::PointEvent>,
-    mut id_map: ResMut<IdMap>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut query: Query<&mut Transform, With<Point>>,
+    mut point_batch_event: EventReader<message::PointBatchEvent>,
+    mut point_remove_event: EventReader<message::PointRemoveEvent>,
+    mut point_cloud: ResMut<PointCloud>,
+    mut map_points: ResMut<MapPoints>,
+    mode: Res<ColorMode>,
+    ranges: Res<ColorRanges>,
 ) {
+    let mut updates = HashMap::new();
     for event in point_event.iter() {
-        let id = event.0.id;
-        if id_map.0.contains_key(&id) {
-            let entity = id_map.0.get(&id).unwrap();
-            if let Ok(mut t) = query.get_mut(*entity) {
-                t.translation = event.0.position;
-            } else {
-                println!("get mut transform failed");
-            }
-        } else {
-            let id: Entity = commands.spawn(PbrBundle {
-                mesh: meshes.add(Mesh::from(shape::Cube { size: 0.01 })),
-                material: materials.add(Color::rgb(0.8, 0.7, 0.6).into()),
-                transform: Transform::from_translation(event.0.position),
-                ..Default::default()
-            }).insert(Point).id();
-            id_map.0.insert(event.0.id, id);
+        updates.insert(event.0.id, &event.0);
+    }
+    for event in point_batch_event.iter() {
+        for point in event.0.iter() {
+            updates.insert(point.id, point);
+        }
+    }
+
+    for point in updates.into_values() {
+        point_cloud.insert(point.id, point.position, mode.color(point, &ranges));
+        map_points.0.insert(point.id, point.clone());
+    }
+    for id in point_remove_event.iter().flat_map(|event| event.0.iter()) {
+        point_cloud.remove(*id);
+        map_points.0.remove(id);
+    }
+}
+
+fn toggle_color_mode(
+    keys: Res<Input<KeyCode>>,
+    mut mode: ResMut<ColorMode>,
+) {
+    if keys.just_pressed(KeyCode::C) {
+        *mode = mode.next();
+    }
+}
+
+/// Recolors all points when the color mode changes and, for the modes relative to the
+/// map or camera, at most every `RECOLOR_INTERVAL` after what they depend on changed.
+/// Only chunks holding a point whose color changed are rebuilt. New points are colored
+/// as they arrive, with the ranges of the last recoloring.
+fn recolor_points(
+    time: Res<Time>,
+    mut last_recolor: Local<f32>,
+    mut pending: Local<bool>,
+    mode: Res<ColorMode>,
+    mut ranges: ResMut<ColorRanges>,
+    map_points: Res<MapPoints>,
+    body_poses: Res<BodyPoses>,
+    keyframe_poses: Res<KeyFramePoses>,
+    mut point_cloud: ResMut<PointCloud>,
+) {
+    *pending |= match *mode {
+        ColorMode::Height => map_points.is_changed(),
+        ColorMode::Depth => map_points.is_changed() || body_poses.is_changed(),
+        ColorMode::Age => keyframe_poses.is_changed(),
+        _ => false,
+    };
+    let now = time.elapsed_seconds();
+    let due = *pending && now - *last_recolor >= RECOLOR_INTERVAL;
+    if !mode.is_changed() && !due {
+        return;
+    }
+    *last_recolor = now;
+    *pending = false;
+
+    let latest_keyframe = keyframe_poses.0.keys().max().copied().unwrap_or(0);
+    let new_ranges = ColorRanges::new(map_points.0.values(), body_poses.current(), latest_keyframe);
+    if !mode.is_changed() && new_ranges == *ranges {
+        return;
+    }
+    *ranges = new_ranges;
+    for point in map_points.0.values() {
+        point_cloud.set_color(point.id, mode.color(point, &ranges));
+    }
+}
+
+fn receive_keyframe_pose_event(
+    mut keyframe_pose_event: EventReader<message::KeyFramePoseBatchEvent>,
+    mut keyframe_poses: ResMut<KeyFramePoses>,
+) {
+    for event in keyframe_pose_event.iter() {
+        for pose in event.0.iter() {
+            keyframe_poses.0.insert(pose.id, pose.transform);
         }
-        println!("point event: {:?}", event.0);
     }
-}
\ No newline at end of file
+}
+
+fn receive_camera_info_event(
+    mut camera_info_event: EventReader<message::CameraInfoEvent>,
+    mut camera_info: ResMut<CameraInfo>,
+) {
+    if let Some(event) = camera_info_event.iter().last() {
+        camera_info.0 = event.0.clone();
+    }
+}
+
+/// Draws the pyramid from the camera center of `t_wc` to the image corners, with the
+/// image plane at `depth`.
+fn draw_frustum(
+    lines: &mut DebugLines,
+    t_wc: &Transform,
+    camera: &message::CameraInfoMessage,
+    depth: f32,
+    color: Color,
+) {
+    let corner = |u: f32, v: f32| {
+        t_wc.transform_point(Vec3::new((u - camera.cx) / camera.fx * depth, (v - camera.cy) / camera.fy * depth, depth))
+    };
+    let (w, h) = (camera.width as f32, camera.height as f32);
+    let corners = [corner(0.0, 0.0), corner(w, 0.0), corner(w, h), corner(0.0, h)];
+    for i in 0..4 {
+        lines.line_colored(t_wc.translation, corners[i], 0.0, color);
+        lines.line_colored(corners[i], corners[(i + 1) % 4], 0.0, color);
+    }
+    // a tick above the top edge tells up from down
+    let top = (corners[0] + corners[1]) / 2.0;
+    lines.line_colored(top, corner(w / 2.0, -h / 4.0), 0.0, color);
+}
+
+fn show_keyframes(
+    keyframe_poses: Res<KeyFramePoses>,
+    camera_info: Res<CameraInfo>,
+    selected: Res<SelectedKeyFrame>,
+    layers: Res<Layers>,
+    mut lines: ResMut<DebugLines>,
+) {
+    if !layers.frustums {
+        return;
+    }
+    for (id, transform) in keyframe_poses.0.iter() {
+        let color = if selected.0 == Some(*id) { SELECTED_COLOR } else { KEYFRAME_COLOR };
+        draw_frustum(&mut lines, transform, &camera_info.0, KEYFRAME_FRUSTUM_DEPTH, color);
+    }
+}
+
+fn show_current_frame(
+    body_poses: Res<BodyPoses>,
+    camera_info: Res<CameraInfo>,
+    layers: Res<Layers>,
+    mut lines: ResMut<DebugLines>,
+) {
+    if !layers.frustums {
+        return;
+    }
+    for t_wc in body_poses.0.values() {
+        draw_frustum(&mut lines, t_wc, &camera_info.0, CURRENT_FRUSTUM_DEPTH, CURRENT_COLOR);
+    }
+}
+
+fn show_trajectory(
+    trajectory: Res<Trajectory>,
+    layers: Res<Layers>,
+    mut lines: ResMut<DebugLines>,
+) {
+    if !layers.trajectory {
+        return;
+    }
+    for positions in trajectory.0.values() {
+        for segment in positions.windows(2) {
+            lines.line_colored(segment[0], segment[1], 0.0, TRAJECTORY_COLOR);
+        }
+    }
+}
+
+fn receive_graph_event(
+    mut keyframe_graph_event: EventReader<message::KeyFrameGraphEvent>,
+    mut graph: ResMut<KeyFrameGraph>,
+) {
+    if let Some(event) = keyframe_graph_event.iter().last() {
+        graph.0 = event.0.clone();
+    }
+}
+
+fn receive_observations_event(
+    mut observations_event: EventReader<message::ObservationsEvent>,
+    mut observations: ResMut<Observations>,
+) {
+    for event in observations_event.iter() {
+        observations.0.insert(event.0.keyframe, event.0.points.clone());
+    }
+}
+
+fn select_keyframe(
+    keys: Res<Input<KeyCode>>,
+    keyframe_poses: Res<KeyFramePoses>,
+    mut selected: ResMut<SelectedKeyFrame>,
+) {
+    if keys.just_pressed(KeyCode::Escape) {
+        selected.0 = None;
+        return;
+    }
+    let next = keys.just_pressed(KeyCode::RBracket);
+    if !next && !keys.just_pressed(KeyCode::LBracket) {
+        return;
+    }
+    let mut ids = keyframe_poses.0.keys().cloned().collect::<Vec<_>>();
+    if ids.is_empty() {
+        return;
+    }
+    ids.sort();
+    let n = ids.len();
+    let index = match (selected.0.and_then(|id| ids.iter().position(|x| *x == id)), next) {
+        (Some(i), true) => (i + 1) % n,
+        (Some(i), false) => (i + n - 1) % n,
+        (None, true) => 0,
+        (None, false) => n - 1,
+    };
+    selected.0 = Some(ids[index]);
+}
+
+/// Draws the keyframe graph, covisibility edges from light to dark green with the
+/// number of shared points.
+fn show_graph(
+    graph: Res<KeyFrameGraph>,
+    keyframe_poses: Res<KeyFramePoses>,
+    layers: Res<Layers>,
+    mut lines: ResMut<DebugLines>,
+) {
+    if !layers.graph {
+        return;
+    }
+    let max_weight = graph.0.iter()
+        .filter(|edge| edge.kind == message::EdgeKind::Covisibility)
+        .map(|edge| edge.weight)
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    for edge in graph.0.iter() {
+        let (from, to) = match (keyframe_poses.0.get(&edge.from), keyframe_poses.0.get(&edge.to)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => continue,
+        };
+        let color = match edge.kind {
+            message::EdgeKind::Covisibility => {
+                let w = edge.weight as f32 / max_weight as f32;
+                Color::rgb(0.7 * (1.0 - w), 0.9 - 0.4 * w, 0.7 * (1.0 - w))
+            },
+            message::EdgeKind::SpanningTree => SPANNING_TREE_COLOR,
+            message::EdgeKind::Loop => LOOP_COLOR,
+        };
+        lines.line_colored(from.translation, to.translation, 0.0, color);
+    }
+}
+
+/// Draws a line from the selected keyframe to every map point it observes.
+fn show_observations(
+    selected: Res<SelectedKeyFrame>,
+    observations: Res<Observations>,
+    keyframe_poses: Res<KeyFramePoses>,
+    point_cloud: Res<PointCloud>,
+    mut lines: ResMut<DebugLines>,
+) {
+    let (id, t_wc) = match selected.0.and_then(|id| keyframe_poses.0.get(&id).map(|t_wc| (id, t_wc))) {
+        Some(selection) => selection,
+        None => return,
+    };
+    for point in observations.0.get(&id).into_iter().flatten() {
+        if let Some(position) = point_cloud.position(*point) {
+            lines.line_colored(t_wc.translation, position, 0.0, OBSERVATION_COLOR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Message, PointUpdate};
+    use crate::visualizer::server::StreamEvent;
+
+    /// Messages handed to the stream one update at a time.
+    #[derive(Resource, Default)]
+    struct Incoming(Vec<Vec<Message>>);
+
+    fn feed(mut incoming: ResMut<Incoming>, mut events: EventWriter<StreamEvent>) {
+        if !incoming.0.is_empty() {
+            events.send_batch(incoming.0.remove(0).into_iter().map(StreamEvent));
+        }
+    }
+
+    fn point(id: u32) -> PointUpdate {
+        PointUpdate {
+            id,
+            position: [0.0, 0.0, 1.0],
+            color: [255, 255, 255],
+            observations: 2,
+            reprojection_error: 0.5,
+            first_keyframe: 0,
+        }
+    }
+
+    fn app(incoming: Vec<Vec<Message>>) -> App {
+        let mut app = App::new();
+        app
+            .add_event::<StreamEvent>()
+            .add_plugin(message::MessagePlugin)
+            .insert_resource(Incoming(incoming))
+            .init_resource::<PointCloud>()
+            .init_resource::<MapPoints>()
+            .init_resource::<BodyPoses>()
+            .init_resource::<KeyFramePoses>()
+            .init_resource::<Trajectory>()
+            .init_resource::<CameraInfo>()
+            .init_resource::<KeyFrameGraph>()
+            .init_resource::<Observations>()
+            .init_resource::<SelectedKeyFrame>()
+            .init_resource::<ColorMode>()
+            .init_resource::<ColorRanges>()
+            .add_system(feed.in_set(MessageSet::Read))
+            .add_system(receive_reset_event.in_set(MessageSet::Reset))
+            .add_system(receive_point_event.in_set(MessageSet::Receive));
+        app
+    }
+
+    fn point_ids(app: &App) -> Vec<usize> {
+        let mut ids = app.world.resource::<MapPoints>().0.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_reset_then_points() {
+        let mut app = app(vec![
+            vec![Message::PointBatch(vec![point(0), point(1)])],
+            vec![Message::Reset],
+            vec![Message::PointBatch(vec![point(2)])],
+        ]);
+        app.update();
+        assert_eq!(point_ids(&app), vec![0, 1]);
+        app.update();
+        assert_eq!(point_ids(&app), Vec::<usize>::new());
+        assert_eq!(app.world.resource::<PointCloud>().len(), 0);
+        app.update();
+        assert_eq!(point_ids(&app), vec![2]);
+        app.update();
+        assert_eq!(point_ids(&app), vec![2]);
+        assert_eq!(app.world.resource::<PointCloud>().len(), 1);
+    }
+
+    #[test]
+    fn test_reset_in_frame() {
+        let mut app = app(vec![
+            vec![
+                Message::PointBatch(vec![point(0)]),
+                Message::Reset,
+                Message::PointBatch(vec![point(1)]),
+            ],
+        ]);
+        app.update();
+        assert_eq!(point_ids(&app), vec![1]);
+        app.update();
+        assert_eq!(point_ids(&app), vec![1]);
+    }
+
+    #[test]
+    fn test_point_remove() {
+        let mut app = app(vec![
+            vec![Message::PointBatch(vec![point(0), point(1), point(2)])],
+            vec![Message::PointRemove(vec![1, 7])],
+            vec![Message::PointBatch(vec![point(3)]), Message::PointRemove(vec![0, 3])],
+        ]);
+        app.update();
+        assert_eq!(point_ids(&app), vec![0, 1, 2]);
+        app.update();
+        assert_eq!(point_ids(&app), vec![0, 2]);
+        assert_eq!(app.world.resource::<PointCloud>().len(), 2);
+        app.update();
+        assert_eq!(point_ids(&app), vec![2]);
+        let cloud = app.world.resource::<PointCloud>();
+        assert_eq!(cloud.len(), 1);
+        assert!(cloud.position(0).is_none() && cloud.position(2).is_some());
+    }
+}
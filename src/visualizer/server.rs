@@ -1,17 +1,41 @@
+use std::collections::HashMap;
 use std::io::{Read};
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 
 use bevy::prelude::*;
 use crossbeam_channel::{bounded, Receiver, Sender};
 
+use crate::protocol::{Message, MessageKind, codec::StreamDecoder};
+
+use super::message::MessageSet;
+
 #[derive(Resource)]
 struct Server {
     listener: TcpListener,
 }
 
+/// What the connection thread passes on to the app.
+enum Incoming {
+    Connected(SocketAddr),
+    Message(Message),
+    Disconnected,
+}
+
 #[derive(Resource, Deref)]
-struct StreamReceiver(Receiver<String>);
-pub struct StreamEvent(pub String);
+struct StreamReceiver(Receiver<Incoming>);
+pub struct StreamEvent(pub Message);
+
+/// Connected client and message statistics.
+#[derive(Resource, Default)]
+pub struct ServerStatus {
+    pub client: Option<SocketAddr>,
+    /// messages received per kind since startup
+    pub received: HashMap<MessageKind, usize>,
+    /// messages per second per kind, over the last full second
+    pub rates: HashMap<MessageKind, f32>,
+    window_start: f32,
+    window_counts: HashMap<MessageKind, usize>,
+}
 
 pub struct ServerPlugin;
 
@@ -24,13 +48,16 @@ impl Plugin for ServerPlugin {
 
         app.add_event::<StreamEvent>()
             .insert_resource(Server { listener })
+            .init_resource::<ServerStatus>()
             .add_startup_system(server_system)
-            .add_system(read_stream);
+            .add_system(read_stream.in_set(MessageSet::Read));
     }
 }
 
-fn handle_client(stream: &mut TcpStream, tx: &Sender<String>)  {
-    let mut buffer = [0; 1024];
+fn handle_client(stream: &mut TcpStream, tx: &Sender<Incoming>)  {
+    let mut buffer = [0; 4096];
+    // frames may straddle reads, the decoder keeps the partial ones
+    let mut decoder = StreamDecoder::new();
     loop {
         match stream.read(&mut buffer) {
             Ok(0) => {
@@ -38,9 +65,17 @@ fn handle_client(stream: &mut TcpStream, tx: &Sender<String>)  {
                 break;
             }
             Ok(n) => {
-                // Process the received message
-                let message = String::from_utf8_lossy(&buffer[..n]).to_string();
-                tx.send(message).unwrap();
+                decoder.push(&buffer[..n]);
+                for message in decoder.by_ref() {
+                    match message {
+                        Ok(message) => {
+                            if tx.send(Incoming::Message(message)).is_err() {
+                                return;
+                            }
+                        },
+                        Err(err) => eprintln!("Dropping frame: {}", err),
+                    }
+                }
             }
             Err(err) => {
                 eprintln!("Error reading from socket: {}", err);
@@ -54,14 +89,18 @@ fn server_system(
     mut commands: Commands, 
     server: Res<Server>,
 ) {
-    let (tx, rx) = bounded::<String>(10);
+    let (tx, rx) = bounded::<Incoming>(1024);
     let incoming = server.listener.try_clone().expect("");
     std::thread::spawn(move || {
         for stream in incoming.incoming() {
             match stream {
                 Ok(mut stream) => {
+                    if let Ok(address) = stream.peer_addr() {
+                        let _ = tx.send(Incoming::Connected(address));
+                    }
                     // Spawn a new thread to handle the client connection
                     handle_client(&mut stream, &tx);
+                    let _ = tx.send(Incoming::Disconnected);
                 }
                 Err(err) => {
                     eprintln!("Error accepting connection: {}", err);
@@ -76,9 +115,29 @@ fn server_system(
 // This system reads from the receiver and sends events to Bevy
 fn read_stream(
     receiver: Res<StreamReceiver>, 
+    time: Res<Time>,
+    mut status: ResMut<ServerStatus>,
     mut events: EventWriter<StreamEvent>
 ) {
     for from_stream in receiver.try_iter() {
-        events.send(StreamEvent(from_stream));
+        match from_stream {
+            Incoming::Connected(address) => status.client = Some(address),
+            Incoming::Disconnected => status.client = None,
+            Incoming::Message(message) => {
+                *status.received.entry(message.kind()).or_default() += 1;
+                *status.window_counts.entry(message.kind()).or_default() += 1;
+                events.send(StreamEvent(message));
+            },
+        }
+    }
+
+    let now = time.elapsed_seconds();
+    let elapsed = now - status.window_start;
+    if elapsed >= 1.0 {
+        let status = status.as_mut();
+        status.rates = status.window_counts.drain()
+            .map(|(kind, count)| (kind, count as f32 / elapsed))
+            .collect();
+        status.window_start = now;
     }
 }
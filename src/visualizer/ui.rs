@@ -0,0 +1,114 @@
+//! Side panel with the layer toggles, point size and coloring, camera follow mode and
+//! connection statistics.
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use super::pan_orbit_camera::FollowTarget;
+use super::point_cloud::PointCloud;
+use super::point_color::ColorMode;
+use super::process_msg::{BodyPoses, KeyFramePoses, Layers};
+use super::server::ServerStatus;
+
+#[derive(Resource, Default)]
+pub struct UiState {
+    /// keep the orbit camera focused on the tracked camera
+    pub follow_camera: bool,
+}
+
+pub struct UiPlugin;
+impl Plugin for UiPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_plugin(EguiPlugin)
+            .init_resource::<UiState>()
+            .add_system(panel)
+            .add_system(follow_camera.after(panel));
+    }
+}
+
+fn panel(
+    mut contexts: EguiContexts,
+    mut layers: ResMut<Layers>,
+    mut point_cloud: ResMut<PointCloud>,
+    mut mode: ResMut<ColorMode>,
+    mut ui_state: ResMut<UiState>,
+    status: Res<ServerStatus>,
+    keyframe_poses: Res<KeyFramePoses>,
+) {
+    // the widgets edit copies, so the resources are only marked changed, and the points
+    // only recolored or rebuilt, when a value actually changes
+    let mut new_layers = layers.clone();
+    let mut scale = point_cloud.scale();
+    let mut new_mode = *mode;
+    let mut follow = ui_state.follow_camera;
+    let point_count = point_cloud.len();
+
+    egui::SidePanel::left("panel").show(contexts.ctx_mut(), |ui| {
+        ui.heading("Layers");
+        ui.checkbox(&mut new_layers.points, "points");
+        ui.checkbox(&mut new_layers.frustums, "frustums");
+        ui.checkbox(&mut new_layers.trajectory, "trajectory");
+        ui.checkbox(&mut new_layers.graph, "keyframe graph");
+        ui.checkbox(&mut new_layers.axes, "axes");
+
+        ui.separator();
+        ui.heading("Points");
+        ui.add(egui::Slider::new(&mut scale, 0.1..=5.0).logarithmic(true).text("size"));
+        egui::ComboBox::from_label("color")
+            .selected_text(new_mode.name())
+            .show_ui(ui, |ui| {
+                for color_mode in ColorMode::ALL {
+                    ui.selectable_value(&mut new_mode, color_mode, color_mode.name());
+                }
+            });
+
+        ui.separator();
+        ui.heading("Camera");
+        ui.checkbox(&mut follow, "follow tracked camera");
+
+        ui.separator();
+        ui.heading("Connection");
+        match status.client {
+            Some(address) => ui.label(format!("connected to {}", address)),
+            None => ui.label("waiting for a connection"),
+        };
+        ui.label(format!("{} keyframes, {} points", keyframe_poses.0.len(), point_count));
+        let mut kinds = status.received.keys().copied().collect::<Vec<_>>();
+        kinds.sort_by_key(|kind| *kind as u8);
+        egui::Grid::new("message rates").striped(true).show(ui, |ui| {
+            for kind in kinds {
+                ui.label(format!("{:?}", kind));
+                ui.label(format!("{:.1}/s", status.rates.get(&kind).copied().unwrap_or(0.0)));
+                ui.label(format!("{} total", status.received[&kind]));
+                ui.end_row();
+            }
+        });
+    });
+
+    if new_layers != *layers {
+        *layers = new_layers;
+    }
+    if scale != point_cloud.scale() {
+        point_cloud.set_scale(scale);
+    }
+    if new_mode != *mode {
+        *mode = new_mode;
+    }
+    if follow != ui_state.follow_camera {
+        ui_state.follow_camera = follow;
+    }
+}
+
+fn follow_camera(
+    ui_state: Res<UiState>,
+    body_poses: Res<BodyPoses>,
+    mut target: ResMut<FollowTarget>,
+) {
+    let current = body_poses.current()
+        .filter(|_| ui_state.follow_camera)
+        .map(|t_wc| t_wc.translation);
+    if target.0 != current {
+        target.0 = current;
+    }
+}